@@ -2,11 +2,14 @@ use crate::{
     core::{
         create_genesis_block,
         encoding::{json_decoder::JsonDecoder, json_encoder::JsonEncoder},
-        state::mem_state::MemState,
+        genesis_alloc_hash,
+        state::{mem_state::MemState, trie_state::TrieState, DynState},
+        storage::file_storage::FileStorage,
         storage::mem_storage::MemStorage,
         storage::DynStorage,
-        vm::bytecode_vm::BytecodeVM,
-        BlockHasher, BlockchainConfig, DefaultBlockValidator, DynBlockValidator, TxHasher,
+        vm::{bytecode_vm::BytecodeVM, wasm_vm::WasmVM, DynVM},
+        Address, BlockHasher, BlockchainConfig, DefaultBlockValidator, DynBlockValidator,
+        DynForkChoice, LongestChain, TxHasher,
     },
     crypto::PrivateKey,
     net::DynTransport,
@@ -19,8 +22,14 @@ pub struct Config {
     pub hashers: HasherConfig,
     pub storage: DynStorage,
     pub block_validator: DynBlockValidator,
+    pub fork_choice: DynForkChoice,
+    pub state_backend: StateBackend,
+    pub vm_backend: VmBackend,
     pub genesis_block: Block,
+    pub genesis_alloc: Vec<(Address, u64)>,
     pub block_time_ms: u64,
+    pub limits: LimitsConfig,
+    pub tx_pool: TxPoolConfig,
 }
 
 impl Default for Config {
@@ -37,25 +46,98 @@ impl Default for Config {
 
         let storage = Box::new(MemStorage::new());
         let block_validator = Box::new(DefaultBlockValidator {});
+        let fork_choice = Box::new(LongestChain);
+        let state_backend = StateBackend::Memory;
+        let vm_backend = VmBackend::Bytecode;
         let genesis_block = create_genesis_block();
         let block_time_ms = 1000;
+        let limits = LimitsConfig {
+            block_gas_limit: 10_000_000,
+            max_block_bytes: 1 << 20,
+            max_block_txs: 1000,
+            max_tx_bytes: 64 << 10,
+            max_reorg_depth: 64,
+        };
+        let tx_pool = TxPoolConfig {
+            min_gas_price: 0,
+            max_txs: 4096,
+            max_bytes: 8 << 20,
+            tx_ttl_ms: 10 * 60 * 1000,
+            max_txs_per_sender: 64,
+            max_txs_per_peer: 1024,
+        };
 
         Self {
             encoding,
             hashers,
             storage,
             block_validator,
+            fork_choice,
+            state_backend,
+            vm_backend,
             genesis_block,
+            genesis_alloc: vec![],
             block_time_ms,
+            limits,
+            tx_pool,
         }
     }
 }
 
 impl Config {
+    // Store the blocks on disk in `dir` instead of keeping them in memory
+    pub fn with_file_storage(mut self, dir: impl AsRef<std::path::Path>) -> Result<Self> {
+        self.storage = Box::new(FileStorage::open(dir)?);
+        Ok(self)
+    }
+
+    // Keep the state in a merkle trie whose nodes are written to the block storage
+    pub fn with_trie_state(mut self) -> Self {
+        self.state_backend = StateBackend::Trie;
+        self
+    }
+
+    /*
+        Balances the accounts start with. They are part of the genesis block, its data hash commits to them,
+        so nodes with a different allocation have a different genesis block and can't join the same network
+    */
+    pub fn with_genesis_alloc(mut self, alloc: Vec<(Address, u64)>) -> Self {
+        self.genesis_block.header.data_hash = genesis_alloc_hash(&alloc);
+        self.genesis_alloc = alloc;
+        self
+    }
+
+    // Give the network its own chain id, transactions and blocks signed for another network are rejected
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.genesis_block.header.chain_id = chain_id;
+        self
+    }
+
+    fn create_state(&self) -> DynState {
+        match self.state_backend {
+            StateBackend::Memory => Box::new(MemState::new()),
+            StateBackend::Trie => Box::new(TrieState::new(self.storage.clone())),
+        }
+    }
+
+    // Run the code of transactions and contracts as WebAssembly modules instead of bytecode
+    pub fn with_wasm_vm(mut self) -> Self {
+        self.vm_backend = VmBackend::Wasm;
+        self
+    }
+
+    fn create_vm(&self) -> DynVM {
+        match self.vm_backend {
+            VmBackend::Bytecode => Box::new(BytecodeVM::<128>::new()),
+            VmBackend::Wasm => Box::new(WasmVM::new()),
+        }
+    }
+
     pub fn node_config(&self) -> NodeConfig {
         NodeConfig {
             encoding: self.encoding.clone(),
             hashers: self.hashers.clone(),
+            tx_pool: self.tx_pool.clone(),
         }
     }
 
@@ -65,9 +147,12 @@ impl Config {
             hashers: self.hashers.clone(),
             storage: self.storage.clone(),
             block_validator: self.block_validator.clone(),
+            fork_choice: self.fork_choice.clone(),
             genesis_block: self.genesis_block.clone(),
-            state: Box::new(MemState::new()),
-            vm: Box::new(BytecodeVM::<128>::new()),
+            genesis_alloc: self.genesis_alloc.clone(),
+            limits: self.limits.clone(),
+            state: self.create_state(),
+            vm: self.create_vm(),
         }
     }
 
@@ -77,6 +162,7 @@ impl Config {
             hashers: self.hashers.clone(),
             private_key,
             block_time_ms: self.block_time_ms,
+            limits: self.limits.clone(),
         }
     }
 
@@ -88,12 +174,27 @@ impl Config {
     }
 }
 
+// Every node of a network has to use the same backend since the state root is part of the block header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateBackend {
+    Memory,
+    Trie,
+}
+
+// Like the state backend, the VM has to be the same on every node of a network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmBackend {
+    Bytecode,
+    Wasm,
+}
+
 #[derive(Debug, Clone)]
 pub struct ValidatorConfig {
     pub private_key: PrivateKey,
     pub block_time_ms: u64,
     pub encoding: EncodingConfig,
     pub hashers: HasherConfig,
+    pub limits: LimitsConfig,
 }
 
 #[derive(Debug, Clone)]
@@ -102,6 +203,34 @@ pub struct HasherConfig {
     pub block_hasher: DynHasher<Block>,
 }
 
+// Limits every block has to stay within, they have to be the same on every node
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    // the sum of the gas limits of all transactions in a block
+    pub block_gas_limit: u64,
+    // the size of a block and of a single transaction in bytes, see Block::size and Transaction::size
+    pub max_block_bytes: usize,
+    pub max_block_txs: usize,
+    pub max_tx_bytes: usize,
+    // how many blocks a reorg can take off the main chain, the state changes of older blocks are dropped
+    pub max_reorg_depth: u32,
+}
+
+// Which transactions a node keeps in its pool, unlike the limits every node can choose its own
+#[derive(Debug, Clone)]
+pub struct TxPoolConfig {
+    // transactions with a lower gas price are not accepted
+    pub min_gas_price: u64,
+    // once the pool holds more transactions or bytes the lowest paying transactions are evicted
+    pub max_txs: usize,
+    pub max_bytes: usize,
+    // transactions that didn't make it into a block in time are dropped
+    pub tx_ttl_ms: u64,
+    // how many transactions a single sender and a single peer can have in the pool
+    pub max_txs_per_sender: usize,
+    pub max_txs_per_peer: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct EncodingConfig {
     pub encoder: DynEncoder,
@@ -112,4 +241,5 @@ pub struct EncodingConfig {
 pub struct NodeConfig {
     pub encoding: EncodingConfig,
     pub hashers: HasherConfig,
+    pub tx_pool: TxPoolConfig,
 }
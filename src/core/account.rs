@@ -0,0 +1,239 @@
+/*
+    Balances of the native token and the nonces of the accounts, stored in the state like everything else:
+
+        balance/<account>     balance of the account, u64
+        tx_nonce/<account>    nonce the next transaction of the account has to use, u64
+
+    An account without an entry has a balance and a nonce of 0. Only the chain itself writes these keys,
+    the code of transactions and contracts runs on storages of its own, see contract.rs.
+    The tx nonce is not the deploy nonce of contract.rs, that one only counts deployed contracts.
+*/
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+
+use super::{state::DynState, Address, Hash, McError, Transaction};
+
+const BALANCE_PREFIX: &[u8] = b"balance/";
+const TX_NONCE_PREFIX: &[u8] = b"tx_nonce/";
+
+fn key(prefix: &[u8], account: &Address) -> Vec<u8> {
+    [prefix, account.as_bytes()].concat()
+}
+
+async fn get_u64(state: &DynState, key: &[u8]) -> u64 {
+    match state.get(key).await {
+        Ok(bytes) => u64::from_le_bytes(bytes.try_into().unwrap_or_default()),
+        Err(_) => 0,
+    }
+}
+
+pub async fn balance(state: &DynState, account: &Address) -> u64 {
+    get_u64(state, &key(BALANCE_PREFIX, account)).await
+}
+
+pub async fn set_balance(state: &DynState, account: &Address, amount: u64) -> Result<()> {
+    state
+        .set(&key(BALANCE_PREFIX, account), &amount.to_le_bytes())
+        .await
+}
+
+// Moves `value` from one account to the other, nothing changes if `from` can't pay it
+pub async fn transfer(state: &DynState, from: &Address, to: &Address, value: u64) -> Result<()> {
+    let from_balance = balance(state, from).await;
+    if from_balance < value {
+        Err(McError::InsufficientBalance {
+            account: *from,
+            balance: from_balance,
+            value,
+        })?;
+    }
+    set_balance(state, from, from_balance - value).await?;
+    credit(state, to, value).await
+}
+
+async fn credit(state: &DynState, account: &Address, value: u64) -> Result<()> {
+    // nothing is written for nothing, otherwise free transactions would create empty accounts
+    if value == 0 {
+        return Ok(());
+    }
+    let balance = balance(state, account)
+        .await
+        .checked_add(value)
+        .ok_or_else(|| anyhow!("balance of {account} overflows"))?;
+    set_balance(state, account, balance).await
+}
+
+pub async fn account_nonce(state: &DynState, account: &Address) -> u64 {
+    get_u64(state, &key(TX_NONCE_PREFIX, account)).await
+}
+
+// Checks that `nonce` is the next nonce of the account and uses it up
+pub async fn use_nonce(state: &DynState, account: &Address, nonce: u64) -> Result<()> {
+    let expected = account_nonce(state, account).await;
+    if nonce != expected {
+        Err(McError::InvalidNonce {
+            account: *account,
+            expected,
+            found: nonce,
+        })?;
+    }
+    state
+        .set(&key(TX_NONCE_PREFIX, account), &(nonce + 1).to_le_bytes())
+        .await
+}
+
+// Uses up the nonce of a signed transaction
+pub async fn use_tx_nonce(state: &DynState, tx: &Transaction) -> Result<()> {
+    match tx.sender() {
+        Some(sender) => use_nonce(state, &sender, tx.nonce).await,
+        None => Ok(()),
+    }
+}
+
+// Takes the fee for the whole gas limit from the sender before the transaction runs
+pub async fn charge_fee(state: &DynState, tx: &Transaction) -> Result<()> {
+    let (Some(sender), fee @ 1..) = (tx.sender(), tx.max_fee()) else {
+        return Ok(());
+    };
+    let balance = balance(state, &sender).await;
+    if balance < fee {
+        Err(McError::InsufficientBalance {
+            account: sender,
+            balance,
+            value: fee,
+        })?;
+    }
+    set_balance(state, &sender, balance - fee).await
+}
+
+// Refunds the gas the transaction didn't use to its sender and pays the used gas to the validator,
+// without a validator that part of the fee is burned
+pub async fn pay_fee(
+    state: &DynState,
+    tx: &Transaction,
+    gas_used: u64,
+    validator: Option<&Address>,
+) -> Result<()> {
+    let Some(sender) = tx.sender() else {
+        return Ok(());
+    };
+    let gas_used = gas_used.min(tx.gas_limit);
+    let refund = (tx.gas_limit - gas_used).saturating_mul(tx.gas_price);
+    credit(state, &sender, refund).await?;
+    if let Some(validator) = validator {
+        credit(state, validator, gas_used.saturating_mul(tx.gas_price)).await?;
+    }
+    Ok(())
+}
+
+// Pays the value of the transaction from its sender to its recipient
+pub async fn transfer_value(state: &DynState, tx: &Transaction) -> Result<()> {
+    let (Some(to), value @ 1..) = (tx.to, tx.value) else {
+        return Ok(());
+    };
+    let from = tx
+        .sender()
+        .ok_or_else(|| anyhow!("unsigned transaction can't transfer a value"))?;
+    transfer(state, &from, &to, value).await
+}
+
+// Sets the balances the accounts start with, before the first block is executed
+pub async fn apply_genesis_alloc(state: &DynState, alloc: &[(Address, u64)]) -> Result<()> {
+    for (account, amount) in alloc {
+        set_balance(state, account, *amount).await?;
+    }
+    Ok(())
+}
+
+// Used as data hash of the genesis block, so chains with different allocations have different genesis blocks
+pub fn genesis_alloc_hash(alloc: &[(Address, u64)]) -> Hash {
+    let mut sha = Sha256::new();
+    for (account, amount) in alloc {
+        sha.update(account.as_bytes());
+        sha.update(amount.to_le_bytes());
+    }
+    Hash::from_bytes(&sha.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{core::state::mem_state::MemState, crypto::PrivateKey};
+
+    #[tokio::test]
+    async fn test_transfer() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let a = Address::from_bytes(&[1; 20]);
+        let b = Address::from_bytes(&[2; 20]);
+
+        apply_genesis_alloc(&state, &[(a, 100)]).await?;
+        transfer(&state, &a, &b, 30).await?;
+        assert_eq!(balance(&state, &a).await, 70);
+        assert_eq!(balance(&state, &b).await, 30);
+
+        // sending to yourself changes nothing
+        transfer(&state, &a, &a, 70).await?;
+        assert_eq!(balance(&state, &a).await, 70);
+
+        let err = transfer(&state, &b, &a, 31).await.unwrap_err();
+        assert!(matches!(
+            err.downcast::<McError>()?,
+            McError::InsufficientBalance { balance: 30, .. }
+        ));
+        assert_eq!(balance(&state, &a).await, 70);
+        assert_eq!(balance(&state, &b).await, 30);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fee() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let private_key = PrivateKey::generate();
+        let sender = private_key.public_key().address();
+        let validator = Address::from_bytes(&[1; 20]);
+        apply_genesis_alloc(&state, &[(sender, 100)]).await?;
+
+        let mut tx = Transaction::new(vec![])
+            .with_gas_limit(30)
+            .with_gas_price(2);
+        tx.sign(&private_key);
+
+        charge_fee(&state, &tx).await?;
+        assert_eq!(balance(&state, &sender).await, 40);
+        pay_fee(&state, &tx, 10, Some(&validator)).await?;
+        assert_eq!(balance(&state, &sender).await, 80);
+        assert_eq!(balance(&state, &validator).await, 20);
+
+        // the sender can't pay for the whole gas limit anymore
+        let mut tx = tx.with_gas_limit(41);
+        tx.sign(&private_key);
+        assert!(charge_fee(&state, &tx).await.is_err());
+        assert_eq!(balance(&state, &sender).await, 80);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_use_nonce() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let a = Address::from_bytes(&[1; 20]);
+
+        use_nonce(&state, &a, 0).await?;
+        use_nonce(&state, &a, 1).await?;
+        assert_eq!(account_nonce(&state, &a).await, 2);
+
+        // a used nonce and a nonce from the future are both rejected
+        for nonce in [1, 3] {
+            let err = use_nonce(&state, &a, nonce).await.unwrap_err();
+            assert!(matches!(
+                err.downcast::<McError>()?,
+                McError::InvalidNonce { expected: 2, .. }
+            ));
+        }
+        assert_eq!(account_nonce(&state, &a).await, 2);
+
+        Ok(())
+    }
+}
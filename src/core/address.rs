@@ -1,11 +1,20 @@
-use std::fmt::Display;
+use std::{fmt, str::FromStr};
 
 use crate::util::from_bytes;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Address([u8; 20]);
 
-impl Display for Address {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         for byte in &self.0 {
             write!(f, "{byte:02x}")?;
         }
@@ -18,4 +27,36 @@ impl Address {
         let address = from_bytes::<20>(bytes);
         Self(address)
     }
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+// Parses the hex form that Display prints
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 40 || !s.is_ascii() {
+            return Err(anyhow!("address {s} is not 40 hex digits"));
+        }
+        let mut address = [0u8; 20];
+        for (i, byte) in address.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(Self(address))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_from_str() {
+        let address = Address::from_bytes(&[0xab; 20]);
+        assert_eq!(address.to_string().parse::<Address>().unwrap(), address);
+        assert!("abcd".parse::<Address>().is_err());
+        assert!("zz".repeat(20).parse::<Address>().is_err());
+    }
 }
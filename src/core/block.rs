@@ -1,7 +1,11 @@
-use crate::crypto::{PrivateKey, PublicKey, Signature};
+use super::{merkle_proof, merkle_root, timestamp_now, McError, MerkleProof, DEFAULT_CHAIN_ID};
+use crate::crypto::{signing_digest, PrivateKey, PublicKey, Signature, SigningDomain, SIGNER_SIZE};
 use crate::prelude::*;
 use sha2::{Digest, Sha256};
-use tokio::time::Instant;
+
+// The most a header and the signature of its validator can take up:
+// version, chain id, height, timestamp, four hashes and the previous block hash
+pub const BLOCK_HEADER_SIZE: usize = 4 + 8 + 4 + 16 + 4 * 32 + 1 + 32 + SIGNER_SIZE;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
@@ -12,8 +16,9 @@ pub struct Block {
     pub validator_public_key: Option<PublicKey>,
     pub signature: Option<Signature>,
 
-    // we cache the hash of the transaction to avoid recomputing it
-    // #[serde(skip)]
+    // we cache the hash of the block to avoid recomputing it,
+    // it's never sent along since a peer could put any hash in there
+    #[serde(skip)]
     hash: Option<Hash>,
 }
 
@@ -50,14 +55,22 @@ impl Block {
         transactions: Vec<Transaction>,
         encoder: &DynEncoder,
         hasher: &DynHasher<Self>,
+        tx_hasher: &DynHasher<Transaction>,
     ) -> Result<Self> {
         let data_hash = data_hash(&transactions, encoder)?;
+        let tx_root = tx_root(&transactions, tx_hasher)?;
 
         let header = BlockHeader {
             version: prev_header.version,
+            chain_id: prev_header.chain_id,
             height: prev_header.height + 1,
-            timestamp: Instant::now().elapsed().as_nanos(),
+            // a clock that is behind the parent still has to produce a valid block
+            timestamp: timestamp_now().max(prev_header.timestamp + 1),
             data_hash,
+            tx_root,
+            // the state and receipts roots can only be known after executing the transactions
+            state_root: Hash::zero(),
+            receipts_root: Hash::zero(),
             prev_block_header_hash: Some(Block::hash_header(prev_header, hasher)?),
         };
 
@@ -74,11 +87,18 @@ impl Block {
         }
     }
 
-    pub fn sign(&mut self, private_key: &PrivateKey, enc: &DynEncoder) -> Result<()> {
-        // Get header as bytes
-        let bytes = self.header.bytes(enc)?;
-        // Sign header bytes
-        let signature = private_key.sign(bytes.as_slice());
+    // What the validator signs, the header under the chain id of the block
+    fn signing_digest(&self) -> Vec<u8> {
+        signing_digest(
+            SigningDomain::Block,
+            self.header.chain_id,
+            &self.header.signing_bytes(),
+        )
+    }
+
+    pub fn sign(&mut self, private_key: &PrivateKey) -> Result<()> {
+        // Sign the digest of the header
+        let signature = private_key.sign(&self.signing_digest());
 
         // Set the signature and public key
         self.validator_public_key = Some(private_key.public_key());
@@ -87,7 +107,20 @@ impl Block {
         Ok(())
     }
 
-    pub fn verify(&self, enc: &DynEncoder) -> Result<()> {
+    // Checks the block and its transactions, all of them have to belong to the chain with `chain_id`
+    pub fn verify(
+        &self,
+        enc: &DynEncoder,
+        tx_hasher: &DynHasher<Transaction>,
+        chain_id: u64,
+    ) -> Result<()> {
+        if self.header.chain_id != chain_id {
+            Err(McError::ChainIdMismatch {
+                expected: chain_id,
+                found: self.header.chain_id,
+            })?;
+        }
+
         // Check if the block has a signature
         let sig = self
             .signature
@@ -101,14 +134,14 @@ impl Block {
             .ok_or_else(|| anyhow!("block has no validator (public_key)"))?;
 
         // Verify the signature
-        if !sig.verify(&self.header.bytes(enc)?, pub_key) {
+        if !sig.verify(&self.signing_digest(), pub_key) {
             return Err(anyhow!("block has invalid signature"));
         }
 
         // Verify every transactions
         // TODO: could probably make this faster by using a thread pool
         for tx in &self.transactions {
-            tx.verify()?;
+            tx.verify(chain_id)?;
         }
 
         // Verify the data hash
@@ -118,9 +151,44 @@ impl Block {
             return Err(anyhow!("block has invalid data hash {}", data_hash));
         }
 
+        // Verify the merkle root of the transactions
+        let tx_root = tx_root(&self.transactions, tx_hasher)?;
+
+        if tx_root != self.header.tx_root {
+            return Err(anyhow!("block has invalid tx root {}", tx_root));
+        }
+
         Ok(())
     }
 
+    // Proof that the transaction at `index` is part of this block, check it with `verify_tx_inclusion`
+    pub fn tx_proof(
+        &self,
+        index: usize,
+        tx_hasher: &DynHasher<Transaction>,
+    ) -> Result<MerkleProof> {
+        let tx_hashes = tx_hashes(&self.transactions, tx_hasher)?;
+        merkle_proof(&tx_hashes, index)
+            .ok_or_else(|| anyhow!("block has no transaction at index {}", index))
+    }
+
+    // The gas the block may use at most, the sum of the gas limits of its transactions
+    pub fn gas_limit(&self) -> u64 {
+        self.transactions
+            .iter()
+            .fold(0u64, |sum, tx| sum.saturating_add(tx.gas_limit))
+    }
+
+    // Size of the block in bytes, the header counts with the most it can take up
+    pub fn size(&self) -> usize {
+        BLOCK_HEADER_SIZE
+            + self
+                .transactions
+                .iter()
+                .map(Transaction::size)
+                .sum::<usize>()
+    }
+
     pub fn encode(&self, encoder: &DynEncoder) -> Result<Vec<u8>> {
         encoder.encode(self)
     }
@@ -141,15 +209,31 @@ pub fn data_hash(transactions: &[Transaction], encoder: &DynEncoder) -> Result<H
     Ok(Hash::from_bytes(hash.as_slice()))
 }
 
+pub fn tx_hashes(
+    transactions: &[Transaction],
+    tx_hasher: &DynHasher<Transaction>,
+) -> Result<Vec<Hash>> {
+    transactions.iter().map(|tx| tx_hasher.hash(tx)).collect()
+}
+
+// merkle root over the hashes of all the transactions in the block
+pub fn tx_root(transactions: &[Transaction], tx_hasher: &DynHasher<Transaction>) -> Result<Hash> {
+    Ok(merkle_root(&tx_hashes(transactions, tx_hasher)?))
+}
+
 // TODO: find a way to include a secret message in the block
 pub fn create_genesis_block() -> Block {
     Block::new(
         BlockHeader {
             version: 1,
+            chain_id: DEFAULT_CHAIN_ID,
             height: 0,
             timestamp: 0,
             prev_block_header_hash: None,
             data_hash: Hash::zero(),
+            tx_root: Hash::zero(),
+            state_root: Hash::zero(),
+            receipts_root: Hash::zero(),
         },
         vec![],
     )
@@ -159,8 +243,12 @@ pub fn create_genesis_block() -> Block {
 mod tests {
     use super::*;
     use crate::{
-        core::{encoding::json_encoder::JsonEncoder, hasher::BlockHasher},
-        util::random_block,
+        core::{
+            encoding::json_encoder::JsonEncoder,
+            hasher::{BlockHasher, TxHasher},
+            verify_tx_inclusion,
+        },
+        util::{random_block, random_transaction},
     };
 
     use anyhow::Result;
@@ -173,6 +261,10 @@ mod tests {
         Box::new(BlockHasher::new(encoder()))
     }
 
+    fn tx_hasher() -> DynHasher<Transaction> {
+        Box::new(TxHasher)
+    }
+
     #[test]
     fn test_hash_block() -> Result<()> {
         let mut block = random_block(0, Hash::zero(), &encoder())?;
@@ -185,7 +277,7 @@ mod tests {
     fn test_sign_block() -> Result<()> {
         let private_key = PrivateKey::generate();
         let mut b = random_block(0, Hash::zero(), &encoder())?;
-        b.sign(&private_key, &encoder())?;
+        b.sign(&private_key)?;
         assert!(b.signature.is_some());
 
         Ok(())
@@ -196,18 +288,91 @@ mod tests {
         let enc = encoder();
         let private_key = PrivateKey::generate();
         let mut b = random_block(0, Hash::zero(), &enc)?;
-        b.sign(&private_key, &enc)?;
-        b.verify(&enc)?;
+        b.sign(&private_key)?;
+        b.verify(&enc, &tx_hasher(), DEFAULT_CHAIN_ID)?;
 
         // changing the data should make the public key invalid
         b.header.height = 100;
-        assert!(b.verify(&enc).is_err());
+        assert!(b.verify(&enc, &tx_hasher(), DEFAULT_CHAIN_ID).is_err());
         b.header.height = 0;
 
         // changing the public key should make the signature invalid
         let other_private_key = PrivateKey::generate();
         b.validator_public_key = Some(other_private_key.public_key());
-        assert!(b.verify(&enc).is_err());
+        assert!(b.verify(&enc, &tx_hasher(), DEFAULT_CHAIN_ID).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_block_chain_id() -> Result<()> {
+        let enc = encoder();
+        let private_key = PrivateKey::generate();
+        let mut b = random_block(0, Hash::zero(), &enc)?;
+        b.header.chain_id = 2;
+        b.sign(&private_key)?;
+        b.verify(&enc, &tx_hasher(), 2)?;
+
+        let err = b.verify(&enc, &tx_hasher(), DEFAULT_CHAIN_ID).unwrap_err();
+        assert!(matches!(
+            err.downcast::<McError>()?,
+            McError::ChainIdMismatch {
+                expected: 1,
+                found: 2
+            }
+        ));
+
+        // a block of another chain can't be passed off as one of this chain
+        b.header.chain_id = DEFAULT_CHAIN_ID;
+        assert!(b.verify(&enc, &tx_hasher(), DEFAULT_CHAIN_ID).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tx_inclusion_proof() -> Result<()> {
+        let enc = encoder();
+        let txs: Vec<Transaction> = (0..5).map(|_| random_transaction()).collect();
+        let genesis = create_genesis_block();
+        let b = Block::from_prev_header(&genesis.header, txs, &enc, &block_hasher(), &tx_hasher())?;
+
+        for (i, tx) in b.transactions.iter().enumerate() {
+            let proof = b.tx_proof(i, &tx_hasher())?;
+            let tx_hash = tx_hasher().hash(tx)?;
+            assert!(verify_tx_inclusion(&b.header.tx_root, &tx_hash, &proof));
+        }
+
+        // a proof for one transaction doesn't prove another one
+        let proof = b.tx_proof(0, &tx_hasher())?;
+        let other_hash = tx_hasher().hash(&b.transactions[1])?;
+        assert!(!verify_tx_inclusion(&b.header.tx_root, &other_hash, &proof));
+
+        assert!(b.tx_proof(5, &tx_hasher()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_block_tx_root() -> Result<()> {
+        let enc = encoder();
+        let genesis = create_genesis_block();
+        // the hash of a transaction covers its sender, so they are signed before the tx root is computed
+        let txs = (0..2)
+            .map(|_| {
+                let mut tx = random_transaction();
+                tx.sign(&PrivateKey::generate());
+                tx
+            })
+            .collect();
+        let mut b =
+            Block::from_prev_header(&genesis.header, txs, &enc, &block_hasher(), &tx_hasher())?;
+        b.sign(&PrivateKey::generate())?;
+        b.verify(&enc, &tx_hasher(), DEFAULT_CHAIN_ID)?;
+
+        // a tx root that doesn't match the transactions makes the block invalid
+        b.header.tx_root = Hash::zero();
+        b.sign(&PrivateKey::generate())?;
+        assert!(b.verify(&enc, &tx_hasher(), DEFAULT_CHAIN_ID).is_err());
 
         Ok(())
     }
@@ -1,11 +1,31 @@
 use crate::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Chain id of the default genesis block, every network should pick its own
+pub const DEFAULT_CHAIN_ID: u64 = 1;
+
+// The current time in the unit of the header timestamp, nanoseconds since the unix epoch
+pub fn timestamp_now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos())
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BlockHeader {
     pub version: u32,
+    // network the block belongs to, set in the genesis block and the same in every block after it
+    pub chain_id: u64,
     pub height: u32,
+    // nanoseconds since the unix epoch when the block was created, always later than its parent
     pub timestamp: u128,
     pub data_hash: Hash,
+    // merkle root of the transaction hashes
+    pub tx_root: Hash,
+    // root of the state after executing the transactions of the block
+    pub state_root: Hash,
+    // merkle root of the receipts of the transactions
+    pub receipts_root: Hash,
     pub prev_block_header_hash: Option<Hash>,
 }
 
@@ -16,6 +36,23 @@ impl BlockHeader {
     pub fn bytes(&self, enc: &DynEncoder) -> Result<Vec<u8>> {
         enc.encode(self)
     }
+    // Encoding of the header that is signed, unlike `bytes` it doesn't depend on the encoder
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let prev = self.prev_block_header_hash.as_ref();
+        [
+            self.version.to_le_bytes().as_slice(),
+            &self.height.to_le_bytes(),
+            &self.timestamp.to_le_bytes(),
+            self.data_hash.as_bytes(),
+            self.tx_root.as_bytes(),
+            self.state_root.as_bytes(),
+            self.receipts_root.as_bytes(),
+            &[prev.is_some() as u8],
+            prev.map_or(&[][..], Hash::as_bytes),
+        ]
+        .concat()
+    }
+
     pub fn hash(&self, hasher: &DynHasher<Self>) -> Result<Hash> {
         hasher.hash(self)
     }
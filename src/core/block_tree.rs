@@ -0,0 +1,154 @@
+/*
+    The BlockTree keeps track of every valid block header we have seen, including
+    the ones that are not part of the main chain (side branches).
+    Headers are indexed by their hash and linked to their parent through prev_block_header_hash.
+*/
+
+use std::collections::HashMap;
+
+use crate::prelude::*;
+
+#[derive(Debug, Clone)]
+pub struct TreeEntry {
+    pub hash: Hash,
+    pub header: BlockHeader,
+    // accumulated fork choice weight from genesis up to this block
+    pub total_weight: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BlockTree {
+    entries: HashMap<Hash, TreeEntry>,
+    children: HashMap<Hash, Vec<Hash>>,
+}
+
+impl BlockTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, entry: TreeEntry) {
+        if let Some(prev_hash) = entry.header.prev_block_header_hash {
+            self.children.entry(prev_hash).or_default().push(entry.hash);
+        }
+        self.entries.insert(entry.hash, entry);
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<&TreeEntry> {
+        self.entries.get(hash)
+    }
+
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    // Removes a block and all of its descendants, returns the removed hashes
+    pub fn remove(&mut self, hash: &Hash) -> Vec<Hash> {
+        let mut removed = vec![];
+        let mut queue = vec![*hash];
+
+        while let Some(hash) = queue.pop() {
+            if let Some(entry) = self.entries.remove(&hash) {
+                if let Some(prev_hash) = entry.header.prev_block_header_hash {
+                    if let Some(siblings) = self.children.get_mut(&prev_hash) {
+                        siblings.retain(|h| *h != hash);
+                    }
+                }
+                removed.push(hash);
+            }
+            if let Some(children) = self.children.remove(&hash) {
+                queue.extend(children);
+            }
+        }
+
+        removed
+    }
+
+    // Walks from `hash` back to the genesis block and stops at the first block for which
+    // `is_ancestor` returns true. Returns the ancestor and the path from it to `hash` (oldest first)
+    pub fn branch_from<F>(&self, hash: &Hash, is_ancestor: F) -> Result<(Hash, Vec<Hash>)>
+    where
+        F: Fn(&TreeEntry) -> bool,
+    {
+        let mut path = vec![];
+        let mut current = *hash;
+
+        loop {
+            let entry = self
+                .get(&current)
+                .ok_or_else(|| anyhow!("block tree has no block {}", current))?;
+
+            if is_ancestor(entry) {
+                path.reverse();
+                return Ok((current, path));
+            }
+
+            path.push(current);
+
+            current = entry
+                .header
+                .prev_block_header_hash
+                .ok_or_else(|| anyhow!("block {} has no common ancestor", hash))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::random_hash;
+
+    fn entry(height: u32, prev: Option<Hash>) -> TreeEntry {
+        TreeEntry {
+            hash: random_hash(),
+            header: BlockHeader {
+                version: 1,
+                chain_id: 0,
+                height,
+                timestamp: 0,
+                data_hash: Hash::zero(),
+                tx_root: Hash::zero(),
+                state_root: Hash::zero(),
+                receipts_root: Hash::zero(),
+                prev_block_header_hash: prev,
+            },
+            total_weight: height as u64,
+        }
+    }
+
+    #[test]
+    fn test_branch_from() -> Result<()> {
+        let mut tree = BlockTree::new();
+        let genesis = entry(0, None);
+        let a1 = entry(1, Some(genesis.hash));
+        let b1 = entry(1, Some(genesis.hash));
+        let b2 = entry(2, Some(b1.hash));
+
+        for e in [&genesis, &a1, &b1, &b2] {
+            tree.insert(e.clone());
+        }
+
+        let (ancestor, path) = tree.branch_from(&b2.hash, |e| e.hash == genesis.hash)?;
+        assert_eq!(ancestor, genesis.hash);
+        assert_eq!(path, vec![b1.hash, b2.hash]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_descendants() {
+        let mut tree = BlockTree::new();
+        let genesis = entry(0, None);
+        let b1 = entry(1, Some(genesis.hash));
+        let b2 = entry(2, Some(b1.hash));
+
+        for e in [&genesis, &b1, &b2] {
+            tree.insert(e.clone());
+        }
+
+        let removed = tree.remove(&b1.hash);
+        assert_eq!(removed.len(), 2);
+        assert!(tree.contains(&genesis.hash));
+        assert!(!tree.contains(&b2.hash));
+    }
+}
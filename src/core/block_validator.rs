@@ -1,8 +1,11 @@
-use super::{Block, Blockchain, DynHasher, McError};
+use super::{timestamp_now, Block, Blockchain, DynHasher, McError};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use dyn_clone::DynClone;
-use std::fmt::Debug;
+use std::{fmt::Debug, time::Duration};
+
+// How far the timestamp of a block may be ahead of the clock of the node, clocks are never exactly in sync
+pub const MAX_FUTURE_BLOCK_TIME: Duration = Duration::from_secs(15);
 
 pub type DynBlockValidator = Box<dyn BlockValidator>;
 
@@ -29,43 +32,91 @@ impl BlockValidator for DefaultBlockValidator {
         hasher: &DynHasher<Block>,
     ) -> Result<()> {
         // Check if block already exists
-        if bc.has_block(block.header.height).await {
-            let hash = block.hash(hasher)?;
+        let hash = block.hash(hasher)?;
+        if bc.has_block(&hash).await {
             Err(McError::BlockAlreadyExists(hash))?;
         }
 
-        // Check if block height is too high
-        let bc_height = bc.height().await;
-        if block.header.height != bc_height + 1 {
-            return Err(anyhow!(
-                "local_height: {} remote_height {} is too high",
-                bc_height,
-                block.header.height
-            ));
-        }
-
         // Check if block is valid
 
-        // Get previous block header
+        // Get the parent of the block, it can be part of the main chain or a side branch
+        let prev_header_hash = block
+            .header
+            .prev_block_header_hash
+            .ok_or_else(|| anyhow!("invalid block: {} has no previous block", hash))?;
+
         let prev_header = bc
-            .get_prev_header(block.header.height)
+            .get_header_by_hash(&prev_header_hash)
             .await
-            .ok_or_else(|| anyhow!("bc (height:{bc_height}) has no previous block!"))?;
+            .ok_or_else(|| anyhow!("invalid block: unknown previous block {prev_header_hash}"))?;
+
+        // Check if the block height follows the parent
+        if block.header.height != prev_header.height + 1 {
+            return Err(anyhow!(
+                "invalid block: height {} does not follow previous block height {}",
+                block.header.height,
+                prev_header.height
+            ));
+        }
+
+        // Check that the time moves forward and that the block doesn't claim to be from the future
+        if block.header.timestamp <= prev_header.timestamp {
+            return Err(anyhow!(
+                "invalid block: timestamp {} is not after the previous block timestamp {}",
+                block.header.timestamp,
+                prev_header.timestamp
+            ));
+        }
+        let max_timestamp = timestamp_now() + MAX_FUTURE_BLOCK_TIME.as_nanos();
+        if block.header.timestamp > max_timestamp {
+            return Err(anyhow!(
+                "invalid block: timestamp {} is too far in the future",
+                block.header.timestamp
+            ));
+        }
 
-        // Hash the previous block header
-        let bc_prev_header_hash = Block::hash_header(&prev_header, hasher)?;
-        let block_prev_header_hash = block.header.prev_block_header_hash;
+        // Check the size of the block and its transactions before spending any time on executing them
+        let limits = &bc.config.limits;
+        if block.transactions.len() > limits.max_block_txs {
+            return Err(anyhow!(
+                "invalid block: {} transactions exceed the limit of {}",
+                block.transactions.len(),
+                limits.max_block_txs
+            ));
+        }
+        if let Some(tx) = block
+            .transactions
+            .iter()
+            .find(|tx| tx.size() > limits.max_tx_bytes)
+        {
+            return Err(anyhow!(
+                "invalid block: a transaction of {} bytes exceeds the limit of {}",
+                tx.size(),
+                limits.max_tx_bytes
+            ));
+        }
+        if block.size() > limits.max_block_bytes {
+            return Err(anyhow!(
+                "invalid block: {} bytes exceed the limit of {}",
+                block.size(),
+                limits.max_block_bytes
+            ));
+        }
 
-        // Check if the previous block header hash is correct
-        if Some(bc_prev_header_hash) != block_prev_header_hash {
+        // Check that the transactions can't use more gas than a block is allowed to
+        if block.gas_limit() > bc.config.limits.block_gas_limit {
             return Err(anyhow!(
-                "invalid block: {} != {:?}",
-                bc_prev_header_hash,
-                block_prev_header_hash
+                "invalid block: gas limit {} exceeds the block gas limit {}",
+                block.gas_limit(),
+                bc.config.limits.block_gas_limit
             ));
         }
 
-        block.verify(&bc.config.encoding.encoder)?;
+        block.verify(
+            &bc.config.encoding.encoder,
+            &bc.config.hashers.tx_hasher,
+            bc.chain_id(),
+        )?;
 
         Ok(())
     }
@@ -1,22 +1,39 @@
 use crate::{
-    config::{EncodingConfig, HasherConfig},
+    config::{EncodingConfig, HasherConfig, LimitsConfig},
     prelude::*,
 };
 
 use super::{
-    block_header::BlockHeader, state::DynState, storage::DynStorage, vm::DynVM, Block,
-    DynBlockValidator,
+    account_nonce, apply_genesis_alloc, balance,
+    block_header::BlockHeader,
+    charge_fee, execute_transaction, pay_fee, receipts_root,
+    state::{
+        journaled_state::{JournaledState, StateJournal},
+        mem_state::MemState,
+        DynState,
+    },
+    storage::DynStorage,
+    transfer_value, tx_hashes, use_tx_nonce,
+    vm::{
+        trace::{Trace, TraceRecorder},
+        DynVM, ExecutionContext,
+    },
+    Address, Block, BlockTree, DynBlockValidator, DynForkChoice, McError, Receipt, TreeEntry,
+    TxExecution,
 };
-use std::{ops::Range, sync::Arc};
-use tokio::sync::RwLock;
+use std::{cmp::Ordering, collections::HashMap, ops::Range, sync::Arc};
+use tokio::sync::{Mutex, RwLock};
 
 #[derive(Debug, Clone)]
 pub struct BlockchainConfig {
     pub genesis_block: Block,
+    pub genesis_alloc: Vec<(Address, u64)>,
     pub storage: DynStorage,
     pub block_validator: DynBlockValidator,
+    pub fork_choice: DynForkChoice,
     pub hashers: HasherConfig,
     pub encoding: EncodingConfig,
+    pub limits: LimitsConfig,
     pub vm: DynVM,
     pub state: DynState,
 }
@@ -24,43 +41,590 @@ pub struct BlockchainConfig {
 #[derive(Debug, Clone)]
 pub struct Blockchain {
     pub config: BlockchainConfig,
+    // headers of the main chain, the index is the height of the block
     block_headers: Arc<RwLock<Vec<BlockHeader>>>,
+    // every valid block we know of, including side branches
+    tree: Arc<RwLock<BlockTree>>,
+    // state changes of the last max_reorg_depth blocks on the main chain, used to roll back the state on a reorg
+    journals: Arc<RwLock<HashMap<Hash, StateJournal>>>,
+    // receipts of the transactions of the blocks on the main chain
+    receipts: Arc<RwLock<HashMap<Hash, Vec<Receipt>>>>,
+    // block hash and index of every transaction on the main chain
+    tx_locations: Arc<RwLock<HashMap<Hash, (Hash, usize)>>>,
+    // only one block can be added at a time, otherwise a reorg could interleave with another block
+    lock: Arc<Mutex<()>>,
+}
+
+// How the main chain changed by adding a block, both lists are ordered by height
+#[derive(Debug, Clone, Default)]
+pub struct ChainUpdate {
+    // blocks that became part of the main chain
+    pub enacted: Vec<Block>,
+    // blocks that a reorg removed from the main chain, their transactions are not included anymore
+    pub retracted: Vec<Block>,
+}
+
+// Outcome of running the transactions of a block
+struct Execution {
+    journal: StateJournal,
+    receipts: Vec<Receipt>,
+    state_root: Hash,
+    receipts_root: Hash,
 }
 
 impl Blockchain {
     pub async fn new(config: BlockchainConfig) -> Result<Self> {
-        let genesis_block = config.genesis_block.clone();
-
         let bc = Self {
             block_headers: Arc::new(RwLock::new(vec![])),
+            tree: Arc::new(RwLock::new(BlockTree::new())),
+            journals: Arc::new(RwLock::new(HashMap::new())),
+            receipts: Arc::new(RwLock::new(HashMap::new())),
+            tx_locations: Arc::new(RwLock::new(HashMap::new())),
+            lock: Arc::new(Mutex::new(())),
             config,
         };
 
-        bc.add_block_without_validation(genesis_block).await?;
+        // Continue the chain that is already in storage, otherwise start from the genesis block
+        if bc.config.storage.get(&0u32.to_le_bytes()).await.is_some() {
+            bc.load_from_storage().await?;
+        } else {
+            bc.add_genesis_block().await?;
+        }
 
         Ok(bc)
     }
 
-    pub async fn add_block(&self, mut block: Block) -> Result<()> {
+    async fn add_genesis_block(&self) -> Result<()> {
+        let mut genesis_block = self.config.genesis_block.clone();
+        apply_genesis_alloc(&self.config.state, &self.config.genesis_alloc).await?;
+
+        let entry = TreeEntry {
+            hash: genesis_block.hash(&self.config.hashers.block_hasher)?,
+            header: genesis_block.header.clone(),
+            total_weight: self.config.fork_choice.block_weight(&genesis_block),
+        };
+        self.tree.write().await.insert(entry);
+
+        self.add_block_without_validation(genesis_block).await
+    }
+
+    // Rebuild the main chain from the blocks that are stored by height.
+    // Every block is checked against its parent and executed again to rebuild the state,
+    // the node refuses to start if the stored chain doesn't match the configured genesis block
+    async fn load_from_storage(&self) -> Result<()> {
+        let hasher = &self.config.hashers.block_hasher;
+        let genesis_hash = Block::hash_header(&self.config.genesis_block.header, hasher)?;
+
+        let mut height = 0u32;
+        let mut prev: Option<TreeEntry> = None;
+
+        while let Some(stored_hash) = self.config.storage.get(&height.to_le_bytes()).await {
+            let stored_hash = Hash::from_bytes(&stored_hash);
+            let block = self.get_block_by_hash(&stored_hash).await?;
+
+            // Don't trust the hash cached inside the stored block
+            let hash = Block::hash_header(&block.header, hasher)?;
+            if hash != stored_hash || block.header.height != height {
+                Err(McError::CorruptedBlock { height, hash })?;
+            }
+
+            match &prev {
+                None if hash != genesis_hash => Err(McError::GenesisMismatch {
+                    expected: genesis_hash,
+                    found: hash,
+                })?,
+                None => apply_genesis_alloc(&self.config.state, &self.config.genesis_alloc).await?,
+                Some(prev) => {
+                    if block.header.prev_block_header_hash != Some(prev.hash) {
+                        Err(McError::BrokenChain { height, hash })?;
+                    }
+
+                    block.verify(
+                        &self.config.encoding.encoder,
+                        &self.config.hashers.tx_hasher,
+                        self.chain_id(),
+                    )?;
+
+                    let execution = self.execute_block(&block).await.map_err(|err| {
+                        anyhow!("could not execute stored block {hash} at height {height}: {err}")
+                    })?;
+                    self.store_execution(hash, execution).await;
+                }
+            }
+
+            let entry = TreeEntry {
+                hash,
+                header: block.header.clone(),
+                total_weight: prev.as_ref().map_or(0, |prev| prev.total_weight)
+                    + self.config.fork_choice.block_weight(&block),
+            };
+            self.tree.write().await.insert(entry.clone());
+            self.block_headers.write().await.push(block.header);
+            self.prune_journals().await?;
+
+            prev = Some(entry);
+            height += 1;
+        }
+
+        let side_blocks = self.load_side_branches(genesis_hash).await?;
+        info!("loaded {height} blocks and {side_blocks} side branch blocks from storage");
+
+        Ok(())
+    }
+
+    /*
+        Link the side branches back into the block tree, starting from the genesis block and following
+        the children every stored block has. Side blocks are not executed, like when they were first added,
+        they only have to be in the tree so that a branch can still win a reorg after a restart.
+        A side block that can't be restored is dropped, a peer can send it again.
+    */
+    async fn load_side_branches(&self, genesis_hash: Hash) -> Result<usize> {
+        let hasher = &self.config.hashers.block_hasher;
+        let mut loaded = 0;
+        let mut queue = vec![genesis_hash];
+
+        while let Some(parent_hash) = queue.pop() {
+            let Some(parent) = self.get_tree_entry(Some(&parent_hash)).await else {
+                continue;
+            };
+
+            for hash in self.stored_children(&parent_hash).await {
+                if !self.has_block(&hash).await {
+                    let block = match self.get_block_by_hash(&hash).await {
+                        Ok(block) => block,
+                        Err(err) => {
+                            warn!("dropping side block {hash}: {err}");
+                            continue;
+                        }
+                    };
+                    if Block::hash_header(&block.header, hasher)? != hash {
+                        warn!("dropping side block {hash}: stored block has a different hash");
+                        continue;
+                    }
+                    if let Err(err) = block.verify(
+                        &self.config.encoding.encoder,
+                        &self.config.hashers.tx_hasher,
+                        self.chain_id(),
+                    ) {
+                        warn!("dropping side block {hash}: {err}");
+                        continue;
+                    }
+
+                    self.tree.write().await.insert(TreeEntry {
+                        hash,
+                        header: block.header.clone(),
+                        total_weight: parent.total_weight
+                            + self.config.fork_choice.block_weight(&block),
+                    });
+                    loaded += 1;
+                }
+                queue.push(hash);
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    async fn stored_children(&self, hash: &Hash) -> Vec<Hash> {
+        self.config
+            .storage
+            .get(&children_key(hash))
+            .await
+            .unwrap_or_default()
+            .chunks_exact(32)
+            .map(Hash::from_bytes)
+            .collect()
+    }
+
+    pub async fn add_block(&self, mut block: Block) -> Result<ChainUpdate> {
+        let _guard = self.lock.lock().await;
+
         // Validate the block
         self.config
             .block_validator
             .validate(self, &mut block, &self.config.hashers.block_hasher)
             .await?;
 
+        let hash = block.hash(&self.config.hashers.block_hasher)?;
+
+        // The validator made sure that the parent exists in the tree
+        let parent = self
+            .get_tree_entry(block.header.prev_block_header_hash.as_ref())
+            .await
+            .ok_or_else(|| anyhow!("block {} has no parent in the block tree", hash))?;
+
+        let entry = TreeEntry {
+            hash,
+            header: block.header.clone(),
+            total_weight: parent.total_weight + self.config.fork_choice.block_weight(&block),
+        };
+
+        let head = self.head().await?;
+
+        // The block extends the main chain
+        if parent.hash == head.hash {
+            self.apply_block(block.clone()).await?;
+            self.tree.write().await.insert(entry);
+            return Ok(ChainUpdate {
+                enacted: vec![block],
+                retracted: vec![],
+            });
+        }
+
+        // The block is part of a side branch, keep it around in case the branch wins
+        self.save_block_by_hash(&hash, &block).await?;
+        self.tree.write().await.insert(entry.clone());
+
+        let ordering = self.config.fork_choice.compare(
+            (entry.total_weight, &entry.hash),
+            (head.total_weight, &head.hash),
+        );
+
+        if ordering == Ordering::Greater {
+            return self.reorg(&hash).await;
+        }
+
+        debug!(
+            "block {} (height: {}) added to a side branch",
+            hash, block.header.height
+        );
+        Ok(ChainUpdate::default())
+    }
+
+    // Switch the main chain to the branch ending in `new_head`
+    async fn reorg(&self, new_head: &Hash) -> Result<ChainUpdate> {
+        let (ancestor, enacted) = {
+            let tree = self.tree.read().await;
+            let headers = self.block_headers.read().await;
+            let hasher = &self.config.hashers.block_hasher;
+
+            tree.branch_from(new_head, |entry| {
+                headers
+                    .get(entry.header.height as usize)
+                    .and_then(|header| Block::hash_header(header, hasher).ok())
+                    == Some(entry.hash)
+            })?
+        };
+
+        let ancestor_height = self
+            .get_tree_entry(Some(&ancestor))
+            .await
+            .ok_or_else(|| anyhow!("block tree has no block {}", ancestor))?
+            .header
+            .height;
+
+        // The state changes of blocks deeper than that are gone, the main chain stays as it is
+        let depth = self.height().await - ancestor_height;
+        let max_depth = self.config.limits.max_reorg_depth;
+        if depth > max_depth {
+            return Err(anyhow!(
+                "reorg to {new_head} would take {depth} blocks off the main chain, the limit is {max_depth}"
+            ));
+        }
+
+        let retracted = self.rollback_to(ancestor_height).await?;
+
+        let mut enacted_blocks = Vec::with_capacity(enacted.len());
+        for hash in enacted.iter() {
+            let block = self.get_block_by_hash(hash).await?;
+
+            if let Err(err) = self.apply_block(block.clone()).await {
+                warn!("reorg to {new_head} failed on block {hash}, restoring previous chain");
+
+                // The block is invalid, so is every block building on top of it
+                for removed in self.tree.write().await.remove(hash) {
+                    self.config.storage.delete(&hash_key(&removed)).await;
+                }
+
+                self.rollback_to(ancestor_height).await?;
+                for block in retracted {
+                    self.apply_block(block).await?;
+                }
+
+                return Err(err);
+            }
+            enacted_blocks.push(block);
+        }
+
+        info!(
+            "reorg to {} (common ancestor height: {}, retracted: {}, enacted: {})",
+            new_head,
+            ancestor_height,
+            retracted.len(),
+            enacted.len()
+        );
+
+        Ok(ChainUpdate {
+            enacted: enacted_blocks,
+            retracted,
+        })
+    }
+
+    // Remove all blocks above `height` from the main chain and undo their state changes
+    // returns the removed blocks ordered by height
+    async fn rollback_to(&self, height: u32) -> Result<Vec<Block>> {
+        let mut retracted = vec![];
+
+        while self.height().await > height {
+            let block = self.get_block(self.height().await).await?;
+            let hash = Block::hash_header(&block.header, &self.config.hashers.block_hasher)?;
+
+            let journal = self
+                .journals
+                .write()
+                .await
+                .remove(&hash)
+                .ok_or_else(|| anyhow!("block {hash} is too deep to roll back"))?;
+            journal.revert(&self.config.state).await?;
+            if let Some(receipts) = self.receipts.write().await.remove(&hash) {
+                let mut tx_locations = self.tx_locations.write().await;
+                for receipt in receipts {
+                    // a transaction with the same hash could be part of an earlier block too
+                    if tx_locations.get(&receipt.tx_hash).map(|(block, _)| block) == Some(&hash) {
+                        tx_locations.remove(&receipt.tx_hash);
+                    }
+                }
+            }
+
+            self.block_headers.write().await.pop();
+            self.config
+                .storage
+                .delete(&block.header.height.to_le_bytes())
+                .await;
+
+            retracted.push(block);
+        }
+
+        retracted.reverse();
+        Ok(retracted)
+    }
+
+    // Execute the transactions of the block and append it to the main chain
+    async fn apply_block(&self, block: Block) -> Result<()> {
+        let hash = Block::hash_header(&block.header, &self.config.hashers.block_hasher)?;
+
+        let execution = self.execute_block(&block).await?;
+        self.store_execution(hash, execution).await;
+
+        self.add_block_without_validation(block).await?;
+        self.prune_journals().await
+    }
+
+    // Drop the journal of the block that just became too deep for a reorg
+    async fn prune_journals(&self) -> Result<()> {
+        let Some(height) = self
+            .height()
+            .await
+            .checked_sub(self.config.limits.max_reorg_depth)
+        else {
+            return Ok(());
+        };
+        if let Some(header) = self.get_header(height).await {
+            let hash = Block::hash_header(&header, &self.config.hashers.block_hasher)?;
+            self.journals.write().await.remove(&hash);
+        }
+        Ok(())
+    }
+
+    async fn store_execution(&self, hash: Hash, execution: Execution) {
+        self.journals.write().await.insert(hash, execution.journal);
+
+        let mut tx_locations = self.tx_locations.write().await;
+        for (index, receipt) in execution.receipts.iter().enumerate() {
+            tx_locations.insert(receipt.tx_hash, (hash, index));
+        }
+        self.receipts.write().await.insert(hash, execution.receipts);
+    }
+
+    // Runs all transactions of the block and checks the state root of the resulting state and the receipts root.
+    // The block runs inside a state checkpoint, if a root doesn't match the state is restored
+    async fn execute_block(&self, block: &Block) -> Result<Execution> {
+        let state = &self.config.state;
+
+        state.checkpoint().await?;
+        let execution = match self.run_transactions(block).await {
+            Ok(execution) => execution,
+            Err(err) => {
+                state.revert().await?;
+                return Err(err);
+            }
+        };
+
+        let hash = || Block::hash_header(&block.header, &self.config.hashers.block_hasher);
+        if execution.state_root != block.header.state_root {
+            state.revert().await?;
+            return Err(McError::StateRootMismatch {
+                hash: hash()?,
+                expected: block.header.state_root,
+                found: execution.state_root,
+            }
+            .into());
+        }
+        if execution.receipts_root != block.header.receipts_root {
+            state.revert().await?;
+            return Err(McError::ReceiptsRootMismatch {
+                hash: hash()?,
+                expected: block.header.receipts_root,
+                found: execution.receipts_root,
+            }
+            .into());
+        }
+
+        state.commit().await?;
+        Ok(execution)
+    }
+
+    async fn run_transactions(&self, block: &Block) -> Result<Execution> {
+        let journaled = JournaledState::new(self.config.state.clone());
+        let state: DynState = Box::new(journaled.clone());
+
         // The vm is cloned here because we don't need the mutability
         // the vm only serves as a way to execute the txs
         let mut vm = self.config.vm.clone();
+        let receipts = self.apply_transactions(&mut vm, &state, block).await?;
+
+        Ok(Execution {
+            journal: journaled.take_journal().await,
+            receipts_root: receipts_root(&receipts, &self.config.encoding.encoder)?,
+            receipts,
+            state_root: state.root().await?,
+        })
+    }
+
+    // Every transaction runs in its own checkpoint, a failed transaction is reverted together with
+    // its transfer but doesn't invalidate the block, its failure is recorded in the receipt.
+    // Its nonce stays used and its fee paid so it can't be included again for free. A transaction
+    // with a nonce that isn't the next nonce of its sender, or whose sender can't pay its fee
+    // and value, invalidates the block
+    async fn apply_transactions(
+        &self,
+        vm: &mut DynVM,
+        state: &DynState,
+        block: &Block,
+    ) -> Result<Vec<Receipt>> {
+        let tx_hashes = tx_hashes(&block.transactions, &self.config.hashers.tx_hasher)?;
+        let mut receipts = Vec::with_capacity(block.transactions.len());
+        let validator = block.validator_public_key.as_ref().map(|key| key.address());
+
+        for (tx, tx_hash) in block.transactions.iter().zip(tx_hashes) {
+            use_tx_nonce(state, tx).await?;
+            charge_fee(state, tx).await?;
+            state.checkpoint().await?;
+
+            if let Err(err) = transfer_value(state, tx).await {
+                state.revert().await?;
+                return Err(err);
+            }
 
-        for tx in block.transactions.iter() {
             // configured vm executes the tx
-            vm.execute(&self.config.state, &tx.data).await?;
+            let TxExecution {
+                result,
+                contract_address,
+            } = execute_transaction(vm, state, tx, &tx_context(block, tx_hash)).await;
+            match &result.error {
+                None => state.commit().await?,
+                Some(err) => {
+                    debug!("transaction {tx_hash} failed: {err}");
+                    state.revert().await?;
+                }
+            }
+            pay_fee(state, tx, result.gas_used, validator.as_ref()).await?;
+
+            receipts.push(Receipt {
+                tx_hash,
+                gas_used: result.gas_used,
+                error: result.error,
+                contract_address,
+                logs: result.logs,
+            });
         }
 
-        self.add_block_without_validation(block).await?;
+        Ok(receipts)
+    }
 
-        // Add the block
-        Ok(())
+    /*
+        Execute the transaction at `index` of the block at `height` of the main chain again and trace it.
+        The blocks before it are replayed from the genesis block on a fresh in memory state,
+        the values in the state are the same for every backend so the transaction sees what it saw back then
+    */
+    pub async fn replay_transaction(&self, height: u32, index: usize) -> Result<Trace> {
+        let block = self.get_block(height).await?;
+        let tx = block
+            .transactions
+            .get(index)
+            .ok_or_else(|| anyhow!("block {} has no transaction at index {}", height, index))?;
+
+        let state: DynState = Box::new(MemState::new());
+        apply_genesis_alloc(&state, &self.config.genesis_alloc).await?;
+        let mut vm = self.config.vm.clone();
+        for block in self.get_blocks(1..height).await? {
+            self.apply_transactions(&mut vm, &state, &block).await?;
+        }
+
+        // the transactions before it in its own block
+        let mut before = block.clone();
+        before.transactions.truncate(index);
+        self.apply_transactions(&mut vm, &state, &before).await?;
+
+        let recorder = TraceRecorder::new();
+        vm.set_tracer(Some(Box::new(recorder.clone())));
+        let tx_hash = self.config.hashers.tx_hasher.hash(tx)?;
+        use_tx_nonce(&state, tx).await?;
+        charge_fee(&state, tx).await?;
+        transfer_value(&state, tx).await?;
+        let execution =
+            execute_transaction(&mut vm, &state, tx, &tx_context(&block, tx_hash)).await;
+
+        Ok(recorder.take_trace(&execution.result))
+    }
+
+    // The chain id of the genesis block, every block and transaction of the chain has to use it
+    pub fn chain_id(&self) -> u64 {
+        self.config.genesis_block.header.chain_id
+    }
+
+    // Balance of the account in the state of the head of the main chain
+    pub async fn balance(&self, account: &Address) -> u64 {
+        balance(&self.config.state, account).await
+    }
+
+    // Nonce the next transaction of the account has to use on top of the head of the main chain
+    pub async fn nonce(&self, account: &Address) -> u64 {
+        account_nonce(&self.config.state, account).await
+    }
+
+    // The state root and the receipts root after executing the block on top of the current state,
+    // used by validators to fill in the header of a new block. The state is left untouched
+    pub async fn compute_roots(&self, block: &Block) -> Result<(Hash, Hash)> {
+        let _guard = self.lock.lock().await;
+
+        self.config.state.checkpoint().await?;
+        let result = self.run_transactions(block).await;
+        self.config.state.revert().await?;
+
+        result.map(|execution| (execution.state_root, execution.receipts_root))
+    }
+
+    // Receipts of the transactions of a block on the main chain, in the order of the transactions
+    pub async fn get_receipts(&self, block_hash: &Hash) -> Option<Vec<Receipt>> {
+        self.receipts.read().await.get(block_hash).cloned()
+    }
+
+    pub async fn get_receipts_by_height(&self, height: u32) -> Option<Vec<Receipt>> {
+        let header = self.get_header(height).await?;
+        let hash = Block::hash_header(&header, &self.config.hashers.block_hasher).ok()?;
+        self.get_receipts(&hash).await
+    }
+
+    // Receipt of a transaction on the main chain
+    pub async fn get_receipt(&self, tx_hash: &Hash) -> Option<Receipt> {
+        let (block_hash, index) = *self.tx_locations.read().await.get(tx_hash)?;
+        self.receipts
+            .read()
+            .await
+            .get(&block_hash)?
+            .get(index)
+            .cloned()
     }
 
     async fn add_block_without_validation(&self, block: Block) -> Result<()> {
@@ -68,8 +632,8 @@ impl Blockchain {
         self.save_block(block).await
     }
 
-    pub async fn has_block(&self, height: u32) -> bool {
-        self.block_headers.read().await.len() > height as usize
+    pub async fn has_block(&self, hash: &Hash) -> bool {
+        self.tree.read().await.contains(hash)
     }
 
     pub async fn get_header(&self, height: u32) -> Option<BlockHeader> {
@@ -78,6 +642,30 @@ impl Blockchain {
         Some(block_header.clone())
     }
 
+    // Get the header of any known block, including blocks on side branches
+    pub async fn get_header_by_hash(&self, hash: &Hash) -> Option<BlockHeader> {
+        self.get_tree_entry(Some(hash))
+            .await
+            .map(|entry| entry.header)
+    }
+
+    async fn get_tree_entry(&self, hash: Option<&Hash>) -> Option<TreeEntry> {
+        self.tree.read().await.get(hash?).cloned()
+    }
+
+    // The last block of the main chain
+    async fn head(&self) -> Result<TreeEntry> {
+        let header = self
+            .get_header(self.height().await)
+            .await
+            .ok_or_else(|| anyhow!("blockchain has no blocks"))?;
+        let hash = Block::hash_header(&header, &self.config.hashers.block_hasher)?;
+
+        self.get_tree_entry(Some(&hash))
+            .await
+            .ok_or_else(|| anyhow!("block tree has no head block {}", hash))
+    }
+
     pub async fn len(&self) -> usize {
         self.block_headers.read().await.len()
     }
@@ -100,47 +688,103 @@ impl Blockchain {
         Ok(blocks)
     }
 
+    // Get a block of the main chain
     pub async fn get_block(&self, height: u32) -> Result<Block> {
-        let bytes = self
+        let hash = self
             .config
             .storage
             .get(&height.to_le_bytes())
             .await
             .ok_or_else(|| anyhow!("could not get block from storage"))?;
 
+        self.get_block_by_hash(&Hash::from_bytes(&hash)).await
+    }
+
+    pub async fn get_block_by_hash(&self, hash: &Hash) -> Result<Block> {
+        let bytes = self
+            .config
+            .storage
+            .get(&hash_key(hash))
+            .await
+            .ok_or_else(|| anyhow!("could not get block {} from storage", hash))?;
+
         let block = Block::decode(&bytes, &self.config.encoding.decoder)?;
 
         Ok(block)
     }
 
-    async fn save_block(&self, block: Block) -> Result<()> {
-        let bytes = block.encode(&self.config.encoding.encoder)?;
+    // Blocks are stored by their hash, the height only points to the hash of the main chain block
+    async fn save_block(&self, mut block: Block) -> Result<()> {
+        let hash = block.hash(&self.config.hashers.block_hasher)?;
+        self.save_block_by_hash(&hash, &block).await?;
         self.config
             .storage
-            .put(&block.header.height.to_le_bytes(), &bytes)
+            .put(&block.header.height.to_le_bytes(), hash.as_bytes())
             .await;
 
+        // The block is committed to the main chain, make sure it survives a restart
+        self.config.storage.flush().await
+    }
+
+    async fn save_block_by_hash(&self, hash: &Hash, block: &Block) -> Result<()> {
+        let bytes = block.encode(&self.config.encoding.encoder)?;
+        self.config.storage.put(&hash_key(hash), &bytes).await;
+
+        // Every block is also listed under its parent, that's how side branches are found on a restart
+        if let Some(parent) = block.header.prev_block_header_hash {
+            let mut children = self.stored_children(&parent).await;
+            if !children.contains(hash) {
+                children.push(*hash);
+                let bytes = children.iter().map(Hash::as_bytes).collect::<Vec<_>>();
+                self.config
+                    .storage
+                    .put(&children_key(&parent), &bytes.concat())
+                    .await;
+            }
+        }
         Ok(())
     }
 
     pub async fn height(&self) -> u32 {
         self.block_headers.read().await.len() as u32 - 1
     }
+}
 
-    // Get the previous block header
-    pub async fn get_prev_header(&self, height: u32) -> Option<BlockHeader> {
-        if height == 0 {
-            self.get_header(0).await
-        } else {
-            self.get_header(height - 1).await
-        }
+// What a transaction of the block can know about it. The validator is set before the block
+// is executed, see Validator::create_new_block
+fn tx_context(block: &Block, tx_hash: Hash) -> ExecutionContext {
+    ExecutionContext {
+        tx_hash,
+        block_height: block.header.height,
+        block_timestamp: u64::try_from(block.header.timestamp).unwrap_or(u64::MAX),
+        validator: block.validator_public_key.as_ref().map(|key| key.address()),
+        ..Default::default()
     }
 }
 
+fn hash_key(hash: &Hash) -> Vec<u8> {
+    [b"block/".as_slice(), hash.as_bytes()].concat()
+}
+
+fn children_key(hash: &Hash) -> Vec<u8> {
+    [b"children/".as_slice(), hash.as_bytes()].concat()
+}
+
 #[cfg(test)]
 mod tests {
 
-    use crate::{config::Config, util::random_block};
+    use crate::{
+        config::Config,
+        core::{
+            contract_address, contract_storage, global_storage, timestamp_now,
+            vm::{
+                bytecode_vm::asm::assemble, trace::StateAccess, Log, VmError, DEFAULT_TX_GAS_LIMIT,
+            },
+            Address, HeaviestChain, BLOCK_HEADER_SIZE, MAX_FUTURE_BLOCK_TIME,
+        },
+        crypto::PrivateKey,
+        util::{random_block, random_hash},
+    };
 
     use super::*;
 
@@ -173,4 +817,1069 @@ mod tests {
 
         Ok(())
     }
+
+    // stores 2 at key 4
+    fn store_tx() -> Transaction {
+        let mut tx = Transaction::new(vec![
+            0xaa, 0x02, 0x00, 0x00, 0x00, 0xaa, 0x04, 0x00, 0x00, 0x00, 0xbc,
+        ]);
+        tx.sign(&PrivateKey::generate());
+        tx
+    }
+
+    // stores 5 at key 7 and then fails because it adds a bool to an int
+    fn failing_tx() -> Transaction {
+        let mut tx = Transaction::new(vec![
+            0xaa, 0x05, 0x00, 0x00, 0x00, 0xaa, 0x07, 0x00, 0x00, 0x00, 0xbc, 0xab, 0x01, 0xaa,
+            0x01, 0x00, 0x00, 0x00, 0xad,
+        ]);
+        tx.sign(&PrivateKey::generate());
+        tx
+    }
+
+    // A chain with its own storage and state, used like a validator to produce blocks
+    async fn producer() -> Result<Blockchain> {
+        Blockchain::new(Config::default().blockchain_config()).await
+    }
+
+    // Create a block on top of the producers head the same way the validator does
+    async fn produce_block(producer: &Blockchain, txs: Vec<Transaction>) -> Result<Block> {
+        let config = &producer.config;
+        let head = producer.get_header(producer.height().await).await.unwrap();
+
+        let mut block = Block::from_prev_header(
+            &head,
+            txs,
+            &config.encoding.encoder,
+            &config.hashers.block_hasher,
+            &config.hashers.tx_hasher,
+        )?;
+        let private_key = PrivateKey::generate();
+        block.validator_public_key = Some(private_key.public_key());
+        (block.header.state_root, block.header.receipts_root) =
+            producer.compute_roots(&block).await?;
+        block.sign(&private_key)?;
+
+        producer.add_block(block.clone()).await?;
+
+        Ok(block)
+    }
+
+    fn hash(config: &Config, block: &Block) -> Result<Hash> {
+        Block::hash_header(&block.header, &config.hashers.block_hasher)
+    }
+
+    async fn head_hash(bc: &Blockchain) -> Result<Hash> {
+        Ok(bc.head().await?.hash)
+    }
+
+    #[tokio::test]
+    async fn test_forged_block_hash_is_ignored() -> Result<()> {
+        let config = Config::default();
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        let block = produce_block(&producer().await?, vec![]).await?;
+
+        // a peer sends the block with a hash that doesn't belong to it
+        let mut json = serde_json::to_value(&block)?;
+        json["hash"] = serde_json::to_value(random_hash())?;
+        let forged: Block = serde_json::from_value(json)?;
+
+        bc.add_block(forged).await?;
+        assert_eq!(head_hash(&bc).await?, hash(&config, &block)?);
+        assert!(bc.add_block(block).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_side_branch_does_not_reorg() -> Result<()> {
+        let config = Config::default();
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        let (producer_a, producer_b) = (producer().await?, producer().await?);
+
+        let a1 = produce_block(&producer_a, vec![store_tx()]).await?;
+        let a2 = produce_block(&producer_a, vec![]).await?;
+        let b1 = produce_block(&producer_b, vec![]).await?;
+
+        bc.add_block(a1).await?;
+        bc.add_block(a2.clone()).await?;
+        bc.add_block(b1.clone()).await?;
+
+        assert_eq!(bc.height().await, 2);
+        assert_eq!(head_hash(&bc).await?, hash(&config, &a2)?);
+
+        // the side block is known but not part of the main chain
+        let b1_hash = hash(&config, &b1)?;
+        assert!(bc.has_block(&b1_hash).await);
+        assert!(bc.get_block_by_hash(&b1_hash).await.is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reorg_reverts_state() -> Result<()> {
+        let config = Config::default();
+        let bc_config = config.blockchain_config();
+        let bc = Blockchain::new(bc_config.clone()).await?;
+        let (producer_a, producer_b) = (producer().await?, producer().await?);
+
+        let a1 = produce_block(&producer_a, vec![store_tx()]).await?;
+        let b1 = produce_block(&producer_b, vec![]).await?;
+        let b2 = produce_block(&producer_b, vec![]).await?;
+
+        let update = bc.add_block(a1.clone()).await?;
+        assert_eq!(update.enacted.len(), 1);
+        assert_eq!(
+            global_storage(&bc_config.state).get(&[4, 0, 0, 0]).await?,
+            vec![0, 2, 0, 0, 0]
+        );
+
+        // b1 can already win the tie against a1, in any case both blocks end up enacted
+        let mut update = bc.add_block(b1.clone()).await?;
+        let b2_update = bc.add_block(b2.clone()).await?;
+        update.enacted.extend(b2_update.enacted);
+        update.retracted.extend(b2_update.retracted);
+        let hashes = |blocks: &[Block]| -> Result<Vec<Hash>> {
+            blocks.iter().map(|block| hash(&config, block)).collect()
+        };
+        assert_eq!(hashes(&update.retracted)?, [hash(&config, &a1)?]);
+        assert_eq!(
+            hashes(&update.enacted)?,
+            [hash(&config, &b1)?, hash(&config, &b2)?]
+        );
+
+        assert_eq!(bc.height().await, 2);
+        assert_eq!(head_hash(&bc).await?, hash(&config, &b2)?);
+        // the store of a1 got rolled back
+        assert!(global_storage(&bc_config.state)
+            .get(&[4, 0, 0, 0])
+            .await
+            .is_err());
+        assert_eq!(bc_config.state.root().await?, b2.header.state_root);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reorg_depth_limit() -> Result<()> {
+        let mut config = Config::default();
+        config.limits.max_reorg_depth = 1;
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+
+        let (producer_a, producer_b, producer_c) =
+            (producer().await?, producer().await?, producer().await?);
+        let a1 = produce_block(&producer_a, vec![]).await?;
+        let a2 = produce_block(&producer_a, vec![store_tx()]).await?;
+        producer_c.add_block(a1.clone()).await?;
+        let c2 = produce_block(&producer_c, vec![]).await?;
+        let c3 = produce_block(&producer_c, vec![]).await?;
+        let b_blocks = [
+            produce_block(&producer_b, vec![]).await?,
+            produce_block(&producer_b, vec![]).await?,
+            produce_block(&producer_b, vec![]).await?,
+        ];
+
+        bc.add_block(a1).await?;
+        bc.add_block(a2.clone()).await?;
+        // only the journal of the head is kept
+        assert_eq!(bc.journals.read().await.len(), 1);
+
+        // the heavier b branch would take off two blocks
+        let [b1, b2, b3] = b_blocks;
+        bc.add_block(b1).await?;
+        // b2 ties with a2, if its hash is lower the reorg is refused already
+        let _ = bc.add_block(b2).await;
+        assert!(bc.add_block(b3).await.is_err());
+        assert_eq!(head_hash(&bc).await?, hash(&config, &a2)?);
+        assert!(global_storage(&bc.config.state)
+            .get(&[4, 0, 0, 0])
+            .await
+            .is_ok());
+
+        // a reorg of one block still works
+        bc.add_block(c2).await?;
+        bc.add_block(c3.clone()).await?;
+        assert_eq!(head_hash(&bc).await?, hash(&config, &c3)?);
+        assert!(global_storage(&bc.config.state)
+            .get(&[4, 0, 0, 0])
+            .await
+            .is_err());
+        assert_eq!(bc.journals.read().await.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_trie_state_blocks() -> Result<()> {
+        let producer =
+            Blockchain::new(Config::default().with_trie_state().blockchain_config()).await?;
+        let b1 = produce_block(&producer, vec![store_tx()]).await?;
+        assert_ne!(b1.header.state_root, Hash::zero());
+
+        let bc_config = Config::default().with_trie_state().blockchain_config();
+        let bc = Blockchain::new(bc_config.clone()).await?;
+        bc.add_block(b1.clone()).await?;
+
+        assert_eq!(bc_config.state.root().await?, b1.header.state_root);
+        assert_eq!(
+            global_storage(&bc_config.state).get(&[4, 0, 0, 0]).await?,
+            vec![0, 2, 0, 0, 0]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deploy_and_call_contract() -> Result<()> {
+        let private_key = PrivateKey::generate();
+        let mut deploy = Transaction::deploy(assemble("PushInt 2\nPushInt 4\nStore")?);
+        deploy.sign(&private_key);
+
+        let producer = producer().await?;
+        let b1 = produce_block(&producer, vec![deploy]).await?;
+        let receipts = producer.get_receipts(&hash(&Config::default(), &b1)?).await;
+        let contract = receipts.unwrap()[0].contract_address.unwrap();
+        assert_eq!(
+            contract,
+            contract_address(&private_key.public_key().address(), 0)
+        );
+
+        let mut call = Transaction::call(contract, vec![]).with_nonce(1);
+        call.sign(&private_key);
+        produce_block(&producer, vec![call]).await?;
+
+        // the contract stored into its own storage, not the global state
+        let state = &producer.config.state;
+        assert_eq!(
+            contract_storage(state, &contract)
+                .get(&[4, 0, 0, 0])
+                .await?,
+            vec![0, 2, 0, 0, 0]
+        );
+        assert!(global_storage(state).get(&[4, 0, 0, 0]).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_execution_context() -> Result<()> {
+        // stores the height, the sender, the validator and the timestamp of the block it runs in
+        let code = assemble(
+            "BlockHeight\nPushInt 1\nStore\nCaller\nPushInt 2\nStore\nValidator\nPushInt 3\nStore\n\
+             Timestamp\nPushInt 4\nStore",
+        )?;
+        let private_key = PrivateKey::generate();
+        let tx = |nonce| {
+            let mut tx = Transaction::new(code.clone()).with_nonce(nonce);
+            tx.sign(&private_key);
+            tx
+        };
+
+        let producer = producer().await?;
+        let state = &producer.config.state;
+        // the values are stored with the tag of their type, 2 is U64 and 6 is Bytes
+        let bytes = |address: Address| [&[6], address.as_bytes()].concat();
+        for height in 1..=3u64 {
+            let block = produce_block(&producer, vec![tx(height - 1)]).await?;
+
+            // the same code gives a different result in every block
+            assert_eq!(
+                global_storage(state).get(&[1, 0, 0, 0]).await?,
+                [&[2], height.to_le_bytes().as_slice()].concat()
+            );
+            assert_eq!(
+                global_storage(state).get(&[2, 0, 0, 0]).await?,
+                bytes(private_key.public_key().address())
+            );
+            let validator = block.validator_public_key.unwrap().address();
+            assert_eq!(
+                global_storage(state).get(&[3, 0, 0, 0]).await?,
+                bytes(validator)
+            );
+            let timestamp = block.header.timestamp as u64;
+            assert_eq!(
+                global_storage(state).get(&[4, 0, 0, 0]).await?,
+                [&[2], timestamp.to_le_bytes().as_slice()].concat()
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replay_transaction() -> Result<()> {
+        let tx = |source: &str| -> Result<Transaction> {
+            let mut tx = Transaction::new(assemble(source)?);
+            tx.sign(&PrivateKey::generate());
+            Ok(tx)
+        };
+
+        let producer = producer().await?;
+        produce_block(&producer, vec![tx("PushInt 2\nPushInt 4\nStore")?]).await?;
+        produce_block(
+            &producer,
+            vec![
+                tx("PushInt 3\nPushInt 4\nStore")?,
+                // doubles the value at key 4
+                tx("PushInt 4\nGet\nPushInt 2\nMul\nPushInt 4\nStore")?,
+            ],
+        )
+        .await?;
+        // changes the value again after the replayed transaction
+        produce_block(&producer, vec![tx("PushInt 9\nPushInt 4\nStore")?]).await?;
+
+        // the transaction reads the value the transaction before it in the same block stored
+        let trace = producer.replay_transaction(2, 1).await?;
+        assert_eq!(trace.error, None);
+        assert_eq!(trace.steps.len(), 6);
+        assert_eq!(
+            trace.steps[1].state,
+            [StateAccess::Read {
+                key: vec![4, 0, 0, 0],
+                value: Some(vec![0, 3, 0, 0, 0]),
+            }]
+        );
+        assert_eq!(
+            trace.steps[5].state,
+            [StateAccess::Write {
+                key: vec![4, 0, 0, 0],
+                value: vec![0, 6, 0, 0, 0],
+            }]
+        );
+        let receipts = producer.get_receipts_by_height(2).await.unwrap();
+        assert_eq!(trace.gas_used, receipts[1].gas_used);
+
+        // the state of the chain is untouched
+        assert_eq!(
+            global_storage(&producer.config.state)
+                .get(&[4, 0, 0, 0])
+                .await?,
+            vec![0, 9, 0, 0, 0]
+        );
+
+        assert!(producer.replay_transaction(2, 2).await.is_err());
+        assert!(producer.replay_transaction(4, 0).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wasm_contract() -> Result<()> {
+        // stores the input of a call at "key" in the storage of the contract
+        let code = wat::parse_str(
+            r#"(module
+                (import "env" "set" (func $set (param i32 i32 i32 i32)))
+                (import "env" "input_len" (func $input_len (result i32)))
+                (import "env" "input" (func $input (param i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "key")
+                (func (export "main")
+                    (call $input (i32.const 16))
+                    (call $set (i32.const 0) (i32.const 3) (i32.const 16) (call $input_len))))"#,
+        )?;
+
+        let private_key = PrivateKey::generate();
+        let mut deploy = Transaction::deploy(code);
+        deploy.sign(&private_key);
+        let contract = contract_address(&private_key.public_key().address(), 0);
+        let mut call = Transaction::call(contract, b"hello".to_vec()).with_nonce(1);
+        call.sign(&private_key);
+
+        let config = Config::default().with_wasm_vm();
+        let producer = Blockchain::new(config.blockchain_config()).await?;
+        let block = produce_block(&producer, vec![deploy, call]).await?;
+
+        // another node with the wasm vm comes to the same state
+        let bc_config = Config::default().with_wasm_vm().blockchain_config();
+        let bc = Blockchain::new(bc_config.clone()).await?;
+        bc.add_block(block).await?;
+
+        let receipts = bc.get_receipts(&head_hash(&bc).await?).await.unwrap();
+        assert!(receipts.iter().all(|receipt| receipt.success()));
+        assert_eq!(
+            contract_storage(&bc_config.state, &contract)
+                .get(b"key")
+                .await?,
+            b"hello".to_vec()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_value() -> Result<()> {
+        let alice = PrivateKey::generate();
+        let bob = Address::from_bytes(&[1; 20]);
+        let alloc = vec![(alice.public_key().address(), 100)];
+        let config = Config::default().with_genesis_alloc(alloc.clone());
+
+        // the allocation is part of the genesis block
+        let default_genesis = Config::default().genesis_block;
+        assert_ne!(
+            hash(&config, &config.genesis_block)?,
+            hash(&config, &default_genesis)?
+        );
+
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        assert_eq!(bc.balance(&alice.public_key().address()).await, 100);
+
+        let mut transfer = Transaction::transfer(bob, 30);
+        transfer.sign(&alice);
+        // the execution fails so the transfer is reverted as well
+        let mut failing = failing_tx().with_value(bob, 50).with_nonce(1);
+        failing.sign(&alice);
+        let block = produce_block(&bc, vec![transfer, failing]).await?;
+
+        assert_eq!(bc.balance(&alice.public_key().address()).await, 70);
+        assert_eq!(bc.balance(&bob).await, 30);
+        // the failed transaction still used its nonce
+        assert_eq!(bc.nonce(&alice.public_key().address()).await, 2);
+
+        // another node with the same allocation ends up with the same balances
+        let other = Blockchain::new(
+            Config::default()
+                .with_genesis_alloc(alloc)
+                .blockchain_config(),
+        )
+        .await?;
+        other.add_block(block).await?;
+        assert_eq!(other.balance(&alice.public_key().address()).await, 70);
+        assert_eq!(other.balance(&bob).await, 30);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_execute_cant_change_balance() -> Result<()> {
+        let alice = PrivateKey::generate();
+        let address = alice.public_key().address();
+        let config = Config::default().with_genesis_alloc(vec![(address, 10)]);
+        let producer = Blockchain::new(config.blockchain_config()).await?;
+
+        // stores a huge value at "balance/" followed by the address of alice
+        let mut tx = Transaction::new(assemble(&format!(
+            "PushBytes 0xffffffffffffff\nPushBytes 0x62616c616e63652f{address}\nStore"
+        ))?);
+        tx.sign(&alice);
+        produce_block(&producer, vec![tx]).await?;
+
+        let receipts = producer.get_receipts_by_height(1).await.unwrap();
+        assert!(receipts[0].success());
+        assert_eq!(producer.balance(&address).await, 10);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fees_go_to_validator() -> Result<()> {
+        let alice = PrivateKey::generate();
+        let config =
+            Config::default().with_genesis_alloc(vec![(alice.public_key().address(), 1_000)]);
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+
+        let sign = |tx: Transaction, nonce| {
+            let mut tx = tx.with_gas_limit(100).with_gas_price(3).with_nonce(nonce);
+            tx.sign(&alice);
+            tx
+        };
+        // a failed transaction pays for the gas it used as well
+        let txs = vec![sign(store_tx(), 0), sign(failing_tx(), 1)];
+        let mut block = produce_block(&bc, txs).await?;
+
+        let receipts = bc
+            .get_receipts(&block.hash(&config.hashers.block_hasher)?)
+            .await;
+        let gas_used: u64 = receipts.unwrap().iter().map(|r| r.gas_used).sum();
+        assert!(gas_used > 0);
+        let validator = block.validator_public_key.unwrap().address();
+        assert_eq!(bc.balance(&validator).await, 3 * gas_used);
+        assert_eq!(
+            bc.balance(&alice.public_key().address()).await,
+            1_000 - 3 * gas_used
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_block_with_unpayable_transfer() -> Result<()> {
+        let alice = PrivateKey::generate();
+        let config = Config::default().with_genesis_alloc(vec![(alice.public_key().address(), 10)]);
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+
+        let mut transfer = Transaction::transfer(Address::from_bytes(&[1; 20]), 50);
+        transfer.sign(&alice);
+        let mut block = Block::from_prev_header(
+            &config.genesis_block.header,
+            vec![store_tx(), transfer],
+            &config.encoding.encoder,
+            &config.hashers.block_hasher,
+            &config.hashers.tx_hasher,
+        )?;
+        block.sign(&PrivateKey::generate())?;
+
+        let err = bc
+            .add_block(block)
+            .await
+            .expect_err("alice can't pay the transfer");
+        assert!(matches!(
+            err.downcast_ref::<McError>(),
+            Some(McError::InsufficientBalance {
+                balance: 10,
+                value: 50,
+                ..
+            })
+        ));
+
+        // the state is left untouched
+        assert_eq!(bc.height().await, 0);
+        assert_eq!(bc.balance(&alice.public_key().address()).await, 10);
+        assert!(global_storage(&bc.config.state)
+            .get(&[4, 0, 0, 0])
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_replayed_transaction() -> Result<()> {
+        let bc = producer().await?;
+        let tx = store_tx();
+        let b1 = produce_block(&bc, vec![tx.clone()]).await?;
+
+        // the same signed transaction again in the next block
+        let config = &bc.config;
+        let mut block = Block::from_prev_header(
+            &b1.header,
+            vec![tx.clone()],
+            &config.encoding.encoder,
+            &config.hashers.block_hasher,
+            &config.hashers.tx_hasher,
+        )?;
+        block.sign(&PrivateKey::generate())?;
+
+        let err = bc.add_block(block).await.expect_err("nonce 0 is used");
+        assert!(matches!(
+            err.downcast_ref::<McError>(),
+            Some(McError::InvalidNonce {
+                expected: 1,
+                found: 0,
+                ..
+            })
+        ));
+        assert_eq!(bc.height().await, 1);
+        assert_eq!(bc.nonce(&tx.sender().unwrap()).await, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_execute_cant_change_nonce() -> Result<()> {
+        let bc = producer().await?;
+        let victim_tx = store_tx();
+        let victim = victim_tx.sender().unwrap();
+        produce_block(&bc, vec![victim_tx.clone()]).await?;
+
+        // stores at "tx_nonce/" followed by the address of the victim
+        let mut tx = Transaction::new(assemble(&format!(
+            "PushBytes 0x00000000000000\nPushBytes 0x74785f6e6f6e63652f{victim}\nStore"
+        ))?);
+        tx.sign(&PrivateKey::generate());
+        produce_block(&bc, vec![tx]).await?;
+        assert!(bc.get_receipts_by_height(2).await.unwrap()[0].success());
+
+        // the nonce of the victim is still used up, its transaction can't be replayed
+        assert_eq!(bc.nonce(&victim).await, 1);
+        assert!(produce_block(&bc, vec![victim_tx]).await.is_err());
+        assert_eq!(bc.height().await, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chain_id() -> Result<()> {
+        let config = Config::default().with_chain_id(2);
+        let default_genesis = Config::default().genesis_block;
+        assert_ne!(
+            hash(&config, &config.genesis_block)?,
+            hash(&config, &default_genesis)?
+        );
+
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        let private_key = PrivateKey::generate();
+        let mut tx = Transaction::new(vec![]).with_chain_id(2);
+        tx.sign(&private_key);
+        let block = produce_block(&bc, vec![tx]).await?;
+        assert_eq!(block.header.chain_id, 2);
+
+        // a transaction signed for another chain makes the block invalid
+        let err = produce_block(&bc, vec![store_tx()])
+            .await
+            .expect_err("the transaction is signed for chain 1");
+        assert!(matches!(
+            err.downcast_ref::<McError>(),
+            Some(McError::ChainIdMismatch {
+                expected: 2,
+                found: 1
+            })
+        ));
+        assert_eq!(bc.height().await, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_tx_is_reverted() -> Result<()> {
+        for trie in [false, true] {
+            // every config has its own storage
+            let config = || match trie {
+                true => Config::default().with_trie_state(),
+                false => Config::default(),
+            };
+
+            let producer = Blockchain::new(config().blockchain_config()).await?;
+            let block = produce_block(&producer, vec![failing_tx(), store_tx()]).await?;
+
+            let bc_config = config().blockchain_config();
+            let bc = Blockchain::new(bc_config.clone()).await?;
+            bc.add_block(block).await?;
+
+            // the block is valid, only the changes of the failed transaction are gone
+            assert_eq!(bc.height().await, 1);
+            assert!(global_storage(&bc_config.state)
+                .get(&[7, 0, 0, 0])
+                .await
+                .is_err());
+            assert_eq!(
+                global_storage(&bc_config.state).get(&[4, 0, 0, 0]).await?,
+                vec![0, 2, 0, 0, 0]
+            );
+
+            let receipts = bc.get_receipts(&head_hash(&bc).await?).await.unwrap();
+            assert_eq!(receipts[0].error, Some(VmError::TypeMismatch));
+            assert!(receipts[1].success());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_out_of_gas() -> Result<()> {
+        let bc = producer().await?;
+
+        let mut tx = Transaction::new(vec![
+            0xaa, 0x02, 0x00, 0x00, 0x00, 0xaa, 0x04, 0x00, 0x00, 0x00, 0xbc,
+        ])
+        .with_gas_limit(10);
+        tx.sign(&PrivateKey::generate());
+        produce_block(&bc, vec![tx]).await?;
+
+        let receipts = bc.get_receipts(&head_hash(&bc).await?).await.unwrap();
+        assert_eq!(receipts[0].error, Some(VmError::OutOfGas));
+        assert_eq!(receipts[0].gas_used, 10);
+        assert!(global_storage(&bc.config.state)
+            .get(&[4, 0, 0, 0])
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_block_over_gas_limit() -> Result<()> {
+        let producer = producer().await?;
+        let block = produce_block(&producer, vec![store_tx(), store_tx()]).await?;
+
+        let mut config = Config::default();
+        config.limits.block_gas_limit = DEFAULT_TX_GAS_LIMIT;
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+
+        assert!(bc.add_block(block).await.is_err());
+        assert_eq!(bc.height().await, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_block_with_invalid_timestamp() -> Result<()> {
+        let config = Config::default();
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        let block = produce_block(&producer().await?, vec![]).await?;
+        // the block is produced with the wall clock time
+        assert!(block.header.timestamp > 1_600_000_000 * 1_000_000_000);
+
+        let future = timestamp_now() + 2 * MAX_FUTURE_BLOCK_TIME.as_nanos();
+        for timestamp in [config.genesis_block.header.timestamp, future] {
+            let mut invalid = block.clone();
+            invalid.header.timestamp = timestamp;
+            invalid.sign(&PrivateKey::generate())?;
+
+            assert!(bc.add_block(invalid).await.is_err());
+            assert_eq!(bc.height().await, 0);
+        }
+        bc.add_block(block).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_block_over_size_limits() -> Result<()> {
+        let producer = producer().await?;
+        let block = produce_block(&producer, vec![store_tx(), store_tx()]).await?;
+        let tx_size = store_tx().size();
+
+        // too many transactions, a transaction too large and a block too large are each rejected
+        let limits: [fn(&mut LimitsConfig, usize); 3] = [
+            |limits, _| limits.max_block_txs = 1,
+            |limits, tx_size| limits.max_tx_bytes = tx_size - 1,
+            |limits, tx_size| limits.max_block_bytes = BLOCK_HEADER_SIZE + 2 * tx_size - 1,
+        ];
+        for set_limit in limits {
+            let mut config = Config::default();
+            set_limit(&mut config.limits, tx_size);
+            let bc = Blockchain::new(config.blockchain_config()).await?;
+
+            assert!(bc.add_block(block.clone()).await.is_err());
+            assert_eq!(bc.height().await, 0);
+        }
+
+        // right at the limits the block is accepted
+        let mut config = Config::default();
+        config.limits.max_block_txs = 2;
+        config.limits.max_tx_bytes = tx_size;
+        config.limits.max_block_bytes = block.size();
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        bc.add_block(block).await?;
+        assert_eq!(bc.height().await, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nodes_converge() -> Result<()> {
+        let config = Config::default();
+        let (producer_a, producer_b) = (producer().await?, producer().await?);
+
+        let a1 = produce_block(&producer_a, vec![store_tx()]).await?;
+        let b1 = produce_block(&producer_b, vec![]).await?;
+        let b2 = produce_block(&producer_b, vec![]).await?;
+
+        let node_a = Blockchain::new(config.blockchain_config()).await?;
+        let node_b = Blockchain::new(Config::default().blockchain_config()).await?;
+
+        // both branches have the same weight, the lower hash wins on both nodes
+        node_a.add_block(a1.clone()).await?;
+        node_a.add_block(b1.clone()).await?;
+        node_b.add_block(b1.clone()).await?;
+        node_b.add_block(a1.clone()).await?;
+        assert_eq!(head_hash(&node_a).await?, head_hash(&node_b).await?);
+
+        // the longer branch wins on both nodes
+        node_a.add_block(b2.clone()).await?;
+        node_b.add_block(b2.clone()).await?;
+        let b2_hash = hash(&config, &b2)?;
+        assert_eq!(head_hash(&node_a).await?, b2_hash);
+        assert_eq!(head_hash(&node_b).await?, b2_hash);
+
+        for height in 0..=2 {
+            let a = node_a.get_header(height).await.unwrap();
+            let b = node_b.get_header(height).await.unwrap();
+            assert_eq!(
+                Block::hash_header(&a, &config.hashers.block_hasher)?,
+                Block::hash_header(&b, &config.hashers.block_hasher)?
+            );
+        }
+        assert_eq!(
+            node_a.config.state.root().await?,
+            node_b.config.state.root().await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_heaviest_chain() -> Result<()> {
+        let config = Config {
+            fork_choice: Box::new(HeaviestChain),
+            ..Config::default()
+        };
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        let (producer_a, producer_b) = (producer().await?, producer().await?);
+
+        let a1 = produce_block(&producer_a, vec![]).await?;
+        let a2 = produce_block(&producer_a, vec![]).await?;
+        let b1 = produce_block(&producer_b, vec![store_tx(), store_tx(), store_tx()]).await?;
+
+        bc.add_block(a1).await?;
+        bc.add_block(a2).await?;
+        bc.add_block(b1.clone()).await?;
+
+        // the shorter branch carries more transactions and wins
+        assert_eq!(bc.height().await, 1);
+        assert_eq!(head_hash(&bc).await?, hash(&config, &b1)?);
+        assert!(bc.get_block(2).await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_invalid_state_root() -> Result<()> {
+        let config = Config::default();
+        let bc_config = config.blockchain_config();
+        let bc = Blockchain::new(bc_config.clone()).await?;
+        let genesis = config.genesis_block.header.clone();
+
+        let mut block = Block::from_prev_header(
+            &genesis,
+            vec![store_tx()],
+            &config.encoding.encoder,
+            &config.hashers.block_hasher,
+            &config.hashers.tx_hasher,
+        )?;
+        block.header.state_root = random_hash();
+        block.sign(&PrivateKey::generate())?;
+
+        let err = bc
+            .add_block(block)
+            .await
+            .expect_err("state root is invalid");
+        assert!(matches!(
+            err.downcast_ref::<McError>(),
+            Some(McError::StateRootMismatch { .. })
+        ));
+
+        // the state is left untouched
+        assert_eq!(bc.height().await, 0);
+        assert_eq!(bc_config.state.root().await?, Hash::zero());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reject_invalid_receipts_root() -> Result<()> {
+        let bc_config = Config::default().blockchain_config();
+        let bc = Blockchain::new(bc_config.clone()).await?;
+
+        let producer = producer().await?;
+        let head = producer.get_header(0).await.unwrap();
+        let mut block = Block::from_prev_header(
+            &head,
+            vec![store_tx()],
+            &producer.config.encoding.encoder,
+            &producer.config.hashers.block_hasher,
+            &producer.config.hashers.tx_hasher,
+        )?;
+        (block.header.state_root, _) = producer.compute_roots(&block).await?;
+        block.header.receipts_root = random_hash();
+        block.sign(&PrivateKey::generate())?;
+
+        let err = bc
+            .add_block(block)
+            .await
+            .expect_err("receipts root is invalid");
+        assert!(matches!(
+            err.downcast_ref::<McError>(),
+            Some(McError::ReceiptsRootMismatch { .. })
+        ));
+        assert_eq!(bc_config.state.root().await?, Hash::zero());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_receipts_and_logs() -> Result<()> {
+        let private_key = PrivateKey::generate();
+        // emits a log with one topic and its input as data
+        let mut deploy = Transaction::deploy(assemble(
+            "PushBytes 0x01\nPushByte 1\nInput\nLog\nPushBytes 0x02\nPushInt 1\nStore",
+        )?);
+        deploy.sign(&private_key);
+
+        let producer = producer().await?;
+        produce_block(&producer, vec![deploy]).await?;
+        let contract = contract_address(&private_key.public_key().address(), 0);
+
+        let mut call = Transaction::call(contract, vec![0xaa, 0xbb]).with_nonce(1);
+        call.sign(&private_key);
+        let mut failing = failing_tx();
+        let b2 = produce_block(&producer, vec![call.clone(), failing.clone()]).await?;
+
+        let receipts = producer.get_receipts_by_height(2).await.unwrap();
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(
+            receipts[0].logs,
+            vec![Log {
+                address: Some(contract),
+                topics: vec![vec![0x01]],
+                data: vec![0xaa, 0xbb],
+            }]
+        );
+        assert!(receipts[0].gas_used > 0);
+        assert_eq!(receipts[1].error_message(), Some("type mismatch".into()));
+        assert_eq!(
+            b2.header.receipts_root,
+            receipts_root(&receipts, &producer.config.encoding.encoder)?
+        );
+
+        let tx_hasher = &producer.config.hashers.tx_hasher;
+        let failing_hash = failing.hash(tx_hasher.clone()).await?;
+        assert_eq!(
+            producer.get_receipt(&failing_hash).await,
+            Some(receipts[1].clone())
+        );
+        assert_eq!(producer.get_receipt(&random_hash()).await, None);
+
+        // receipts of rolled back blocks are gone
+        producer.rollback_to(1).await?;
+        assert_eq!(producer.get_receipt(&failing_hash).await, None);
+        assert_eq!(producer.get_receipts_by_height(2).await, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compute_roots_leaves_state() -> Result<()> {
+        let bc = producer().await?;
+        let head = bc.get_header(0).await.unwrap();
+
+        let block = Block::from_prev_header(
+            &head,
+            vec![store_tx()],
+            &bc.config.encoding.encoder,
+            &bc.config.hashers.block_hasher,
+            &bc.config.hashers.tx_hasher,
+        )?;
+
+        let (state_root, receipts_root) = bc.compute_roots(&block).await?;
+        assert_ne!(state_root, Hash::zero());
+        assert_ne!(receipts_root, Hash::zero());
+        assert_eq!(bc.config.state.root().await?, Hash::zero());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart_from_file_storage() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("muckchain-bc-{}", random_hash()));
+        let config = Config::default().with_file_storage(&dir)?;
+
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        produce_block(&bc, vec![store_tx()]).await?;
+        let b2 = produce_block(&bc, vec![]).await?;
+        drop(bc);
+
+        let config = Config::default().with_file_storage(&dir)?;
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+
+        assert_eq!(bc.height().await, 2);
+        assert_eq!(head_hash(&bc).await?, hash(&config, &b2)?);
+
+        // the restored chain can be extended
+        produce_block(&bc, vec![]).await?;
+        assert_eq!(bc.height().await, 3);
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart_rebuilds_state() -> Result<()> {
+        let config = Config::default();
+
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        produce_block(&bc, vec![store_tx()]).await?;
+        drop(bc);
+
+        // same storage but a fresh state
+        let bc_config = config.blockchain_config();
+        let bc = Blockchain::new(bc_config.clone()).await?;
+
+        assert_eq!(bc.height().await, 1);
+        assert_eq!(
+            global_storage(&bc_config.state).get(&[4, 0, 0, 0]).await?,
+            vec![0, 2, 0, 0, 0]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart_keeps_side_branches() -> Result<()> {
+        let config = Config::default();
+        let (producer_a, producer_b) = (producer().await?, producer().await?);
+        let a1 = produce_block(&producer_a, vec![store_tx()]).await?;
+        let a2 = produce_block(&producer_a, vec![]).await?;
+        let b1 = produce_block(&producer_b, vec![]).await?;
+        let b2 = produce_block(&producer_b, vec![]).await?;
+        let b3 = produce_block(&producer_b, vec![]).await?;
+
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        bc.add_block(a1).await?;
+        bc.add_block(a2.clone()).await?;
+        bc.add_block(b1.clone()).await?;
+        drop(bc);
+
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        assert_eq!(head_hash(&bc).await?, hash(&config, &a2)?);
+        assert!(bc.has_block(&hash(&config, &b1)?).await);
+
+        // the branch seen before the restart can still take over
+        bc.add_block(b2).await?;
+        bc.add_block(b3.clone()).await?;
+        assert_eq!(head_hash(&bc).await?, hash(&config, &b3)?);
+        assert!(global_storage(&bc.config.state)
+            .get(&[4, 0, 0, 0])
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart_genesis_mismatch() -> Result<()> {
+        let config = Config::default();
+        Blockchain::new(config.blockchain_config()).await?;
+
+        let mut other = config.clone();
+        other.genesis_block.header.timestamp = 1;
+
+        let err = Blockchain::new(other.blockchain_config())
+            .await
+            .expect_err("genesis block does not match");
+        assert!(matches!(
+            err.downcast_ref::<McError>(),
+            Some(McError::GenesisMismatch { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restart_broken_chain() -> Result<()> {
+        let config = Config::default();
+
+        let bc = Blockchain::new(config.blockchain_config()).await?;
+        produce_block(&bc, vec![]).await?;
+        produce_block(&bc, vec![]).await?;
+        let c1 = produce_block(&producer().await?, vec![store_tx()]).await?;
+
+        // replace the block at height 1 with a block that the block at height 2 doesn't build on
+        bc.save_block(c1.clone()).await?;
+        assert_eq!(
+            config.storage.get(&1u32.to_le_bytes()).await,
+            Some(hash(&config, &c1)?.as_bytes().to_vec())
+        );
+        drop(bc);
+
+        let err = Blockchain::new(config.blockchain_config())
+            .await
+            .expect_err("chain is broken");
+        assert!(matches!(
+            err.downcast_ref::<McError>(),
+            Some(McError::BrokenChain { height: 2, .. })
+        ));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,448 @@
+/*
+    Contracts are code stored in the state under an address. A call runs the code of the contract
+    with the input data of the transaction on a storage only the contract can access.
+
+    Layout of the state:
+
+        nonce/<account>             number of contracts the account has deployed, u64
+        code/<contract>             code of the contract
+        storage/<contract>/<key>    storage of the contract
+        global/<key>                storage of the code of Execute transactions
+
+    Execute transactions run their data as code on a storage of their own below global/, shared by all of them.
+    Like a contract, that code can't reach anything else in the state: the code and storage of the contracts
+    and the balances and nonces of the accounts, see account.rs.
+    Contracts can call other contracts with the Call instruction of the BytecodeVM, see BytecodeVM::call.
+*/
+
+use sha2::{Digest, Sha256};
+
+use super::{
+    state::{namespaced_state::NamespacedState, DynState},
+    vm::{DynVM, ExecutionContext, ExecutionResult, VmError},
+    Address, Transaction, TxKind,
+};
+
+const NONCE_PREFIX: &[u8] = b"nonce/";
+const CODE_PREFIX: &[u8] = b"code/";
+const STORAGE_PREFIX: &[u8] = b"storage/";
+const GLOBAL_PREFIX: &[u8] = b"global/";
+
+// Gas charged for every byte of deployed code
+pub const DEPLOY_GAS_PER_BYTE: u64 = 10;
+
+pub fn contract_address(deployer: &Address, nonce: u64) -> Address {
+    let hash = Sha256::digest([deployer.as_bytes(), &nonce.to_le_bytes()].concat());
+    Address::from_bytes(&hash[hash.len() - 20..])
+}
+
+fn key(prefix: &[u8], address: &Address) -> Vec<u8> {
+    [prefix, address.as_bytes()].concat()
+}
+
+// Keys of a contract are stored below storage/<contract>/, addresses have a fixed length
+// so the storage of one contract is never a prefix of the storage of another one
+pub fn contract_storage(state: &DynState, contract: &Address) -> DynState {
+    let prefix = [key(STORAGE_PREFIX, contract).as_slice(), b"/"].concat();
+    Box::new(NamespacedState::new(state.clone(), prefix))
+}
+
+// The storage of the code of Execute transactions
+pub fn global_storage(state: &DynState) -> DynState {
+    Box::new(NamespacedState::new(state.clone(), GLOBAL_PREFIX.to_vec()))
+}
+
+// The state code runs on, the storage of the contract or the global storage if the code isn't a contract
+pub fn execution_storage(state: &DynState, contract: Option<&Address>) -> DynState {
+    match contract {
+        Some(contract) => contract_storage(state, contract),
+        None => global_storage(state),
+    }
+}
+
+pub async fn contract_code(state: &DynState, contract: &Address) -> Option<Vec<u8>> {
+    state.get(&key(CODE_PREFIX, contract)).await.ok()
+}
+
+// The outcome of a transaction, `contract_address` is set if it deployed a contract
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxExecution {
+    pub result: ExecutionResult,
+    pub contract_address: Option<Address>,
+}
+
+impl From<ExecutionResult> for TxExecution {
+    fn from(result: ExecutionResult) -> Self {
+        Self {
+            result,
+            contract_address: None,
+        }
+    }
+}
+
+fn failed(gas_used: u64, err: VmError) -> TxExecution {
+    ExecutionResult::failed(gas_used, err).into()
+}
+
+// Like the vm, every failure is part of the result so that every node comes to the same outcome.
+// `ctx` describes the block and the hash of the transaction, the sender and the input are taken from `tx`
+pub async fn execute_transaction(
+    vm: &mut DynVM,
+    state: &DynState,
+    tx: &Transaction,
+    ctx: &ExecutionContext,
+) -> TxExecution {
+    let mut ctx = ExecutionContext {
+        sender: tx.sender(),
+        ..ctx.clone()
+    };
+
+    match tx.kind {
+        TxKind::Execute => vm.execute(state, &tx.data, &ctx, tx.gas_limit).await.into(),
+        TxKind::Deploy => deploy(state, tx).await,
+        TxKind::Call(contract) => {
+            let Some(code) = contract_code(state, &contract).await else {
+                return failed(0, VmError::NoContract(contract));
+            };
+            ctx.address = Some(contract);
+            ctx.input = tx.data.clone();
+            vm.execute(state, &code, &ctx, tx.gas_limit).await.into()
+        }
+    }
+}
+
+async fn deploy(state: &DynState, tx: &Transaction) -> TxExecution {
+    let Some(deployer) = tx.sender() else {
+        return failed(0, VmError::MissingSender);
+    };
+
+    let gas_used = (tx.data.len() as u64).saturating_mul(DEPLOY_GAS_PER_BYTE);
+    if gas_used > tx.gas_limit {
+        return failed(tx.gas_limit, VmError::OutOfGas);
+    }
+
+    let nonce_key = key(NONCE_PREFIX, &deployer);
+    let nonce = match state.get(&nonce_key).await {
+        Ok(bytes) => u64::from_le_bytes(bytes.try_into().unwrap_or_default()),
+        Err(_) => 0,
+    };
+    let address = contract_address(&deployer, nonce);
+
+    let stored = async {
+        state.set(&nonce_key, &(nonce + 1).to_le_bytes()).await?;
+        state.set(&key(CODE_PREFIX, &address), &tx.data).await
+    };
+    if let Err(err) = stored.await {
+        return failed(gas_used, VmError::State(err.to_string()));
+    }
+
+    TxExecution {
+        result: ExecutionResult {
+            gas_used,
+            error: None,
+            logs: vec![],
+            return_data: vec![],
+        },
+        contract_address: Some(address),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        core::{
+            state::mem_state::MemState,
+            vm::{
+                bytecode_vm::{asm::assemble, BytecodeVM},
+                trace::TraceRecorder,
+                MAX_CALL_DEPTH,
+            },
+        },
+        crypto::PrivateKey,
+    };
+    use anyhow::Result;
+
+    // Stores its input at key 1
+    fn store_input_code() -> Vec<u8> {
+        assemble("Input\nPushInt 1\nStore").unwrap()
+    }
+
+    fn signed(mut tx: Transaction, private_key: &PrivateKey) -> Transaction {
+        tx.sign(private_key);
+        tx
+    }
+
+    #[tokio::test]
+    async fn test_deploy_and_call() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let mut vm: DynVM = Box::new(BytecodeVM::<256>::new());
+        let private_key = PrivateKey::generate();
+        let deployer = private_key.public_key().address();
+
+        // the same code deployed twice ends up at two addresses
+        let mut contracts = vec![];
+        for nonce in 0..2 {
+            let tx = signed(Transaction::deploy(store_input_code()), &private_key);
+            let execution =
+                execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+            assert_eq!(execution.result.error, None);
+            assert_eq!(
+                execution.result.gas_used,
+                store_input_code().len() as u64 * DEPLOY_GAS_PER_BYTE
+            );
+            assert_eq!(
+                execution.contract_address,
+                Some(contract_address(&deployer, nonce))
+            );
+            contracts.push(contract_address(&deployer, nonce));
+        }
+        assert_eq!(
+            contract_code(&state, &contracts[0]).await,
+            Some(store_input_code())
+        );
+
+        // both contracts store at key 1 without overwriting each other
+        for (contract, input) in contracts.iter().zip([vec![0xaa], vec![0xbb]]) {
+            let tx = signed(Transaction::call(*contract, input), &private_key);
+            let execution =
+                execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+            assert_eq!(execution.result.error, None);
+        }
+        for (contract, input) in contracts.iter().zip([0xaa, 0xbb]) {
+            let value = contract_storage(&state, contract)
+                .get(&[1, 0, 0, 0])
+                .await?;
+            assert_eq!(value, vec![6, input]);
+        }
+        assert!(global_storage(&state).get(&[1, 0, 0, 0]).await.is_err());
+
+        Ok(())
+    }
+
+    // Code that stores 0x01 at every one of the keys, written as they are in the state
+    fn overwrite_code(keys: &[Vec<u8>]) -> Vec<u8> {
+        let hex = |key: &Vec<u8>| key.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let source: Vec<String> = keys
+            .iter()
+            .map(|key| format!("PushBytes 0x01\nPushBytes 0x{}\nStore", hex(key)))
+            .collect();
+        assemble(&source.join("\n")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_execute_cant_reach_contracts() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let mut vm: DynVM = Box::new(BytecodeVM::<256>::new());
+        let private_key = PrivateKey::generate();
+        let deployer = private_key.public_key().address();
+
+        let contract = deploy_all(
+            &mut vm,
+            &state,
+            &private_key,
+            &["PushInt 7\nPushInt 1\nStore".into()],
+        )
+        .await?[0];
+        let tx = signed(Transaction::call(contract, vec![]), &private_key);
+        execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        let code = contract_code(&state, &contract).await;
+
+        // the code, the storage and the deploy nonce stay as they are
+        let storage_key = [
+            key(STORAGE_PREFIX, &contract).as_slice(),
+            b"/",
+            &[1, 0, 0, 0],
+        ]
+        .concat();
+        let keys = [
+            key(CODE_PREFIX, &contract),
+            storage_key,
+            key(NONCE_PREFIX, &deployer),
+        ];
+        let tx = signed(Transaction::new(overwrite_code(&keys)), &private_key);
+        let execution =
+            execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        assert_eq!(execution.result.error, None);
+
+        assert_eq!(contract_code(&state, &contract).await, code);
+        assert_eq!(value(&state, &contract, 1).await, Some(vec![0, 7, 0, 0, 0]));
+        assert_eq!(state.get(&keys[2]).await?, 1u64.to_le_bytes().to_vec());
+        assert!(global_storage(&state).get(&keys[2]).await.is_ok());
+
+        Ok(())
+    }
+
+    // Calls `contract` with the input and the gas, stores whether the call succeeded
+    // at key 2 and the returned data at key 3
+    fn call_code(contract: &Address, input: &str, gas: u64) -> String {
+        format!(
+            "PushU64 {gas}\nPushBytes {input}\nPushBytes 0x{contract}\nCall\nPushInt 2\nStore\nPushInt 3\nStore"
+        )
+    }
+
+    // Deploys the contracts and returns their addresses
+    async fn deploy_all(
+        vm: &mut DynVM,
+        state: &DynState,
+        private_key: &PrivateKey,
+        sources: &[String],
+    ) -> Result<Vec<Address>> {
+        let mut contracts = vec![];
+        for source in sources {
+            let tx = signed(Transaction::deploy(assemble(source)?), private_key);
+            let execution = execute_transaction(vm, state, &tx, &ExecutionContext::default()).await;
+            contracts.push(execution.contract_address.unwrap());
+        }
+        Ok(contracts)
+    }
+
+    async fn value(state: &DynState, contract: &Address, key: u8) -> Option<Vec<u8>> {
+        contract_storage(state, contract)
+            .get(&[key, 0, 0, 0])
+            .await
+            .ok()
+    }
+
+    #[tokio::test]
+    async fn test_cross_contract_call() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let mut vm: DynVM = Box::new(BytecodeVM::<256>::new());
+        let private_key = PrivateKey::generate();
+        let deployer = private_key.public_key().address();
+
+        // the callee stores its input, logs it and returns its caller
+        let callee = contract_address(&deployer, 0);
+        let caller = contract_address(&deployer, 1);
+        let sources = [
+            "Input\nPushInt 1\nStore\nPushByte 0\nInput\nLog\nCaller\nReturn".to_string(),
+            call_code(&callee, "0xaabb", 10_000),
+        ];
+        deploy_all(&mut vm, &state, &private_key, &sources).await?;
+
+        let tx = signed(Transaction::call(caller, vec![]), &private_key);
+        let execution =
+            execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        assert_eq!(execution.result.error, None);
+
+        // every contract works on its own storage, the callee sees the calling contract as its caller
+        assert_eq!(value(&state, &callee, 1).await, Some(vec![6, 0xaa, 0xbb]));
+        assert_eq!(value(&state, &caller, 2).await, Some(vec![4, 1]));
+        assert_eq!(
+            value(&state, &caller, 3).await,
+            Some([&[6], caller.as_bytes()].concat())
+        );
+        assert_eq!(execution.result.logs.len(), 1);
+        assert_eq!(execution.result.logs[0].address, Some(callee));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_nested_call_is_reverted() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let mut vm: DynVM = Box::new(BytecodeVM::<256>::new());
+        let private_key = PrivateKey::generate();
+        let deployer = private_key.public_key().address();
+
+        let reverting = contract_address(&deployer, 0);
+        let looping = contract_address(&deployer, 1);
+        let sources = [
+            "PushInt 7\nPushInt 1\nStore\nPushByte 0\nPushInt 1\nLog\nRevert".to_string(),
+            "loop: PushInt loop\nJump".to_string(),
+            call_code(&reverting, "0x01", 10_000),
+            call_code(&looping, "0x01", 5_000),
+            // more gas than there is, the caller still keeps a 64th
+            call_code(&looping, "0x01", u64::MAX),
+        ];
+        let contracts = deploy_all(&mut vm, &state, &private_key, &sources).await?;
+
+        // the changes and logs of the reverted call are gone, the caller goes on
+        let tx = signed(Transaction::call(contracts[2], vec![]), &private_key);
+        let execution =
+            execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        assert_eq!(execution.result.error, None);
+        assert!(execution.result.logs.is_empty());
+        assert_eq!(value(&state, &reverting, 1).await, None);
+        assert_eq!(value(&state, &contracts[2], 2).await, Some(vec![4, 0]));
+        assert_eq!(value(&state, &contracts[2], 3).await, Some(vec![6]));
+
+        // a call that runs out of gas uses up the gas it was given
+        let tx = signed(Transaction::call(contracts[3], vec![]), &private_key);
+        let execution =
+            execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        assert_eq!(execution.result.error, None);
+        assert_eq!(value(&state, &contracts[3], 2).await, Some(vec![4, 0]));
+        // the pushed input and address cost a gas per byte on top, the stores 10 per byte of the key and value
+        let own_gas = 1 + (3 + 1) + (3 + 20) + 100 + 1 + (1000 + 60) + 1 + (1000 + 50);
+        assert_eq!(execution.result.gas_used, own_gas + 5_000);
+
+        let tx = signed(
+            Transaction::call(contracts[4], vec![]).with_gas_limit(1_000_000),
+            &private_key,
+        );
+        let execution =
+            execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        assert_eq!(execution.result.error, None);
+        assert_eq!(value(&state, &contracts[4], 2).await, Some(vec![4, 0]));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_call_depth() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let mut vm: DynVM = Box::new(BytecodeVM::<256>::new());
+        let private_key = PrivateKey::generate();
+
+        // a contract that calls itself until the call fails
+        let contract = contract_address(&private_key.public_key().address(), 0);
+        let sources = [call_code(&contract, "0x01", u64::MAX)];
+        deploy_all(&mut vm, &state, &private_key, &sources).await?;
+
+        let recorder = TraceRecorder::new();
+        vm.set_tracer(Some(Box::new(recorder.clone())));
+        let tx = signed(
+            Transaction::call(contract, vec![]).with_gas_limit(10_000_000),
+            &private_key,
+        );
+        let execution =
+            execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        assert_eq!(execution.result.error, None);
+
+        let trace = recorder.take_trace(&execution.result);
+        let depth = trace.steps.iter().map(|step| step.depth).max();
+        assert_eq!(depth, Some(MAX_CALL_DEPTH));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_deploy_and_call() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let mut vm: DynVM = Box::new(BytecodeVM::<256>::new());
+        let private_key = PrivateKey::generate();
+
+        let tx = Transaction::deploy(store_input_code());
+        let execution =
+            execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        assert_eq!(execution.result.error, Some(VmError::MissingSender));
+
+        let tx = signed(
+            Transaction::deploy(store_input_code()).with_gas_limit(10),
+            &private_key,
+        );
+        let execution =
+            execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        assert_eq!(execution.result.error, Some(VmError::OutOfGas));
+        assert_eq!(execution.contract_address, None);
+
+        let contract = contract_address(&private_key.public_key().address(), 0);
+        let tx = signed(Transaction::call(contract, vec![]), &private_key);
+        let execution =
+            execute_transaction(&mut vm, &state, &tx, &ExecutionContext::default()).await;
+        assert_eq!(execution.result.error, Some(VmError::NoContract(contract)));
+
+        Ok(())
+    }
+}
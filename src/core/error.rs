@@ -1,9 +1,41 @@
 use serde::{Deserialize, Serialize};
 
-use super::Hash;
+use super::{Address, Hash};
 
 #[derive(thiserror::Error, Debug, Clone, Serialize, Deserialize)]
 pub enum McError {
     #[error("Block {0} already exists!")]
     BlockAlreadyExists(Hash),
+    #[error("Block {hash} has state root {expected} but executing it results in {found}")]
+    StateRootMismatch {
+        hash: Hash,
+        expected: Hash,
+        found: Hash,
+    },
+    #[error("Block {hash} has receipts root {expected} but executing it results in {found}")]
+    ReceiptsRootMismatch {
+        hash: Hash,
+        expected: Hash,
+        found: Hash,
+    },
+    #[error("Stored genesis block {found} does not match the configured genesis block {expected}")]
+    GenesisMismatch { expected: Hash, found: Hash },
+    #[error("Stored block {hash} at height {height} does not build on the block before it")]
+    BrokenChain { height: u32, hash: Hash },
+    #[error("Stored block {hash} at height {height} is corrupted")]
+    CorruptedBlock { height: u32, hash: Hash },
+    #[error("Account {account} has a balance of {balance} and can't pay {value}")]
+    InsufficientBalance {
+        account: Address,
+        balance: u64,
+        value: u64,
+    },
+    #[error("Expected chain id {expected} but found {found}")]
+    ChainIdMismatch { expected: u64, found: u64 },
+    #[error("Account {account} has to use nonce {expected} next but the transaction uses {found}")]
+    InvalidNonce {
+        account: Address,
+        expected: u64,
+        found: u64,
+    },
 }
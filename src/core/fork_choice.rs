@@ -0,0 +1,61 @@
+use crate::prelude::*;
+use dyn_clone::DynClone;
+use std::{cmp::Ordering, fmt::Debug};
+
+pub type DynForkChoice = Box<dyn ForkChoice>;
+
+// A fork choice rule decides which branch of the block tree is the canonical chain.
+// Every block adds a weight to the branch it is part of, the branch with the highest
+// total weight wins. Ties are broken by the lower block hash so that every node picks
+// the same branch, no matter in which order the blocks arrived.
+pub trait ForkChoice: Debug + DynClone + Send + Sync {
+    fn block_weight(&self, block: &Block) -> u64;
+
+    fn compare(&self, a: (u64, &Hash), b: (u64, &Hash)) -> Ordering {
+        a.0.cmp(&b.0).then_with(|| b.1.cmp(a.1))
+    }
+}
+dyn_clone::clone_trait_object!(ForkChoice);
+
+// Every block has the same weight, the branch with the most blocks wins
+#[derive(Debug, Clone)]
+pub struct LongestChain;
+
+impl ForkChoice for LongestChain {
+    fn block_weight(&self, _block: &Block) -> u64 {
+        1
+    }
+}
+
+// Blocks that include more transactions weigh more
+#[derive(Debug, Clone)]
+pub struct HeaviestChain;
+
+impl ForkChoice for HeaviestChain {
+    fn block_weight(&self, block: &Block) -> u64 {
+        1 + block.transactions.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::random_hash;
+
+    #[test]
+    fn test_compare_weight() {
+        let fc = LongestChain;
+        let (a, b) = (random_hash(), random_hash());
+        assert_eq!(fc.compare((2, &a), (1, &b)), Ordering::Greater);
+        assert_eq!(fc.compare((1, &a), (2, &b)), Ordering::Less);
+    }
+
+    #[test]
+    fn test_compare_tie_break() {
+        let fc = LongestChain;
+        let low = Hash::zero();
+        let high = Hash::from_bytes(&[1; 32]);
+        assert_eq!(fc.compare((1, &low), (1, &high)), Ordering::Greater);
+        assert_eq!(fc.compare((1, &high), (1, &low)), Ordering::Less);
+    }
+}
@@ -1,4 +1,6 @@
 use crate::prelude::*;
+
+use super::Address;
 use dyn_clone::DynClone;
 use sha2::{Digest, Sha256};
 use std::fmt::Debug;
@@ -47,9 +49,15 @@ impl Hasher<BlockHeader> for BlockHasher {
 pub struct TxHasher;
 
 impl Hasher<Transaction> for TxHasher {
-    // currently the encoder is not needed here as we only hash the transaction data
+    // the hash covers everything the sender signed and the sender itself, so the same data
+    // sent again with the next nonce or by someone else is a different transaction
     fn hash(&self, tx: &Transaction) -> Result<Hash> {
-        let bytes = tx.data.clone();
+        let sender = tx.sender();
+        let bytes = [
+            tx.signing_digest().as_slice(),
+            sender.as_ref().map_or(&[][..], Address::as_bytes),
+        ]
+        .concat();
         let hash = Hash::from_bytes(Sha256::digest(bytes).as_slice());
         Ok(hash)
     }
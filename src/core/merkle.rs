@@ -0,0 +1,133 @@
+/*
+    Binary merkle tree over transaction hashes.
+    Leaves and inner nodes are hashed with a different prefix, so that a leaf can never be
+    passed off as an inner node. A node without a sibling is moved up to the next level unchanged.
+*/
+
+use crate::prelude::*;
+use sha2::{Digest, Sha256};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    // siblings from the leaf up to the root and on which side of the path they are
+    pub siblings: Vec<(Hash, Side)>,
+}
+
+fn hash_leaf(leaf: &Hash) -> Hash {
+    let mut sha = Sha256::new();
+    sha.update([LEAF_PREFIX]);
+    sha.update(leaf.as_bytes());
+    Hash::from_bytes(sha.finalize().as_slice())
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    let mut sha = Sha256::new();
+    sha.update([NODE_PREFIX]);
+    sha.update(left.as_bytes());
+    sha.update(right.as_bytes());
+    Hash::from_bytes(sha.finalize().as_slice())
+}
+
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+// The root of an empty tree is the zero hash
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Hash::zero();
+    }
+
+    let mut level: Vec<Hash> = leaves.iter().map(hash_leaf).collect();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+pub fn merkle_proof(leaves: &[Hash], mut index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+
+    let mut siblings = vec![];
+    let mut level: Vec<Hash> = leaves.iter().map(hash_leaf).collect();
+
+    while level.len() > 1 {
+        if index % 2 == 1 {
+            siblings.push((level[index - 1], Side::Left));
+        } else if let Some(sibling) = level.get(index + 1) {
+            siblings.push((*sibling, Side::Right));
+        }
+
+        level = next_level(&level);
+        index /= 2;
+    }
+
+    Some(MerkleProof { siblings })
+}
+
+// Check that the transaction with `tx_hash` is part of the tree with the given root
+pub fn verify_tx_inclusion(root: &Hash, tx_hash: &Hash, proof: &MerkleProof) -> bool {
+    let computed =
+        proof
+            .siblings
+            .iter()
+            .fold(hash_leaf(tx_hash), |acc, (sibling, side)| match side {
+                Side::Left => hash_node(sibling, &acc),
+                Side::Right => hash_node(&acc, sibling),
+            });
+
+    computed == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::random_hash;
+
+    #[test]
+    fn test_proofs_for_every_leaf() {
+        for n in 1..=9 {
+            let leaves: Vec<Hash> = (0..n).map(|_| random_hash()).collect();
+            let root = merkle_root(&leaves);
+
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, i).unwrap();
+                assert!(verify_tx_inclusion(&root, leaf, &proof));
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_proof() {
+        let leaves: Vec<Hash> = (0..5).map(|_| random_hash()).collect();
+        let root = merkle_root(&leaves);
+
+        let proof = merkle_proof(&leaves, 2).unwrap();
+        assert!(!verify_tx_inclusion(&root, &leaves[3], &proof));
+        assert!(!verify_tx_inclusion(&root, &random_hash(), &proof));
+        assert!(merkle_proof(&leaves, 5).is_none());
+    }
+
+    #[test]
+    fn test_empty_root() {
+        assert_eq!(merkle_root(&[]), Hash::zero());
+    }
+}
@@ -1,23 +1,35 @@
+mod account;
 mod address;
 mod block;
 mod block_header;
+mod block_tree;
 mod block_validator;
 mod blockchain;
+mod contract;
 pub mod encoding;
 mod error;
+mod fork_choice;
 mod hash;
 mod hasher;
+mod merkle;
+mod receipt;
 pub mod state;
 pub mod storage;
 mod transaction;
 pub mod vm;
 
+pub use account::*;
 pub use address::*;
 pub use block::*;
 pub use block_header::*;
+pub use block_tree::*;
 pub use block_validator::*;
 pub use blockchain::*;
+pub use contract::*;
 pub use error::*;
+pub use fork_choice::*;
 pub use hash::*;
 pub use hasher::*;
+pub use merkle::*;
+pub use receipt::*;
 pub use transaction::*;
@@ -0,0 +1,46 @@
+use crate::prelude::*;
+use sha2::{Digest, Sha256};
+
+use super::{
+    merkle_root,
+    vm::{Log, VmError},
+    Address,
+};
+
+// The outcome of executing a transaction, a failed transaction is still part of the block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub tx_hash: Hash,
+    pub gas_used: u64,
+    pub error: Option<VmError>,
+    // address of the contract a deploy transaction created
+    pub contract_address: Option<Address>,
+    // logs emitted by the transaction, a failed transaction has none
+    pub logs: Vec<Log>,
+}
+
+encodable!(Receipt);
+
+impl Receipt {
+    pub fn success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    pub fn error_message(&self) -> Option<String> {
+        self.error.as_ref().map(ToString::to_string)
+    }
+
+    pub fn hash(&self, enc: &DynEncoder) -> Result<Hash> {
+        let bytes = enc.encode(self)?;
+        Ok(Hash::from_bytes(Sha256::digest(bytes).as_slice()))
+    }
+}
+
+// Merkle root over the hashes of the receipts of a block, in the order of the transactions
+pub fn receipts_root(receipts: &[Receipt], enc: &DynEncoder) -> Result<Hash> {
+    let hashes = receipts
+        .iter()
+        .map(|receipt| receipt.hash(enc))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(merkle_root(&hashes))
+}
@@ -0,0 +1,128 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::Mutex;
+
+use super::{DynState, State};
+use crate::core::Hash;
+
+// Stores the value every key had before it was changed, used to undo the changes of a block
+#[derive(Debug, Clone, Default)]
+pub struct StateJournal {
+    entries: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl StateJournal {
+    // Restore every changed key in reverse order
+    pub async fn revert(self, state: &DynState) -> Result<()> {
+        for (key, prev) in self.entries.into_iter().rev() {
+            match prev {
+                Some(value) => state.set(&key, &value).await?,
+                None => state.delete(&key).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Wraps a state and records every change into a StateJournal
+#[derive(Debug, Clone)]
+pub struct JournaledState {
+    inner: DynState,
+    journal: Arc<Mutex<StateJournal>>,
+}
+
+impl JournaledState {
+    pub fn new(inner: DynState) -> Self {
+        Self {
+            inner,
+            journal: Arc::new(Mutex::new(StateJournal::default())),
+        }
+    }
+
+    pub async fn take_journal(&self) -> StateJournal {
+        std::mem::take(&mut *self.journal.lock().await)
+    }
+
+    async fn record(&self, key: &[u8]) {
+        let prev = self.inner.get(key).await.ok();
+        self.journal.lock().await.entries.push((key.to_vec(), prev));
+    }
+}
+
+#[async_trait::async_trait]
+impl State for JournaledState {
+    async fn set(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.record(key).await;
+        self.inner.set(key, value).await
+    }
+
+    async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.inner.get(key).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.record(key).await;
+        self.inner.delete(key).await
+    }
+
+    async fn root(&self) -> Result<Hash> {
+        self.inner.root().await
+    }
+
+    // A reverted checkpoint restores the values the journal recorded for it,
+    // so reverting the whole journal later still ends up at the original state
+    async fn checkpoint(&self) -> Result<()> {
+        self.inner.checkpoint().await
+    }
+
+    async fn commit(&self) -> Result<()> {
+        self.inner.commit().await
+    }
+
+    async fn revert(&self) -> Result<()> {
+        self.inner.revert().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::state::mem_state::MemState;
+
+    #[tokio::test]
+    async fn test_revert_journal() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        state.set(b"a", b"1").await?;
+
+        let journaled = JournaledState::new(state.clone());
+        journaled.set(b"a", b"2").await?;
+        journaled.set(b"b", b"3").await?;
+        journaled.delete(b"a").await?;
+
+        journaled.take_journal().await.revert(&state).await?;
+
+        assert_eq!(state.get(b"a").await?, b"1".to_vec());
+        assert!(state.get(b"b").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_revert_journal_with_checkpoints() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        state.set(b"a", b"1").await?;
+
+        let journaled = JournaledState::new(state.clone());
+        journaled.set(b"a", b"2").await?;
+        journaled.checkpoint().await?;
+        journaled.set(b"a", b"3").await?;
+        journaled.revert().await?;
+        assert_eq!(state.get(b"a").await?, b"2".to_vec());
+
+        journaled.take_journal().await.revert(&state).await?;
+        assert_eq!(state.get(b"a").await?, b"1".to_vec());
+
+        Ok(())
+    }
+}
@@ -1,18 +1,31 @@
 use std::{collections::HashMap, sync::Arc};
 
+use sha2::{Digest, Sha256};
 use tokio::sync::RwLock;
 
 use super::State;
+use crate::core::Hash;
+
+// previous value of every key changed since a checkpoint, None if the key didn't exist
+type Changes = Vec<(Vec<u8>, Option<Vec<u8>>)>;
 
 #[derive(Debug, Clone)]
 pub struct MemState {
     state: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+    checkpoints: Arc<RwLock<Vec<Changes>>>,
 }
 
 impl MemState {
     pub fn new() -> Self {
         Self {
             state: Arc::new(RwLock::new(HashMap::new())),
+            checkpoints: Arc::new(RwLock::new(vec![])),
+        }
+    }
+
+    async fn record(&self, key: &[u8], prev: Option<Vec<u8>>) {
+        if let Some(changes) = self.checkpoints.write().await.last_mut() {
+            changes.push((key.to_vec(), prev));
         }
     }
 }
@@ -21,13 +34,13 @@ impl MemState {
 impl State for MemState {
     async fn set(&self, key: &[u8], value: &[u8]) -> anyhow::Result<()> {
         let mut state = self.state.write().await;
-        state.insert(key.to_vec(), value.to_vec());
+        let prev = state.insert(key.to_vec(), value.to_vec());
+        self.record(key, prev).await;
         Ok(())
     }
 
     async fn get(&self, key: &[u8]) -> anyhow::Result<Vec<u8>> {
         let state = self.state.read().await;
-        println!("state: {:?}", state);
         Ok(state
             .get(key)
             .ok_or_else(|| anyhow::anyhow!("state could not find key: {:?}", key))?
@@ -36,7 +49,94 @@ impl State for MemState {
 
     async fn delete(&self, key: &[u8]) -> anyhow::Result<()> {
         let mut state = self.state.write().await;
-        state.remove(key);
+        let prev = state.remove(key);
+        self.record(key, prev).await;
+        Ok(())
+    }
+
+    // sha256 over all entries sorted by key, every key and value is prefixed with its length
+    async fn root(&self) -> anyhow::Result<Hash> {
+        let state = self.state.read().await;
+        if state.is_empty() {
+            return Ok(Hash::zero());
+        }
+
+        let mut entries: Vec<_> = state.iter().collect();
+        entries.sort();
+
+        let mut sha = Sha256::new();
+        for (key, value) in entries {
+            sha.update((key.len() as u32).to_le_bytes());
+            sha.update(key);
+            sha.update((value.len() as u32).to_le_bytes());
+            sha.update(value);
+        }
+
+        Ok(Hash::from_bytes(sha.finalize().as_slice()))
+    }
+
+    async fn checkpoint(&self) -> anyhow::Result<()> {
+        self.checkpoints.write().await.push(vec![]);
+        Ok(())
+    }
+
+    async fn commit(&self) -> anyhow::Result<()> {
+        let mut checkpoints = self.checkpoints.write().await;
+        let changes = checkpoints
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("state has no checkpoint to commit"))?;
+
+        // the enclosing checkpoint has to be able to undo these changes as well
+        if let Some(parent) = checkpoints.last_mut() {
+            parent.extend(changes);
+        }
+        Ok(())
+    }
+
+    async fn revert(&self) -> anyhow::Result<()> {
+        let mut state = self.state.write().await;
+        let changes = self
+            .checkpoints
+            .write()
+            .await
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("state has no checkpoint to revert"))?;
+
+        for (key, prev) in changes.into_iter().rev() {
+            match prev {
+                Some(value) => state.insert(key, value),
+                None => state.remove(&key),
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_nested_checkpoints() -> anyhow::Result<()> {
+        let state = MemState::new();
+        state.set(b"a", b"1").await?;
+
+        state.checkpoint().await?;
+        state.set(b"a", b"2").await?;
+
+        state.checkpoint().await?;
+        state.set(b"b", b"3").await?;
+        state.delete(b"a").await?;
+        state.commit().await?;
+        assert!(state.get(b"a").await.is_err());
+
+        // the outer checkpoint also undoes the changes of the committed inner one
+        state.revert().await?;
+        assert_eq!(state.get(b"a").await?, b"1".to_vec());
+        assert!(state.get(b"b").await.is_err());
+
+        assert!(state.revert().await.is_err());
+
         Ok(())
     }
 }
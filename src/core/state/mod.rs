@@ -1,5 +1,9 @@
+pub mod journaled_state;
 pub mod mem_state;
+pub mod namespaced_state;
+pub mod trie_state;
 
+use crate::core::Hash;
 use anyhow::Result;
 use dyn_clone::DynClone;
 use std::fmt::Debug;
@@ -11,6 +15,15 @@ pub trait State: Debug + DynClone + Send + Sync {
     async fn set(&self, key: &[u8], value: &[u8]) -> Result<()>;
     async fn get(&self, key: &[u8]) -> Result<Vec<u8>>;
     async fn delete(&self, key: &[u8]) -> Result<()>;
+    // Commitment to the whole state, the root of an empty state is the zero hash
+    async fn root(&self) -> Result<Hash>;
+
+    // Checkpoints can be nested, every checkpoint has to be closed by either commit or revert
+    async fn checkpoint(&self) -> Result<()>;
+    // Keep the changes since the last checkpoint, they become part of the enclosing checkpoint
+    async fn commit(&self) -> Result<()>;
+    // Undo every change since the last checkpoint
+    async fn revert(&self) -> Result<()>;
 }
 
 dyn_clone::clone_trait_object!(State);
@@ -0,0 +1,80 @@
+use anyhow::Result;
+
+use super::{DynState, State};
+use crate::core::Hash;
+
+// Prefixes every key with a namespace, code running on it can't touch the keys outside of it.
+// Namespaces must not be prefixes of each other, otherwise their keys could overlap
+#[derive(Debug, Clone)]
+pub struct NamespacedState {
+    inner: DynState,
+    prefix: Vec<u8>,
+}
+
+impl NamespacedState {
+    pub fn new(inner: DynState, prefix: Vec<u8>) -> Self {
+        Self { inner, prefix }
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        [self.prefix.as_slice(), key].concat()
+    }
+}
+
+#[async_trait::async_trait]
+impl State for NamespacedState {
+    async fn set(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.inner.set(&self.key(key), value).await
+    }
+
+    async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.inner.get(&self.key(key)).await
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.inner.delete(&self.key(key)).await
+    }
+
+    // The root of the whole state, a namespace has no commitment of its own
+    async fn root(&self) -> Result<Hash> {
+        self.inner.root().await
+    }
+
+    async fn checkpoint(&self) -> Result<()> {
+        self.inner.checkpoint().await
+    }
+
+    async fn commit(&self) -> Result<()> {
+        self.inner.commit().await
+    }
+
+    async fn revert(&self) -> Result<()> {
+        self.inner.revert().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::state::mem_state::MemState;
+
+    #[tokio::test]
+    async fn test_namespaces_are_isolated() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let a = NamespacedState::new(state.clone(), b"a/".to_vec());
+        let b = NamespacedState::new(state.clone(), b"b/".to_vec());
+
+        a.set(b"key", b"1").await?;
+        b.set(b"key", b"2").await?;
+
+        assert_eq!(a.get(b"key").await?, b"1".to_vec());
+        assert_eq!(b.get(b"key").await?, b"2".to_vec());
+        assert_eq!(state.get(b"a/key").await?, b"1".to_vec());
+
+        b.delete(b"key").await?;
+        assert!(b.get(b"key").await.is_err());
+        assert_eq!(a.get(b"key").await?, b"1".to_vec());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,528 @@
+/*
+    TrieState is a sparse merkle tree over the sha256 hashes of the keys.
+    The bits of the key hash (most significant first) are the path from the root to the leaf.
+
+    To keep the tree small it is compacted: an empty subtree has the zero hash and a subtree
+    that contains a single leaf is replaced by the leaf itself. Because of that a leaf
+    sits at the first depth where its path doesn't share a prefix with any other key.
+
+    leaf hash = sha256(0x00 | key_hash | sha256(value))
+    node hash = sha256(0x01 | left | right)
+
+    Every node is stored in the DynStorage under its hash, old nodes are never deleted
+    so a TrieState can be opened at any root that was ever committed.
+    This also makes checkpoints cheap, a checkpoint only has to remember the root.
+*/
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use super::State;
+use crate::core::{storage::DynStorage, Hash};
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf { key_hash: Hash, value: Vec<u8> },
+    Internal { left: Hash, right: Hash },
+}
+
+impl Node {
+    fn hash(&self) -> Hash {
+        match self {
+            Node::Leaf { key_hash, value } => leaf_hash(key_hash, &sha256(value)),
+            Node::Internal { left, right } => node_hash(left, right),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Node::Leaf { key_hash, value } => {
+                [&[LEAF_PREFIX], key_hash.as_bytes(), value.as_slice()].concat()
+            }
+            Node::Internal { left, right } => {
+                [&[NODE_PREFIX], left.as_bytes(), right.as_bytes()].concat()
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        match bytes.first() {
+            Some(&LEAF_PREFIX) if bytes.len() >= 33 => Ok(Node::Leaf {
+                key_hash: Hash::from_bytes(&bytes[1..33]),
+                value: bytes[33..].to_vec(),
+            }),
+            Some(&NODE_PREFIX) if bytes.len() == 65 => Ok(Node::Internal {
+                left: Hash::from_bytes(&bytes[1..33]),
+                right: Hash::from_bytes(&bytes[33..65]),
+            }),
+            _ => Err(anyhow!("invalid trie node")),
+        }
+    }
+}
+
+// Proves that a key has a value (membership) or that it isn't in the trie (non-membership)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrieProof {
+    // siblings from the root down to the leaf
+    pub siblings: Vec<Hash>,
+    // the leaf at the end of the path (key hash, value hash), None if the path ends in an empty subtree
+    pub leaf: Option<(Hash, Hash)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrieState {
+    storage: DynStorage,
+    root: Arc<Mutex<Hash>>,
+    checkpoints: Arc<Mutex<Vec<Hash>>>,
+}
+
+impl TrieState {
+    pub fn new(storage: DynStorage) -> Self {
+        Self::with_root(storage, Hash::zero())
+    }
+
+    // Open the trie at a root that was committed before
+    pub fn with_root(storage: DynStorage, root: Hash) -> Self {
+        Self {
+            storage,
+            root: Arc::new(Mutex::new(root)),
+            checkpoints: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    pub async fn prove(&self, key: &[u8]) -> Result<TrieProof> {
+        let key_hash = sha256(key);
+        let mut current = *self.root.lock().await;
+        let mut siblings = vec![];
+
+        while current != Hash::zero() {
+            match self.load(&current).await? {
+                Node::Leaf { key_hash, value } => {
+                    return Ok(TrieProof {
+                        siblings,
+                        leaf: Some((key_hash, sha256(&value))),
+                    });
+                }
+                Node::Internal { left, right } => {
+                    if bit(&key_hash, siblings.len()) {
+                        siblings.push(left);
+                        current = right;
+                    } else {
+                        siblings.push(right);
+                        current = left;
+                    }
+                }
+            }
+        }
+
+        Ok(TrieProof {
+            siblings,
+            leaf: None,
+        })
+    }
+
+    async fn load(&self, hash: &Hash) -> Result<Node> {
+        let bytes = self
+            .storage
+            .get(&node_key(hash))
+            .await
+            .ok_or_else(|| anyhow!("trie is missing node {}", hash))?;
+        Node::decode(&bytes)
+    }
+
+    async fn store(&self, node: Node) -> Hash {
+        let hash = node.hash();
+        self.storage.put(&node_key(&hash), &node.encode()).await;
+        hash
+    }
+
+    async fn is_leaf(&self, hash: &Hash) -> Result<bool> {
+        if *hash == Hash::zero() {
+            return Ok(false);
+        }
+        Ok(matches!(self.load(hash).await?, Node::Leaf { .. }))
+    }
+
+    // Sets the leaf of `key_hash` to `leaf`, or removes it if `leaf` is None
+    async fn update(&self, key_hash: Hash, leaf: Option<Node>) -> Result<()> {
+        let mut root = self.root.lock().await;
+
+        // Walk down the path and remember the sibling at every depth
+        let mut siblings: Vec<Hash> = vec![];
+        let mut current = *root;
+
+        let new_leaf = match &leaf {
+            Some(node) => self.store(node.clone()).await,
+            None => Hash::zero(),
+        };
+
+        let mut bottom = loop {
+            if current == Hash::zero() {
+                break new_leaf;
+            }
+
+            match self.load(&current).await? {
+                Node::Leaf {
+                    key_hash: existing, ..
+                } => {
+                    if existing == key_hash || leaf.is_none() {
+                        // replace the leaf, or keep it if we are deleting a key that doesn't exist
+                        break if existing == key_hash {
+                            new_leaf
+                        } else {
+                            current
+                        };
+                    }
+
+                    // Both leaves share the path up to here, push them down until their paths split
+                    while bit(&existing, siblings.len()) == bit(&key_hash, siblings.len()) {
+                        siblings.push(Hash::zero());
+                    }
+                    siblings.push(current);
+                    break new_leaf;
+                }
+                Node::Internal { left, right } => {
+                    if bit(&key_hash, siblings.len()) {
+                        siblings.push(left);
+                        current = right;
+                    } else {
+                        siblings.push(right);
+                        current = left;
+                    }
+                }
+            }
+        };
+
+        // Rebuild the path from the bottom up, a single leaf moves up as long as it has no sibling
+        let mut bottom_is_leaf = bottom == Hash::zero() || self.is_leaf(&bottom).await?;
+
+        for depth in (0..siblings.len()).rev() {
+            let sibling = siblings[depth];
+
+            if sibling == Hash::zero() && bottom_is_leaf {
+                continue;
+            }
+
+            if bottom == Hash::zero() && self.is_leaf(&sibling).await? {
+                bottom = sibling;
+                continue;
+            }
+
+            let node = if bit(&key_hash, depth) {
+                Node::Internal {
+                    left: sibling,
+                    right: bottom,
+                }
+            } else {
+                Node::Internal {
+                    left: bottom,
+                    right: sibling,
+                }
+            };
+            bottom = self.store(node).await;
+            bottom_is_leaf = false;
+        }
+
+        *root = bottom;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl State for TrieState {
+    async fn set(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let key_hash = sha256(key);
+        let leaf = Node::Leaf {
+            key_hash,
+            value: value.to_vec(),
+        };
+        self.update(key_hash, Some(leaf)).await
+    }
+
+    async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let key_hash = sha256(key);
+        let mut current = *self.root.lock().await;
+        let mut depth = 0;
+
+        while current != Hash::zero() {
+            match self.load(&current).await? {
+                Node::Leaf {
+                    key_hash: leaf_key,
+                    value,
+                } if leaf_key == key_hash => return Ok(value),
+                Node::Leaf { .. } => break,
+                Node::Internal { left, right } => {
+                    current = if bit(&key_hash, depth) { right } else { left };
+                    depth += 1;
+                }
+            }
+        }
+
+        Err(anyhow!("state could not find key: {:?}", key))
+    }
+
+    async fn delete(&self, key: &[u8]) -> Result<()> {
+        self.update(sha256(key), None).await
+    }
+
+    async fn root(&self) -> Result<Hash> {
+        Ok(*self.root.lock().await)
+    }
+
+    async fn checkpoint(&self) -> Result<()> {
+        let root = self.root.lock().await;
+        self.checkpoints.lock().await.push(*root);
+        Ok(())
+    }
+
+    async fn commit(&self) -> Result<()> {
+        self.checkpoints
+            .lock()
+            .await
+            .pop()
+            .ok_or_else(|| anyhow!("state has no checkpoint to commit"))?;
+        Ok(())
+    }
+
+    async fn revert(&self) -> Result<()> {
+        let mut root = self.root.lock().await;
+        *root = self
+            .checkpoints
+            .lock()
+            .await
+            .pop()
+            .ok_or_else(|| anyhow!("state has no checkpoint to revert"))?;
+        Ok(())
+    }
+}
+
+// Check a proof against a root: `value` is Some for a membership proof and None for a non-membership proof
+pub fn verify_proof(root: &Hash, key: &[u8], value: Option<&[u8]>, proof: &TrieProof) -> bool {
+    let key_hash = sha256(key);
+
+    let bottom = match (&proof.leaf, value) {
+        (Some((leaf_key, value_hash)), Some(value)) => {
+            if *leaf_key != key_hash || *value_hash != sha256(value) {
+                return false;
+            }
+            leaf_hash(leaf_key, value_hash)
+        }
+        // another key sits where our key would be, it has to share the path up to the leaf
+        (Some((leaf_key, value_hash)), None) => {
+            let shares_path =
+                (0..proof.siblings.len()).all(|d| bit(leaf_key, d) == bit(&key_hash, d));
+            if *leaf_key == key_hash || !shares_path {
+                return false;
+            }
+            leaf_hash(leaf_key, value_hash)
+        }
+        (None, None) => Hash::zero(),
+        (None, Some(_)) => return false,
+    };
+
+    let computed = proof
+        .siblings
+        .iter()
+        .enumerate()
+        .rev()
+        .fold(bottom, |acc, (depth, sibling)| {
+            if bit(&key_hash, depth) {
+                node_hash(sibling, &acc)
+            } else {
+                node_hash(&acc, sibling)
+            }
+        });
+
+    computed == *root
+}
+
+fn sha256(bytes: &[u8]) -> Hash {
+    Hash::from_bytes(Sha256::digest(bytes).as_slice())
+}
+
+fn leaf_hash(key_hash: &Hash, value_hash: &Hash) -> Hash {
+    let mut sha = Sha256::new();
+    sha.update([LEAF_PREFIX]);
+    sha.update(key_hash.as_bytes());
+    sha.update(value_hash.as_bytes());
+    Hash::from_bytes(sha.finalize().as_slice())
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut sha = Sha256::new();
+    sha.update([NODE_PREFIX]);
+    sha.update(left.as_bytes());
+    sha.update(right.as_bytes());
+    Hash::from_bytes(sha.finalize().as_slice())
+}
+
+// bit at `depth` of the key hash, most significant bit first. true means right
+fn bit(key_hash: &Hash, depth: usize) -> bool {
+    key_hash.as_bytes()[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+fn node_key(hash: &Hash) -> Vec<u8> {
+    [b"trie/".as_slice(), hash.as_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        state::DynState,
+        storage::mem_storage::MemStorage,
+        vm::{bytecode_vm::BytecodeVM, ExecutionContext, DEFAULT_TX_GAS_LIMIT, VM},
+    };
+
+    fn trie() -> TrieState {
+        TrieState::new(Box::new(MemStorage::new()))
+    }
+
+    #[tokio::test]
+    async fn test_set_get_delete() -> Result<()> {
+        let state = trie();
+        for i in 0..50u32 {
+            state.set(&i.to_le_bytes(), &[i as u8]).await?;
+        }
+        for i in 0..50u32 {
+            assert_eq!(state.get(&i.to_le_bytes()).await?, vec![i as u8]);
+        }
+
+        for i in 0..50u32 {
+            state.delete(&i.to_le_bytes()).await?;
+        }
+        assert!(state.get(&0u32.to_le_bytes()).await.is_err());
+        assert_eq!(state.root().await?, Hash::zero());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_root_is_order_independent() -> Result<()> {
+        let (a, b) = (trie(), trie());
+        for i in 0..20u32 {
+            a.set(&i.to_le_bytes(), b"value").await?;
+        }
+        for i in (0..20u32).rev() {
+            b.set(&i.to_le_bytes(), b"value").await?;
+        }
+        // a key that was added and removed again doesn't change the root
+        b.set(b"temporary", b"value").await?;
+        b.delete(b"temporary").await?;
+
+        assert_eq!(a.root().await?, b.root().await?);
+        assert_ne!(a.root().await?, Hash::zero());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reopen_at_root() -> Result<()> {
+        let storage: DynStorage = Box::new(MemStorage::new());
+        let state = TrieState::new(storage.clone());
+        state.set(b"a", b"1").await?;
+        let root = state.root().await?;
+        state.set(b"a", b"2").await?;
+
+        let old = TrieState::with_root(storage, root);
+        assert_eq!(old.get(b"a").await?, b"1".to_vec());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_checkpoints() -> Result<()> {
+        let state = trie();
+        state.set(b"a", b"1").await?;
+        let root = state.root().await?;
+
+        state.checkpoint().await?;
+        state.set(b"b", b"2").await?;
+        state.checkpoint().await?;
+        state.delete(b"a").await?;
+        state.commit().await?;
+        state.revert().await?;
+
+        assert_eq!(state.root().await?, root);
+        assert_eq!(state.get(b"a").await?, b"1".to_vec());
+        assert!(state.get(b"b").await.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_proofs() -> Result<()> {
+        let state = trie();
+        for i in 0..20u32 {
+            state.set(&i.to_le_bytes(), &[i as u8]).await?;
+        }
+        let root = state.root().await?;
+
+        // membership
+        let proof = state.prove(&3u32.to_le_bytes()).await?;
+        assert!(verify_proof(&root, &3u32.to_le_bytes(), Some(&[3]), &proof));
+        assert!(!verify_proof(
+            &root,
+            &3u32.to_le_bytes(),
+            Some(&[4]),
+            &proof
+        ));
+        assert!(!verify_proof(&root, &3u32.to_le_bytes(), None, &proof));
+
+        // non-membership
+        for key in [b"missing".as_slice(), &100u32.to_le_bytes()] {
+            let proof = state.prove(key).await?;
+            assert!(verify_proof(&root, key, None, &proof));
+            assert!(!verify_proof(&root, key, Some(&[1]), &proof));
+        }
+
+        // a proof is only valid for the root it was created for
+        state.set(&3u32.to_le_bytes(), &[9]).await?;
+        assert!(!verify_proof(
+            &state.root().await?,
+            &3u32.to_le_bytes(),
+            Some(&[3]),
+            &proof
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prove_vm_store() -> Result<()> {
+        let trie = trie();
+        let state: DynState = Box::new(trie.clone());
+
+        let mut vm: BytecodeVM<128> = BytecodeVM::new();
+        vm.execute(
+            &state,
+            &[
+                0xaa, 0x02, 0x00, 0x00, 0x00, 0xaa, 0x04, 0x00, 0x00, 0x00, 0xbc,
+            ],
+            &ExecutionContext::default(),
+            DEFAULT_TX_GAS_LIMIT,
+        )
+        .await
+        .into_result()?;
+
+        // code that isn't a contract stores below global/
+        let key = [b"global/".as_slice(), &[4, 0, 0, 0]].concat();
+        let proof = trie.prove(&key).await?;
+        assert!(verify_proof(
+            &trie.root().await?,
+            &key,
+            // the value is tagged with its type, 0 is an int
+            Some(&[0, 2, 0, 0, 0]),
+            &proof
+        ));
+
+        Ok(())
+    }
+}
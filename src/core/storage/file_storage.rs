@@ -0,0 +1,416 @@
+/*
+    FileStorage is an append-only log split into segment files.
+    Every put and delete appends a record to the active segment, an in-memory index
+    points every key to the location of its latest value and is rebuilt from the segments on startup.
+
+    Record layout (integers are little endian):
+    | op: u8 | key_len: u32 | value_len: u32 | key | value | checksum: [u8; 8] |
+
+    The checksum is the first 8 bytes of the sha256 of everything before it. A record at the
+    end of the last segment that is incomplete or has an invalid checksum is a torn write
+    (the process died while appending) and gets truncated on startup.
+
+    Flush syncs the active segment, a segment is synced when it stops being the active one so
+    that only the last segment can ever end in a torn write.
+*/
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use log::{error, warn};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use super::Storage;
+
+const OP_PUT: u8 = 1;
+const OP_DELETE: u8 = 2;
+const HEADER_LEN: usize = 9;
+const CHECKSUM_LEN: usize = 8;
+const DEFAULT_MAX_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    segment: u32,
+    offset: u64,
+    len: u32,
+}
+
+#[derive(Debug)]
+struct Inner {
+    dir: PathBuf,
+    max_segment_bytes: u64,
+    index: HashMap<Vec<u8>, Location>,
+    segments: HashMap<u32, File>,
+    active: u32,
+    active_len: u64,
+    // the first write error, returned by the next flush since put and delete can't fail
+    write_error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileStorage {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl FileStorage {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_segment_size(dir, DEFAULT_MAX_SEGMENT_BYTES)
+    }
+
+    pub fn open_with_segment_size(dir: impl AsRef<Path>, max_segment_bytes: u64) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut ids = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(id) = name.strip_suffix(".seg") {
+                ids.push(id.parse::<u32>()?);
+            }
+        }
+        ids.sort();
+
+        let mut inner = Inner {
+            dir,
+            max_segment_bytes,
+            index: HashMap::new(),
+            segments: HashMap::new(),
+            active: ids.last().copied().unwrap_or(0),
+            active_len: 0,
+            write_error: None,
+        };
+
+        for (i, id) in ids.iter().enumerate() {
+            let is_last = i == ids.len() - 1;
+            inner.load_segment(*id, is_last)?;
+        }
+
+        if ids.is_empty() {
+            inner.open_segment(0)?;
+        }
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+}
+
+impl Inner {
+    fn segment_path(&self, id: u32) -> PathBuf {
+        self.dir.join(format!("{id:08}.seg"))
+    }
+
+    fn open_segment(&mut self, id: u32) -> Result<()> {
+        let path = self.segment_path(id);
+        let created = !path.exists();
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+
+        // the directory entry of a new segment has to be durable as well, otherwise it can vanish after a crash
+        if created {
+            File::open(&self.dir)?.sync_all()?;
+        }
+
+        self.active_len = file.metadata()?.len();
+        self.active = id;
+        self.segments.insert(id, file);
+        Ok(())
+    }
+
+    // Replay all records of a segment into the index
+    fn load_segment(&mut self, id: u32, is_last: bool) -> Result<()> {
+        let mut bytes = vec![];
+        File::open(self.segment_path(id))?.read_to_end(&mut bytes)?;
+
+        let mut offset = 0;
+        while offset < bytes.len() {
+            match decode_record(&bytes[offset..]) {
+                Some((op, key, value_offset, value_len, record_len)) => {
+                    match op {
+                        OP_PUT => {
+                            let location = Location {
+                                segment: id,
+                                offset: (offset + value_offset) as u64,
+                                len: value_len,
+                            };
+                            self.index.insert(key.to_vec(), location);
+                        }
+                        _ => {
+                            self.index.remove(key);
+                        }
+                    }
+                    offset += record_len;
+                }
+                None if is_last => {
+                    warn!(
+                        "truncating torn write in segment {} at offset {} ({} bytes)",
+                        id,
+                        offset,
+                        bytes.len() - offset
+                    );
+                    OpenOptions::new()
+                        .write(true)
+                        .open(self.segment_path(id))?
+                        .set_len(offset as u64)?;
+                    break;
+                }
+                None => {
+                    return Err(anyhow!("segment {} is corrupted at offset {}", id, offset));
+                }
+            }
+        }
+
+        if is_last {
+            self.open_segment(id)?;
+        } else {
+            let file = File::open(self.segment_path(id))?;
+            self.segments.insert(id, file);
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, op: u8, key: &[u8], value: &[u8]) -> Result<Location> {
+        if self.active_len >= self.max_segment_bytes {
+            // flush only syncs the active segment, so the old one has to be durable before moving on
+            if let Some(file) = self.segments.get(&self.active) {
+                file.sync_data()?;
+            }
+            let next = self.active + 1;
+            self.open_segment(next)?;
+        }
+
+        let record = encode_record(op, key, value);
+        let file = self
+            .segments
+            .get_mut(&self.active)
+            .ok_or_else(|| anyhow!("active segment {} is not open", self.active))?;
+        file.write_all(&record)?;
+
+        let location = Location {
+            segment: self.active,
+            offset: self.active_len + (HEADER_LEN + key.len()) as u64,
+            len: value.len() as u32,
+        };
+        self.active_len += record.len() as u64;
+
+        Ok(location)
+    }
+
+    fn read(&mut self, location: Location) -> Result<Vec<u8>> {
+        let file = self
+            .segments
+            .get_mut(&location.segment)
+            .ok_or_else(|| anyhow!("segment {} is not open", location.segment))?;
+
+        let mut value = vec![0; location.len as usize];
+        file.seek(SeekFrom::Start(location.offset))?;
+        file.read_exact(&mut value)?;
+        Ok(value)
+    }
+
+    fn record_error(&mut self, err: anyhow::Error) {
+        error!("FileStorage write failed: {:?}", err);
+        self.write_error.get_or_insert(err.to_string());
+    }
+}
+
+fn checksum(bytes: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&Sha256::digest(bytes)[..CHECKSUM_LEN]);
+    checksum
+}
+
+fn encode_record(op: u8, key: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN + key.len() + value.len() + CHECKSUM_LEN);
+    record.push(op);
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(value);
+    let checksum = checksum(&record);
+    record.extend_from_slice(&checksum);
+    record
+}
+
+// Returns (op, key, value_offset, value_len, record_len) or None if the record is incomplete or corrupted
+fn decode_record(bytes: &[u8]) -> Option<(u8, &[u8], usize, u32, usize)> {
+    if bytes.len() < HEADER_LEN {
+        return None;
+    }
+
+    let op = bytes[0];
+    let key_len = u32::from_le_bytes(bytes[1..5].try_into().ok()?) as usize;
+    let value_len = u32::from_le_bytes(bytes[5..9].try_into().ok()?);
+
+    let body_len = HEADER_LEN
+        .checked_add(key_len)?
+        .checked_add(value_len as usize)?;
+    let record_len = body_len.checked_add(CHECKSUM_LEN)?;
+
+    if bytes.len() < record_len || (op != OP_PUT && op != OP_DELETE) {
+        return None;
+    }
+
+    if checksum(&bytes[..body_len]) != bytes[body_len..record_len] {
+        return None;
+    }
+
+    let key = &bytes[HEADER_LEN..HEADER_LEN + key_len];
+    Some((op, key, HEADER_LEN + key_len, value_len, record_len))
+}
+
+#[async_trait]
+impl Storage for FileStorage {
+    async fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().await;
+        let location = *inner.index.get(key)?;
+
+        match inner.read(location) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                error!("FileStorage could not read key {:?}: {:?}", key, err);
+                None
+            }
+        }
+    }
+
+    async fn put(&self, key: &[u8], value: &[u8]) {
+        let mut inner = self.inner.lock().await;
+        match inner.append(OP_PUT, key, value) {
+            Ok(location) => {
+                inner.index.insert(key.to_vec(), location);
+            }
+            Err(err) => inner.record_error(err),
+        }
+    }
+
+    async fn delete(&self, key: &[u8]) {
+        let mut inner = self.inner.lock().await;
+        if !inner.index.contains_key(key) {
+            return;
+        }
+        match inner.append(OP_DELETE, key, &[]) {
+            Ok(_) => {
+                inner.index.remove(key);
+            }
+            Err(err) => inner.record_error(err),
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+
+        if let Some(err) = inner.write_error.take() {
+            return Err(anyhow!("FileStorage write failed: {err}"));
+        }
+
+        let active = inner.active;
+        inner
+            .segments
+            .get(&active)
+            .ok_or_else(|| anyhow!("active segment {} is not open", active))?
+            .sync_data()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{thread_rng, Rng};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("muckchain-storage-{}", thread_rng().gen::<u64>()))
+    }
+
+    #[tokio::test]
+    async fn test_reopen() -> Result<()> {
+        let dir = temp_dir();
+
+        let storage = FileStorage::open(&dir)?;
+        storage.put(b"a", b"1").await;
+        storage.put(b"b", b"2").await;
+        storage.put(b"a", b"3").await;
+        storage.delete(b"b").await;
+        storage.flush().await?;
+        drop(storage);
+
+        let storage = FileStorage::open(&dir)?;
+        assert_eq!(storage.get(b"a").await, Some(b"3".to_vec()));
+        assert_eq!(storage.get(b"b").await, None);
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_segment_rollover() -> Result<()> {
+        let dir = temp_dir();
+
+        let storage = FileStorage::open_with_segment_size(&dir, 32)?;
+        for i in 0..10u32 {
+            storage.put(&i.to_le_bytes(), &[i as u8; 16]).await;
+        }
+        storage.flush().await?;
+        drop(storage);
+
+        assert!(fs::read_dir(&dir)?.count() > 1);
+
+        let storage = FileStorage::open_with_segment_size(&dir, 32)?;
+        for i in 0..10u32 {
+            assert_eq!(storage.get(&i.to_le_bytes()).await, Some(vec![i as u8; 16]));
+        }
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_truncate_torn_write() -> Result<()> {
+        let dir = temp_dir();
+
+        let storage = FileStorage::open(&dir)?;
+        storage.put(b"a", b"1").await;
+        storage.put(b"b", b"2").await;
+        storage.flush().await?;
+        drop(storage);
+
+        // cut the last record in half
+        let path = dir.join(format!("{:08}.seg", 0));
+        let len = fs::metadata(&path)?.len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)?
+            .set_len(len - 5)?;
+
+        let storage = FileStorage::open(&dir)?;
+        assert_eq!(storage.get(b"a").await, Some(b"1".to_vec()));
+        assert_eq!(storage.get(b"b").await, None);
+
+        // new writes are appended after the last valid record
+        storage.put(b"c", b"3").await;
+        storage.flush().await?;
+        drop(storage);
+
+        let storage = FileStorage::open(&dir)?;
+        assert_eq!(storage.get(b"a").await, Some(b"1".to_vec()));
+        assert_eq!(storage.get(b"c").await, Some(b"3".to_vec()));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -29,8 +29,7 @@ impl Storage for MemStorage {
 
     async fn put(&self, key: &[u8], value: &[u8]) {
         let mut map = self.data.write().await;
-        let entry = map.entry(key.to_vec());
-        entry.or_default().extend_from_slice(value);
+        map.insert(key.to_vec(), value.to_vec());
     }
 
     async fn delete(&self, key: &[u8]) {
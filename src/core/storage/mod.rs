@@ -1,5 +1,7 @@
+pub mod file_storage;
 pub mod mem_storage;
 
+use anyhow::Result;
 use async_trait::async_trait;
 use dyn_clone::DynClone;
 use std::fmt::Debug;
@@ -12,5 +14,10 @@ pub trait Storage: Debug + DynClone + Send + Sync {
     async fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
     async fn put(&self, key: &[u8], value: &[u8]);
     async fn delete(&self, key: &[u8]);
+
+    // Make sure everything written so far survives a crash
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
 }
 dyn_clone::clone_trait_object!(Storage);
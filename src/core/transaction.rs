@@ -1,16 +1,52 @@
 use crate::prelude::*;
 
-use crate::crypto::{PrivateKey, PublicKey, Signature};
+use crate::crypto::{signing_digest, PrivateKey, PublicKey, Signature, SigningDomain, SIGNER_SIZE};
+
+use super::{vm::DEFAULT_TX_GAS_LIMIT, Address, McError, DEFAULT_CHAIN_ID};
+
+// What the data of a transaction is
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxKind {
+    // code that runs once on the global state
+    Execute,
+    // code of a new contract, its address is derived from the sender and the sender's deploy nonce
+    Deploy,
+    // input data for a call of the contract at the address
+    Call(Address),
+}
+
+impl TxKind {
+    fn to_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Execute => vec![0],
+            Self::Deploy => vec![1],
+            Self::Call(address) => [&[2], address.as_bytes()].concat(),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
+    // network the transaction is meant for, it's invalid on every other one
+    pub chain_id: u64,
+    pub kind: TxKind,
     pub data: Vec<u8>,
+    // maximum amount of gas the execution of the transaction may use
+    pub gas_limit: u64,
+    // what the sender pays the validator for every unit of gas the execution uses
+    pub gas_price: u64,
+    // `value` is transferred from the sender to `to` before the data is executed
+    pub to: Option<Address>,
+    pub value: u64,
+    // number of transactions the sender sent before this one, every nonce can only be used once
+    pub nonce: u64,
 
     public_key_of_sender: Option<PublicKey>,
     signature: Option<Signature>,
 
-    // we cache the hash of the transaction to avoid recomputing it
-    // #[serde(skip)]
+    // we cache the hash of the transaction to avoid recomputing it,
+    // it's never sent along since a peer could put any hash in there
+    #[serde(skip)]
     hash: Option<Hash>,
     #[serde(skip)]
     first_seen: u128,
@@ -23,7 +59,14 @@ decodable!(Transaction);
 impl Transaction {
     pub fn new(data: Vec<u8>) -> Self {
         Transaction {
+            chain_id: DEFAULT_CHAIN_ID,
+            kind: TxKind::Execute,
             data,
+            gas_limit: DEFAULT_TX_GAS_LIMIT,
+            gas_price: 0,
+            to: None,
+            value: 0,
+            nonce: 0,
             hash: None,
             first_seen: 0,
             public_key_of_sender: None,
@@ -31,13 +74,115 @@ impl Transaction {
         }
     }
 
+    pub fn deploy(code: Vec<u8>) -> Self {
+        Self {
+            kind: TxKind::Deploy,
+            ..Self::new(code)
+        }
+    }
+
+    pub fn call(contract: Address, input: Vec<u8>) -> Self {
+        Self {
+            kind: TxKind::Call(contract),
+            ..Self::new(input)
+        }
+    }
+
+    // A transaction that only transfers `value` to `to`
+    pub fn transfer(to: Address, value: u64) -> Self {
+        Self::new(vec![]).with_value(to, value)
+    }
+
+    pub fn with_value(mut self, to: Address, value: u64) -> Self {
+        self.to = Some(to);
+        self.value = value;
+        self
+    }
+
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = chain_id;
+        self
+    }
+
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    pub fn with_gas_limit(mut self, gas_limit: u64) -> Self {
+        self.gas_limit = gas_limit;
+        self
+    }
+
+    pub fn with_gas_price(mut self, gas_price: u64) -> Self {
+        self.gas_price = gas_price;
+        self
+    }
+
+    // The fee if the execution uses the whole gas limit, the sender has to be able to pay it up front
+    pub fn max_fee(&self) -> u64 {
+        self.gas_limit.saturating_mul(self.gas_price)
+    }
+
+    // What the sender needs at most to pay for the transaction
+    pub fn cost(&self) -> u64 {
+        self.max_fee().saturating_add(self.value)
+    }
+
+    // Canonical encoding of every field, otherwise anyone could change them on the way.
+    // The data is length prefixed so the fields can't be shifted into each other
+    fn signing_bytes(&self) -> Vec<u8> {
+        let to = self.to.as_ref().map_or(&[][..], Address::as_bytes);
+        [
+            self.kind.to_bytes().as_slice(),
+            &self.nonce.to_le_bytes(),
+            &self.gas_limit.to_le_bytes(),
+            &self.gas_price.to_le_bytes(),
+            &[self.to.is_some() as u8],
+            to,
+            &self.value.to_le_bytes(),
+            &(self.data.len() as u64).to_le_bytes(),
+            &self.data,
+        ]
+        .concat()
+    }
+
+    // Size of the signed fields, the chain id and the signer in bytes.
+    // It doesn't depend on the encoder, so every node measures the same size
+    pub fn size(&self) -> usize {
+        self.signing_bytes().len() + 8 + SIGNER_SIZE
+    }
+
+    // What the sender signs, it covers the chain id as well
+    pub(crate) fn signing_digest(&self) -> Vec<u8> {
+        signing_digest(
+            SigningDomain::Transaction,
+            self.chain_id,
+            &self.signing_bytes(),
+        )
+    }
+
     pub fn sign(&mut self, private_key: &PrivateKey) {
-        let data = self.data.clone();
         self.public_key_of_sender = Some(private_key.public_key());
-        self.signature = Some(private_key.sign(&data));
+        self.signature = Some(private_key.sign(&self.signing_digest()));
     }
 
-    pub fn verify(&self) -> Result<()> {
+    // Checks the signature and that the transaction is meant for the chain with `chain_id`
+    pub fn verify(&self, chain_id: u64) -> Result<()> {
+        if self.chain_id != chain_id {
+            Err(McError::ChainIdMismatch {
+                expected: chain_id,
+                found: self.chain_id,
+            })?;
+        }
+
+        if self.value > 0 && self.to.is_none() {
+            return Err(anyhow!(
+                "transaction {:?} has a value but no recipient",
+                self.hash
+            ));
+        }
+
         let sig = self
             .signature
             .as_ref()
@@ -48,7 +193,7 @@ impl Transaction {
             .as_ref()
             .ok_or_else(|| anyhow!("transaction {:?} has no public_key_of_sender!", self.hash))?;
 
-        if sig.verify(&self.data, pub_key) {
+        if sig.verify(&self.signing_digest(), pub_key) {
             Ok(())
         } else {
             Err(anyhow!(
@@ -58,6 +203,11 @@ impl Transaction {
         }
     }
 
+    // Address of the signer, None for an unsigned transaction
+    pub fn sender(&self) -> Option<Address> {
+        self.public_key_of_sender.as_ref().map(PublicKey::address)
+    }
+
     pub fn first_seen(&self) -> u128 {
         self.first_seen
     }
@@ -91,7 +241,7 @@ mod tests {
         let mut t = Transaction::new(vec![1, 2, 3]);
         let private_key = PrivateKey::generate();
         t.sign(&private_key);
-        t.verify()?;
+        t.verify(DEFAULT_CHAIN_ID)?;
         Ok(())
     }
 
@@ -101,7 +251,86 @@ mod tests {
         let private_key = PrivateKey::generate();
         t.sign(&private_key);
         t.data = vec![1, 2, 4];
-        assert!(t.verify().is_err());
+        assert!(t.verify(DEFAULT_CHAIN_ID).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_gas_is_signed() -> Result<()> {
+        let mut t = Transaction::new(vec![1, 2, 3])
+            .with_gas_limit(10)
+            .with_gas_price(2);
+        t.sign(&PrivateKey::generate());
+        t.verify(DEFAULT_CHAIN_ID)?;
+        assert_eq!(t.max_fee(), 20);
+
+        t.gas_limit = 1_000_000;
+        assert!(t.verify(DEFAULT_CHAIN_ID).is_err());
+        t.gas_limit = 10;
+        t.gas_price = 1;
+        assert!(t.verify(DEFAULT_CHAIN_ID).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_kind_is_signed() -> Result<()> {
+        let private_key = PrivateKey::generate();
+        let contract = private_key.public_key().address();
+
+        let mut t = Transaction::call(contract, vec![1, 2, 3]);
+        t.sign(&private_key);
+        t.verify(DEFAULT_CHAIN_ID)?;
+        assert_eq!(t.sender(), Some(contract));
+
+        t.kind = TxKind::Execute;
+        assert!(t.verify(DEFAULT_CHAIN_ID).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_value_and_nonce_are_signed() -> Result<()> {
+        let private_key = PrivateKey::generate();
+        let to = Address::from_bytes(&[1; 20]);
+
+        let mut t = Transaction::transfer(to, 10);
+        t.sign(&private_key);
+        t.verify(DEFAULT_CHAIN_ID)?;
+
+        t.value = 1_000;
+        assert!(t.verify(DEFAULT_CHAIN_ID).is_err());
+        t.value = 10;
+        t.to = Some(Address::from_bytes(&[2; 20]));
+        assert!(t.verify(DEFAULT_CHAIN_ID).is_err());
+        t.to = Some(to);
+        t.nonce = 1;
+        assert!(t.verify(DEFAULT_CHAIN_ID).is_err());
+
+        // a value needs someone to receive it
+        let mut t = Transaction::new(vec![]);
+        t.value = 10;
+        t.sign(&private_key);
+        assert!(t.verify(DEFAULT_CHAIN_ID).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction_chain_id() -> Result<()> {
+        let mut t = Transaction::new(vec![1, 2, 3]).with_chain_id(2);
+        t.sign(&PrivateKey::generate());
+        t.verify(2)?;
+
+        let err = t.verify(DEFAULT_CHAIN_ID).unwrap_err();
+        assert!(matches!(
+            err.downcast::<McError>()?,
+            McError::ChainIdMismatch {
+                expected: 1,
+                found: 2
+            }
+        ));
+
+        // the chain id is signed, changing it breaks the signature
+        t.chain_id = DEFAULT_CHAIN_ID;
+        assert!(t.verify(DEFAULT_CHAIN_ID).is_err());
         Ok(())
     }
 }
@@ -0,0 +1,349 @@
+/*
+    Text assembly for the BytecodeVM, one instruction per line:
+
+        ; comments start with a semicolon
+        .const KEY 4        ; constants can be used wherever a number is expected
+        PushInt 0
+        loop:               ; labels are replaced by the offset of the instruction that follows them
+        PushInt loop
+        Jump
+
+    Mnemonics are the names of the instructions (case insensitive). Immediates:
+    PushInt / PushInt64 / PushU64 take a number (decimal or 0x hex), a constant or a label,
+    PushU256 takes a decimal or 0x hex number of up to 256 bits, PushByte takes a number,
+    PushBool takes true or false and PushBytes takes 0x followed by hex digits.
+*/
+
+use std::{collections::HashMap, fmt::Write};
+
+use anyhow::{anyhow, Result};
+
+use super::{code::decode, instruction::Instruction, stack::StackItem, u256::U256};
+
+struct Line<'a> {
+    number: usize,
+    instr: Instruction,
+    arg: Option<&'a str>,
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>> {
+    let mut lines = vec![];
+    let mut symbols: HashMap<&str, i64> = HashMap::new();
+    let mut offset = 0usize;
+
+    // First pass: collect the constants and labels and compute the offset of every instruction
+    for (i, line) in source.lines().enumerate() {
+        let number = i + 1;
+        let mut line = line.split(';').next().unwrap_or_default().trim();
+
+        if let Some(rest) = line.strip_prefix(".const") {
+            let mut parts = rest.split_whitespace();
+            let (Some(name), Some(value), None) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(anyhow!("line {number}: expected .const NAME VALUE"));
+            };
+            let value = parse_number(value)
+                .ok_or_else(|| anyhow!("line {number}: invalid number {value}"))?;
+            define(&mut symbols, name, value, number)?;
+            continue;
+        }
+
+        if let Some((label, rest)) = line.split_once(':') {
+            define(&mut symbols, label.trim(), offset as i64, number)?;
+            line = rest.trim();
+        }
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut parts = line.split_whitespace();
+        let mnemonic = parts.next().unwrap_or_default();
+        let arg = parts.next();
+        if parts.next().is_some() {
+            return Err(anyhow!("line {number}: too many arguments"));
+        }
+
+        let instr = Instruction::ALL
+            .into_iter()
+            .find(|instr| format!("{instr:?}").eq_ignore_ascii_case(mnemonic))
+            .ok_or_else(|| anyhow!("line {number}: unknown instruction {mnemonic}"))?;
+
+        offset += 1 + immediate_len(instr, arg).map_err(|err| anyhow!("line {number}: {err}"))?;
+        lines.push(Line { number, instr, arg });
+    }
+
+    // Second pass: emit the opcodes and immediates with the symbols resolved
+    let mut code = Vec::with_capacity(offset);
+    for line in lines {
+        code.push(line.instr as u8);
+        encode_immediate(&mut code, &line, &symbols)
+            .map_err(|err| anyhow!("line {}: {err}", line.number))?;
+    }
+
+    Ok(code)
+}
+
+// A listing of the code with the offset of every instruction
+pub fn disassemble(code: &[u8]) -> Result<String> {
+    let mut listing = String::new();
+
+    for op in decode(code)? {
+        let arg = match op.immediate {
+            Some(StackItem::Int(i)) => format!(" {i}"),
+            Some(StackItem::Int64(i)) => format!(" {i}"),
+            Some(StackItem::U64(i)) => format!(" {i}"),
+            Some(StackItem::U256(i)) => format!(" {i}"),
+            Some(StackItem::Bool(b)) => format!(" {b}"),
+            Some(StackItem::Byte(b)) => format!(" {b:#04x}"),
+            Some(StackItem::Bytes(bytes)) => format!(" 0x{}", to_hex(&bytes)),
+            None => String::new(),
+        };
+
+        writeln!(listing, "{:04}: {:?}{}", op.offset, op.instr, arg)?;
+    }
+
+    Ok(listing)
+}
+
+fn define<'a>(
+    symbols: &mut HashMap<&'a str, i64>,
+    name: &'a str,
+    value: i64,
+    number: usize,
+) -> Result<()> {
+    if name.is_empty() || name.contains(char::is_whitespace) || parse_number(name).is_some() {
+        return Err(anyhow!("line {number}: invalid name {name:?}"));
+    }
+    if symbols.insert(name, value).is_some() {
+        return Err(anyhow!("line {number}: {name} is already defined"));
+    }
+    Ok(())
+}
+
+fn immediate_len(instr: Instruction, arg: Option<&str>) -> Result<usize> {
+    let len = match instr {
+        Instruction::PushInt => 4,
+        Instruction::PushInt64 | Instruction::PushU64 => 8,
+        Instruction::PushU256 => 32,
+        Instruction::PushBool | Instruction::PushByte => 1,
+        Instruction::PushBytes => {
+            let hex = arg
+                .and_then(|arg| arg.strip_prefix("0x"))
+                .unwrap_or_default();
+            let len = hex.len() / 2;
+            if len > u16::MAX as usize {
+                return Err(anyhow!("{instr:?} takes at most {} bytes", u16::MAX));
+            }
+            2 + len
+        }
+        _ => {
+            if arg.is_some() {
+                return Err(anyhow!("{instr:?} takes no argument"));
+            }
+            return Ok(0);
+        }
+    };
+
+    if arg.is_none() {
+        return Err(anyhow!("{instr:?} needs an argument"));
+    }
+    Ok(len)
+}
+
+fn encode_immediate(code: &mut Vec<u8>, line: &Line, symbols: &HashMap<&str, i64>) -> Result<()> {
+    let Some(arg) = line.arg else {
+        return Ok(());
+    };
+
+    let number = || {
+        parse_number(arg)
+            .or_else(|| symbols.get(arg).copied())
+            .ok_or_else(|| anyhow!("unknown symbol {arg}"))
+    };
+
+    match line.instr {
+        Instruction::PushInt => {
+            let value =
+                i32::try_from(number()?).map_err(|_| anyhow!("{arg} doesn't fit into an int"))?;
+            code.extend_from_slice(&value.to_le_bytes());
+        }
+        Instruction::PushInt64 => code.extend_from_slice(&number()?.to_le_bytes()),
+        Instruction::PushU64 => {
+            // u64 values above i64::MAX can only be written as literals
+            let value = match parse_u256(arg) {
+                Some(value) => u64::try_from(value).ok(),
+                None => u64::try_from(number()?).ok(),
+            };
+            let value = value.ok_or_else(|| anyhow!("{arg} doesn't fit into a u64"))?;
+            code.extend_from_slice(&value.to_le_bytes());
+        }
+        Instruction::PushU256 => {
+            let value = match parse_u256(arg) {
+                Some(value) => value,
+                None => {
+                    U256::from(u64::try_from(number()?).map_err(|_| anyhow!("{arg} is negative"))?)
+                }
+            };
+            code.extend_from_slice(&value.to_le_bytes());
+        }
+        Instruction::PushByte => {
+            let value =
+                u8::try_from(number()?).map_err(|_| anyhow!("{arg} doesn't fit into a byte"))?;
+            code.push(value);
+        }
+        Instruction::PushBool => match arg {
+            "true" => code.push(1),
+            "false" => code.push(0),
+            _ => return Err(anyhow!("expected true or false, got {arg}")),
+        },
+        Instruction::PushBytes => {
+            let bytes = arg
+                .strip_prefix("0x")
+                .and_then(from_hex)
+                .ok_or_else(|| anyhow!("invalid bytes {arg}"))?;
+            code.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+            code.extend_from_slice(&bytes);
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+fn parse_number(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i64>().ok()?,
+    };
+
+    Some(if negative { -value } else { value })
+}
+
+// An unsigned number of up to 256 bits, decimal or 0x hex
+fn parse_u256(s: &str) -> Option<U256> {
+    let (digits, radix) = match s.strip_prefix("0x") {
+        Some(hex) => (hex, 16),
+        None => (s, 10),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+
+    digits.chars().try_fold(U256::ZERO, |value, c| {
+        let digit = c.to_digit(radix)?;
+        value
+            .checked_mul(U256::from(radix as u64))?
+            .checked_add(U256::from(digit as u64))
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() -> Result<()> {
+        let code = assemble(
+            "
+            .const KEY 4
+            ; store 2 at KEY
+            PushInt 2
+            PushInt KEY
+            Store
+            start: PushBool true
+            pushint start   ; mnemonics are case insensitive
+            JumpIf
+            PushByte 0xff
+            PushInt64 -1
+            PushBytes 0xaabb
+            ",
+        )?;
+
+        let expected = [
+            [0xaa, 2, 0, 0, 0, 0xaa, 4, 0, 0, 0, 0xbc].as_slice(),
+            &[0xab, 1, 0xaa, 11, 0, 0, 0, 0xce],
+            &[0xac, 0xff],
+            &[0xde, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            &[0xdf, 2, 0, 0xaa, 0xbb],
+        ]
+        .concat();
+        assert_eq!(code, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_forward_label() -> Result<()> {
+        let code = assemble("PushInt end\nJump\nRevert\nend: Halt")?;
+        assert_eq!(code, vec![0xaa, 7, 0, 0, 0, 0xcd, 0xdd, 0xdc]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        for source in [
+            "Push 1",
+            "PushInt",
+            "Add 1",
+            "PushInt unknown",
+            "PushInt 0x100000000",
+            "PushByte 256",
+            "PushBool 1",
+            "PushBytes 0xabc",
+            "PushU64 -1",
+            "PushU64 0x10000000000000000",
+            "PushU256 0x1ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "a: Add\na: Add",
+            ".const 1 2",
+        ] {
+            assert!(assemble(source).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_disassemble() -> Result<()> {
+        let source_without_offsets =
+            "PushInt -3\nPushBool false\nPushByte 0x0a\nPushInt64 5\nPushBytes 0x0102\nPushU64 18446744073709551615\nPushU256 0xff00000000000000000000000000000001\nAdd\n";
+        let listing = disassemble(&assemble(source_without_offsets)?)?;
+
+        let expected = "\
+0000: PushInt -3
+0005: PushBool false
+0007: PushByte 0x0a
+0009: PushInt64 5
+0018: PushBytes 0x0102
+0023: PushU64 18446744073709551615
+0032: PushU256 0xff00000000000000000000000000000001
+0065: Add
+";
+        assert_eq!(listing, expected);
+
+        // the listing without the offsets assembles to the same code again
+        let source: String = listing
+            .lines()
+            .map(|line| format!("{}\n", &line[6..]))
+            .collect();
+        assert_eq!(assemble(&source)?, assemble(source_without_offsets)?);
+
+        Ok(())
+    }
+}
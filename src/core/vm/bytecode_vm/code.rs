@@ -0,0 +1,145 @@
+/*
+    Bytecode format: every instruction is an opcode followed by its immediate, integers are little endian.
+
+    PushInt    | 0xaa | i32 (4 bytes) |
+    PushBool   | 0xab | 0 or 1        |
+    PushByte   | 0xac | u8            |
+    PushInt64  | 0xde | i64 (8 bytes) |
+    PushBytes  | 0xdf | len: u16 | len bytes |
+    PushU64    | 0xea | u64 (8 bytes) |
+    PushU256   | 0xeb | u256 (32 bytes) |
+
+    All other instructions have no immediate. The whole code is decoded before it runs,
+    malformed code is rejected before a single instruction is executed.
+*/
+
+use super::{instruction::Instruction, stack::StackItem, u256::U256};
+use crate::core::vm::VmError;
+
+// An instruction of the decoded code, `offset` is where its opcode is in the code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Op {
+    pub offset: usize,
+    pub instr: Instruction,
+    // the item a push instruction pushes onto the stack
+    pub immediate: Option<StackItem>,
+}
+
+pub fn decode(code: &[u8]) -> Result<Vec<Op>, VmError> {
+    let mut ops = vec![];
+    let mut offset = 0;
+
+    while offset < code.len() {
+        let instr =
+            Instruction::try_from(code[offset]).map_err(|_| VmError::InvalidInstruction(offset))?;
+        let rest = &code[offset + 1..];
+
+        let (immediate, len) = match instr {
+            Instruction::PushInt => {
+                let bytes = take::<4>(rest, offset)?;
+                (Some(StackItem::Int(i32::from_le_bytes(bytes))), 4)
+            }
+            Instruction::PushInt64 => {
+                let bytes = take::<8>(rest, offset)?;
+                (Some(StackItem::Int64(i64::from_le_bytes(bytes))), 8)
+            }
+            Instruction::PushU64 => {
+                let bytes = take::<8>(rest, offset)?;
+                (Some(StackItem::U64(u64::from_le_bytes(bytes))), 8)
+            }
+            Instruction::PushU256 => {
+                let bytes = take::<32>(rest, offset)?;
+                (Some(StackItem::U256(U256::from_le_bytes(bytes))), 32)
+            }
+            Instruction::PushByte => {
+                let [byte] = take::<1>(rest, offset)?;
+                (Some(StackItem::Byte(byte)), 1)
+            }
+            Instruction::PushBool => match take::<1>(rest, offset)? {
+                [0] => (Some(StackItem::Bool(false)), 1),
+                [1] => (Some(StackItem::Bool(true)), 1),
+                _ => return Err(VmError::InvalidImmediate(offset)),
+            },
+            Instruction::PushBytes => {
+                let len = u16::from_le_bytes(take::<2>(rest, offset)?) as usize;
+                let bytes = rest
+                    .get(2..2 + len)
+                    .ok_or(VmError::InvalidImmediate(offset))?;
+                (Some(StackItem::Bytes(bytes.to_vec())), 2 + len)
+            }
+            _ => (None, 0),
+        };
+
+        ops.push(Op {
+            offset,
+            instr,
+            immediate,
+        });
+        offset += 1 + len;
+    }
+
+    Ok(ops)
+}
+
+fn take<const L: usize>(bytes: &[u8], offset: usize) -> Result<[u8; L], VmError> {
+    bytes
+        .get(..L)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(VmError::InvalidImmediate(offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() -> Result<(), VmError> {
+        let code = [
+            [0xaa].as_slice(),
+            &(-2i32).to_le_bytes(),
+            &[0xab, 0x01],
+            &[0xde],
+            &i64::MAX.to_le_bytes(),
+            &[0xdf, 0x02, 0x00, 0xaa, 0xbb],
+            &[0xea],
+            &u64::MAX.to_le_bytes(),
+            &[0xeb],
+            &U256::MAX.to_le_bytes(),
+            &[0xad],
+        ]
+        .concat();
+
+        let ops = decode(&code)?;
+        let offsets: Vec<usize> = ops.iter().map(|op| op.offset).collect();
+        assert_eq!(offsets, vec![0, 5, 7, 16, 21, 30, 63]);
+
+        assert_eq!(ops[0].immediate, Some(StackItem::Int(-2)));
+        assert_eq!(ops[1].immediate, Some(StackItem::Bool(true)));
+        assert_eq!(ops[2].immediate, Some(StackItem::Int64(i64::MAX)));
+        assert_eq!(ops[3].immediate, Some(StackItem::Bytes(vec![0xaa, 0xbb])));
+        assert_eq!(ops[4].immediate, Some(StackItem::U64(u64::MAX)));
+        assert_eq!(ops[5].immediate, Some(StackItem::U256(U256::MAX)));
+        assert_eq!(ops[6].instr, Instruction::Add);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        // unknown opcode
+        assert_eq!(decode(&[0xad, 0x07]), Err(VmError::InvalidInstruction(1)));
+        // truncated immediates
+        assert_eq!(
+            decode(&[0xaa, 0x01, 0x00]),
+            Err(VmError::InvalidImmediate(0))
+        );
+        assert_eq!(
+            decode(&[0xdf, 0x03, 0x00, 0x01]),
+            Err(VmError::InvalidImmediate(0))
+        );
+        assert_eq!(decode(&[0xdf, 0x03]), Err(VmError::InvalidImmediate(0)));
+        assert_eq!(decode(&[0xeb, 0x01]), Err(VmError::InvalidImmediate(0)));
+        // a bool is 0 or 1
+        assert_eq!(decode(&[0xab, 0x02]), Err(VmError::InvalidImmediate(0)));
+    }
+}
@@ -1,3 +1,9 @@
+// Charged on top of the gas cost for every byte an instruction copies onto the stack
+pub const BYTE_GAS: u64 = 1;
+// Charged on top of the gas cost for every byte of a key or value read from or written to the state,
+// the same as in the WasmVM
+pub const STATE_BYTE_GAS: u64 = 10;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     PushInt = 0xaa,
@@ -9,6 +15,33 @@ pub enum Instruction {
     Mul = 0xba,
     Div = 0xbb,
     Store = 0xbc,
+    Eq = 0xbd,
+    Lt = 0xbe,
+    Gt = 0xbf,
+    Not = 0xca,
+    And = 0xcb,
+    Or = 0xcc,
+    Jump = 0xcd,
+    JumpIf = 0xce,
+    Dup = 0xcf,
+    Swap = 0xda,
+    Pop = 0xdb,
+    Halt = 0xdc,
+    Revert = 0xdd,
+    PushInt64 = 0xde,
+    PushBytes = 0xdf,
+    PushU64 = 0xea,
+    PushU256 = 0xeb,
+    Input = 0xec,
+    Log = 0xed,
+    Caller = 0xee,
+    TxHash = 0xef,
+    BlockHeight = 0xfa,
+    Timestamp = 0xfb,
+    Validator = 0xfc,
+    GasLeft = 0xfd,
+    Call = 0xfe,
+    Return = 0xff,
 }
 
 impl TryFrom<u8> for Instruction {
@@ -25,7 +58,95 @@ impl TryFrom<u8> for Instruction {
             0xba => Ok(Self::Mul),
             0xbb => Ok(Self::Div),
             0xbc => Ok(Self::Store),
+            0xbd => Ok(Self::Eq),
+            0xbe => Ok(Self::Lt),
+            0xbf => Ok(Self::Gt),
+            0xca => Ok(Self::Not),
+            0xcb => Ok(Self::And),
+            0xcc => Ok(Self::Or),
+            0xcd => Ok(Self::Jump),
+            0xce => Ok(Self::JumpIf),
+            0xcf => Ok(Self::Dup),
+            0xda => Ok(Self::Swap),
+            0xdb => Ok(Self::Pop),
+            0xdc => Ok(Self::Halt),
+            0xdd => Ok(Self::Revert),
+            0xde => Ok(Self::PushInt64),
+            0xdf => Ok(Self::PushBytes),
+            0xea => Ok(Self::PushU64),
+            0xeb => Ok(Self::PushU256),
+            0xec => Ok(Self::Input),
+            0xed => Ok(Self::Log),
+            0xee => Ok(Self::Caller),
+            0xef => Ok(Self::TxHash),
+            0xfa => Ok(Self::BlockHeight),
+            0xfb => Ok(Self::Timestamp),
+            0xfc => Ok(Self::Validator),
+            0xfd => Ok(Self::GasLeft),
+            0xfe => Ok(Self::Call),
+            0xff => Ok(Self::Return),
             _ => Err(anyhow::anyhow!("Invalid Instruction: {}", value)),
         }
     }
 }
+
+impl Instruction {
+    pub const ALL: [Instruction; 36] = [
+        Self::PushInt,
+        Self::PushBool,
+        Self::PushByte,
+        Self::Add,
+        Self::Sub,
+        Self::Get,
+        Self::Mul,
+        Self::Div,
+        Self::Store,
+        Self::Eq,
+        Self::Lt,
+        Self::Gt,
+        Self::Not,
+        Self::And,
+        Self::Or,
+        Self::Jump,
+        Self::JumpIf,
+        Self::Dup,
+        Self::Swap,
+        Self::Pop,
+        Self::Halt,
+        Self::Revert,
+        Self::PushInt64,
+        Self::PushBytes,
+        Self::PushU64,
+        Self::PushU256,
+        Self::Input,
+        Self::Log,
+        Self::Caller,
+        Self::TxHash,
+        Self::BlockHeight,
+        Self::Timestamp,
+        Self::Validator,
+        Self::GasLeft,
+        Self::Call,
+        Self::Return,
+    ];
+
+    // Gas schedule, instructions that touch the state are much more expensive than stack operations
+    pub fn gas_cost(&self) -> u64 {
+        match self {
+            Self::Halt | Self::Revert | Self::Return => 0,
+            Self::PushInt | Self::PushInt64 | Self::PushU64 => 1,
+            Self::PushBool | Self::PushByte | Self::Pop => 1,
+            Self::PushU256 | Self::PushBytes | Self::Input => 3,
+            Self::Dup | Self::Swap => 2,
+            Self::Caller | Self::TxHash | Self::Validator => 2,
+            Self::BlockHeight | Self::Timestamp | Self::GasLeft => 2,
+            Self::Add | Self::Sub | Self::Eq | Self::Lt | Self::Gt => 3,
+            Self::Not | Self::And | Self::Or => 3,
+            Self::Mul | Self::Div => 5,
+            Self::Jump => 8,
+            Self::JumpIf => 10,
+            Self::Get | Self::Log | Self::Call => 100,
+            Self::Store => 1000,
+        }
+    }
+}
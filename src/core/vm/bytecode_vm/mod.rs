@@ -1,21 +1,96 @@
-use crate::core::state::{DynState, State};
+use crate::core::{
+    contract_code, execution_storage,
+    state::{DynState, State},
+    Address,
+};
 
+pub mod asm;
+mod code;
 mod instruction;
 mod stack;
+mod u256;
 
 use self::{
-    instruction::Instruction,
+    code::{decode, Op},
+    instruction::{Instruction, BYTE_GAS, STATE_BYTE_GAS},
     stack::{Stack, StackItem},
+    u256::U256,
+};
+
+use super::{
+    trace::{DynTracer, StateAccess, Step},
+    ExecutionContext, ExecutionResult, Log, VmError, MAX_CALL_DEPTH, MAX_LOG_TOPICS, VM,
 };
+use std::cmp::Ordering;
+
+// What happens after an instruction was executed
+enum Flow {
+    Next,
+    Jump(usize),
+    Halt,
+}
+
+// Checked arithmetic of the integer types on the stack
+trait CheckedInteger: Sized {
+    fn checked_add(self, other: Self) -> Option<Self>;
+    fn checked_sub(self, other: Self) -> Option<Self>;
+    fn checked_mul(self, other: Self) -> Option<Self>;
+    fn checked_div(self, other: Self) -> Option<Self>;
+    fn is_zero(&self) -> bool;
+}
+
+macro_rules! checked_integer {
+    ($($t:ty),*) => {
+        $(impl CheckedInteger for $t {
+            fn checked_add(self, other: Self) -> Option<Self> {
+                <$t>::checked_add(self, other)
+            }
+            fn checked_sub(self, other: Self) -> Option<Self> {
+                <$t>::checked_sub(self, other)
+            }
+            fn checked_mul(self, other: Self) -> Option<Self> {
+                <$t>::checked_mul(self, other)
+            }
+            fn checked_div(self, other: Self) -> Option<Self> {
+                <$t>::checked_div(self, other)
+            }
+            fn is_zero(&self) -> bool {
+                *self == <$t>::default()
+            }
+        })*
+    };
+}
+
+checked_integer!(i32, i64, u64, U256);
 
-use super::VM;
-use anyhow::Result;
+fn checked<T: CheckedInteger>(instr: Instruction, a: T, b: T) -> Result<T, VmError> {
+    let result = match instr {
+        Instruction::Add => a.checked_add(b),
+        Instruction::Sub => a.checked_sub(b),
+        Instruction::Mul => a.checked_mul(b),
+        Instruction::Div if b.is_zero() => return Err(VmError::DivisionByZero),
+        Instruction::Div => a.checked_div(b),
+        _ => None,
+    };
+    result.ok_or(VmError::Overflow)
+}
 
 #[derive(Debug, Clone)]
 pub struct BytecodeVM<const N: usize> {
-    // Instruction Pointer
+    // Instruction Pointer, the index of the next instruction in the decoded code
     ip: usize,
     stack: Stack<N>,
+    // context of the current execution
+    ctx: ExecutionContext,
+    gas_limit: u64,
+    // gas used so far, including the gas used by nested calls
+    gas_used: u64,
+    // logs emitted by the current call
+    logs: Vec<Log>,
+    return_data: Vec<u8>,
+    tracer: Option<DynTracer>,
+    // state accesses of the current instruction, only recorded while tracing
+    accesses: Vec<StateAccess>,
 }
 
 impl<const N: usize> BytecodeVM<N> {
@@ -23,192 +98,1091 @@ impl<const N: usize> BytecodeVM<N> {
         Self {
             ip: 0,
             stack: Stack::new(),
+            ctx: ExecutionContext::default(),
+            gas_limit: 0,
+            gas_used: 0,
+            logs: vec![],
+            return_data: vec![],
+            tracer: None,
+            accesses: vec![],
         }
     }
 
-    fn arithmetic_operation<F>(&mut self, f: F) -> Result<()>
+    fn gas_left(&self) -> u64 {
+        self.gas_limit - self.gas_used
+    }
+
+    /*
+        Gas of the instruction before it runs. The ones that copy bytes onto the stack also pay for every byte,
+        the ones that access the state for every byte of the key and of the value they write.
+        The value Get reads and pushes is only known while it runs, it is charged then, see `charge`
+    */
+    fn gas_cost(&self, op: &Op) -> u64 {
+        let items = self.stack.items();
+        let top = |depth: usize| items.len().checked_sub(depth + 1).map(|i| &items[i]);
+
+        let copied = match (op.instr, &op.immediate, top(0)) {
+            (Instruction::PushBytes, Some(StackItem::Bytes(bytes)), _) => bytes.len(),
+            (Instruction::Dup, _, Some(StackItem::Bytes(bytes))) => bytes.len(),
+            (Instruction::Input, ..) => self.ctx.input.len(),
+            _ => 0,
+        };
+        let key_len = || top(0).map_or(0, |key| key.to_bytes().len());
+        let state_bytes = match op.instr {
+            Instruction::Get => key_len(),
+            Instruction::Store => key_len() + top(1).map_or(0, |value| value.encode().len()),
+            _ => 0,
+        };
+
+        op.instr.gas_cost() + copied as u64 * BYTE_GAS + state_bytes as u64 * STATE_BYTE_GAS
+    }
+
+    // Charges gas while an instruction runs, running out of gas uses up the whole limit
+    fn charge(&mut self, gas: u64) -> Result<(), VmError> {
+        self.gas_used = self.gas_used.saturating_add(gas);
+        if self.gas_used > self.gas_limit {
+            self.gas_used = self.gas_limit;
+            return Err(VmError::OutOfGas);
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, access: StateAccess) {
+        if self.tracer.is_some() {
+            self.accesses.push(access);
+        }
+    }
+
+    // The step of the instruction before it runs, its state accesses are added afterwards
+    fn begin_step(&self, op: &Op) -> Step {
+        Step {
+            depth: self.ctx.depth,
+            ip: self.ip,
+            offset: op.offset,
+            opcode: format!("{:?}", op.instr),
+            stack: self
+                .stack
+                .items()
+                .iter()
+                .map(|item| format!("{item:?}"))
+                .collect(),
+            gas_left: self.gas_left(),
+            gas_cost: self.gas_cost(op),
+            state: vec![],
+        }
+    }
+
+    // Jumps have to land on the opcode of an instruction
+    fn jump_destination(ops: &[Op], target: StackItem) -> Result<usize, VmError> {
+        let StackItem::Int(target) = target else {
+            return Err(VmError::TypeMismatch);
+        };
+
+        usize::try_from(target)
+            .ok()
+            .and_then(|offset| ops.binary_search_by_key(&offset, |op| op.offset).ok())
+            .ok_or(VmError::InvalidJump(target))
+    }
+
+    /*
+        Arithmetic is checked: both items have to be of the same integer type and the result has
+        that type as well. A result that doesn't fit into the type fails with Overflow and a
+        division by zero with DivisionByZero, neither ever wraps around or panics
+    */
+    fn arithmetic_operation(&mut self, instr: Instruction) -> Result<(), VmError> {
+        let a = self.stack.pop()?;
+        let b = self.stack.pop()?;
+
+        let result = match (a, b) {
+            (StackItem::Int(a), StackItem::Int(b)) => StackItem::Int(checked(instr, a, b)?),
+            (StackItem::Int64(a), StackItem::Int64(b)) => StackItem::Int64(checked(instr, a, b)?),
+            (StackItem::U64(a), StackItem::U64(b)) => StackItem::U64(checked(instr, a, b)?),
+            (StackItem::U256(a), StackItem::U256(b)) => StackItem::U256(checked(instr, a, b)?),
+            _ => return Err(VmError::TypeMismatch),
+        };
+
+        self.stack.push(result)?;
+        Ok(())
+    }
+
+    fn comparison_operation<F>(&mut self, f: F) -> Result<(), VmError>
     where
-        F: Fn(i32, i32) -> i32,
+        F: Fn(Ordering) -> bool,
     {
-        let a = self.stack.pop();
-        let b = self.stack.pop();
+        let a = self.stack.pop()?;
+        let b = self.stack.pop()?;
 
-        if let StackItem::Int(a) = a {
-            if let StackItem::Int(b) = b {
-                self.stack.push_front(StackItem::Int(f(a, b)));
-                return Ok(());
-            }
+        let ordering = match (a, b) {
+            (StackItem::Int(a), StackItem::Int(b)) => a.cmp(&b),
+            (StackItem::Int64(a), StackItem::Int64(b)) => a.cmp(&b),
+            (StackItem::U64(a), StackItem::U64(b)) => a.cmp(&b),
+            (StackItem::U256(a), StackItem::U256(b)) => a.cmp(&b),
+            _ => return Err(VmError::TypeMismatch),
+        };
+
+        self.stack.push(StackItem::Bool(f(ordering)))?;
+        Ok(())
+    }
+
+    fn logic_operation<F>(&mut self, f: F) -> Result<(), VmError>
+    where
+        F: Fn(bool, bool) -> bool,
+    {
+        let a = self.stack.pop()?;
+        let b = self.stack.pop()?;
+
+        if let (StackItem::Bool(a), StackItem::Bool(b)) = (a, b) {
+            self.stack.push(StackItem::Bool(f(a, b)))?;
+            return Ok(());
+        }
+
+        Err(VmError::TypeMismatch)
+    }
+
+    /*
+        Runs the code of `contract` in a new frame with its own stack, the frame can use at most `gas`
+        and at most all but one 64th of the gas that is left, so that the caller can always finish.
+        A failed call reverts its state changes and drops its logs, the caller only sees that it failed
+    */
+    async fn call(
+        &mut self,
+        state: &DynState,
+        contract: Address,
+        input: Vec<u8>,
+        gas: u64,
+    ) -> Result<(bool, Vec<u8>), VmError> {
+        if self.ctx.depth >= MAX_CALL_DEPTH {
+            return Ok((false, vec![]));
         }
+        let Some(code) = contract_code(state, &contract).await else {
+            return Ok((false, vec![]));
+        };
+
+        let gas = gas.min(self.gas_left() - self.gas_left() / 64);
+        let ctx = ExecutionContext {
+            sender: self.ctx.address.or(self.ctx.sender),
+            address: Some(contract),
+            depth: self.ctx.depth + 1,
+            input,
+            ..self.ctx.clone()
+        };
+
+        // boxed to keep the stack array of the frame off the stack of the caller
+        let mut frame = Box::new(Self::new());
+        frame.tracer = self.tracer.clone();
 
-        Err(anyhow::anyhow!("Invalid Stack Items"))
+        let state_error = |err: anyhow::Error| VmError::State(err.to_string());
+        state.checkpoint().await.map_err(state_error)?;
+        let result = frame.execute(state, &code, &ctx, gas).await;
+        self.gas_used += result.gas_used;
+
+        if result.error.is_some() {
+            state.revert().await.map_err(state_error)?;
+            return Ok((false, vec![]));
+        }
+        state.commit().await.map_err(state_error)?;
+        self.logs.extend(result.logs);
+        Ok((true, result.return_data))
     }
 
     async fn execute_instruction(
         &mut self,
         state: &DynState,
-        instr: Instruction,
-        code: &[u8],
-    ) -> Result<()> {
-        match instr {
-            Instruction::PushBool => {
-                let i = self.ip.saturating_sub(1);
-                let item = StackItem::Bool(code[i] != 0);
-                self.stack.push_front(item);
-            }
-            Instruction::PushInt => {
-                let i = self.ip.saturating_sub(1);
-                let item = StackItem::Int(code[i] as i32);
-                self.stack.push_front(item);
-            }
-            Instruction::PushByte => {
-                let i = self.ip.saturating_sub(1);
-                let item = StackItem::Byte(code[i]);
-                self.stack.push_front(item);
+        storage: &DynState,
+        op: Op,
+        ops: &[Op],
+    ) -> Result<Flow, VmError> {
+        match op.instr {
+            Instruction::PushInt
+            | Instruction::PushInt64
+            | Instruction::PushBool
+            | Instruction::PushByte
+            | Instruction::PushBytes
+            | Instruction::PushU64
+            | Instruction::PushU256 => {
+                let item = op.immediate.ok_or(VmError::InvalidImmediate(op.offset))?;
+                self.stack.push(item)?;
+            }
+            Instruction::Input => {
+                self.stack.push(StackItem::Bytes(self.ctx.input.clone()))?;
+            }
+            Instruction::Caller => {
+                let sender = self.ctx.sender.ok_or(VmError::MissingSender)?;
+                self.stack
+                    .push(StackItem::Bytes(sender.as_bytes().to_vec()))?;
+            }
+            Instruction::TxHash => {
+                let hash = self.ctx.tx_hash.as_bytes().to_vec();
+                self.stack.push(StackItem::Bytes(hash))?;
+            }
+            Instruction::BlockHeight => {
+                self.stack
+                    .push(StackItem::U64(self.ctx.block_height as u64))?;
+            }
+            Instruction::Timestamp => {
+                self.stack.push(StackItem::U64(self.ctx.block_timestamp))?;
+            }
+            Instruction::Validator => {
+                let validator = self.ctx.validator.ok_or(VmError::MissingValidator)?;
+                self.stack
+                    .push(StackItem::Bytes(validator.as_bytes().to_vec()))?;
+            }
+            Instruction::GasLeft => {
+                self.stack.push(StackItem::U64(self.gas_left()))?;
+            }
+            // pops the address, the input and the gas for the call,
+            // pushes the returned data and whether the call succeeded
+            Instruction::Call => {
+                let StackItem::Bytes(address) = self.stack.pop()? else {
+                    return Err(VmError::TypeMismatch);
+                };
+                if address.len() != 20 {
+                    return Err(VmError::TypeMismatch);
+                }
+                let input = self.stack.pop()?.to_bytes();
+                let StackItem::U64(gas) = self.stack.pop()? else {
+                    return Err(VmError::TypeMismatch);
+                };
+
+                let (success, data) = self
+                    .call(state, Address::from_bytes(&address), input, gas)
+                    .await?;
+                self.stack.push(StackItem::Bytes(data))?;
+                self.stack.push(StackItem::Bool(success))?;
+            }
+            Instruction::Return => {
+                self.return_data = self.stack.pop()?.to_bytes();
+                return Ok(Flow::Halt);
+            }
+            // pops the data, the number of topics and then the topics
+            Instruction::Log => {
+                let data = self.stack.pop()?.to_bytes();
+                let StackItem::Byte(count) = self.stack.pop()? else {
+                    return Err(VmError::TypeMismatch);
+                };
+                if count as usize > MAX_LOG_TOPICS {
+                    return Err(VmError::TooManyTopics);
+                }
+
+                let mut topics = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    topics.push(self.stack.pop()?.to_bytes());
+                }
+
+                self.logs.push(Log {
+                    address: self.ctx.address,
+                    topics,
+                    data,
+                });
             }
             Instruction::Add => {
-                self.arithmetic_operation(|a, b| a + b)?;
+                self.arithmetic_operation(Instruction::Add)?;
             }
             Instruction::Sub => {
-                self.arithmetic_operation(|a, b| a - b)?;
+                self.arithmetic_operation(Instruction::Sub)?;
             }
             Instruction::Get => {
-                let key = self.stack.pop();
-                let val = state.get(&key.to_bytes()).await?;
-
-                match val.len() {
-                    1 => {
-                        let item = StackItem::Byte(val[0]);
-                        self.stack.push_front(item)
-                    }
-                    4 => {
-                        let item =
-                            StackItem::Int(i32::from_le_bytes([val[0], val[1], val[2], val[3]]));
-                        self.stack.push_front(item);
-                    }
-                    64 => {
-                        let mut bytes = [0u8; 64];
-                        bytes.copy_from_slice(&val);
-                        let item = StackItem::Bytes(bytes);
-                        self.stack.push_front(item);
-                    }
-                    _ => {}
-                }
+                let key = self.stack.pop()?;
+                let val = storage.get(&key.to_bytes()).await;
+                self.record(StateAccess::Read {
+                    key: key.to_bytes(),
+                    value: val.as_ref().ok().cloned(),
+                });
+                let val = val.map_err(|err| VmError::State(err.to_string()))?;
+                self.charge(val.len() as u64 * (STATE_BYTE_GAS + BYTE_GAS))?;
+
+                let item = StackItem::decode(&val).ok_or_else(|| {
+                    VmError::State(format!("invalid value at key {:?}", key.to_bytes()))
+                })?;
+                self.stack.push(item)?;
             }
             Instruction::Mul => {
-                self.arithmetic_operation(|a, b| a * b)?;
+                self.arithmetic_operation(Instruction::Mul)?;
             }
             Instruction::Div => {
-                self.arithmetic_operation(|a, b| a / b)?;
+                self.arithmetic_operation(Instruction::Div)?;
             }
             Instruction::Store => {
-                let key = self.stack.pop();
-                let val = self.stack.pop();
+                let key = self.stack.pop()?;
+                let val = self.stack.pop()?;
+                self.record(StateAccess::Write {
+                    key: key.to_bytes(),
+                    value: val.encode(),
+                });
 
-                state.set(&key.to_bytes(), &val.to_bytes()).await?;
+                storage
+                    .set(&key.to_bytes(), &val.encode())
+                    .await
+                    .map_err(|err| VmError::State(err.to_string()))?;
+            }
+            Instruction::Eq => {
+                let a = self.stack.pop()?;
+                let b = self.stack.pop()?;
+                self.stack.push(StackItem::Bool(a == b))?;
+            }
+            Instruction::Lt => {
+                self.comparison_operation(|ordering| ordering == Ordering::Less)?;
+            }
+            Instruction::Gt => {
+                self.comparison_operation(|ordering| ordering == Ordering::Greater)?;
+            }
+            Instruction::Not => {
+                let StackItem::Bool(a) = self.stack.pop()? else {
+                    return Err(VmError::TypeMismatch);
+                };
+                self.stack.push(StackItem::Bool(!a))?;
+            }
+            Instruction::And => {
+                self.logic_operation(|a, b| a && b)?;
+            }
+            Instruction::Or => {
+                self.logic_operation(|a, b| a || b)?;
+            }
+            Instruction::Jump => {
+                let target = self.stack.pop()?;
+                return Ok(Flow::Jump(Self::jump_destination(ops, target)?));
+            }
+            Instruction::JumpIf => {
+                let target = self.stack.pop()?;
+                let StackItem::Bool(condition) = self.stack.pop()? else {
+                    return Err(VmError::TypeMismatch);
+                };
+                if condition {
+                    return Ok(Flow::Jump(Self::jump_destination(ops, target)?));
+                }
+            }
+            Instruction::Dup => {
+                let a = self.stack.pop()?;
+                self.stack.push(a.clone())?;
+                self.stack.push(a)?;
+            }
+            Instruction::Swap => {
+                let a = self.stack.pop()?;
+                let b = self.stack.pop()?;
+                self.stack.push(a)?;
+                self.stack.push(b)?;
             }
-            _ => {}
+            Instruction::Pop => {
+                self.stack.pop()?;
+            }
+            Instruction::Halt => return Ok(Flow::Halt),
+            Instruction::Revert => return Err(VmError::Reverted),
         }
 
-        Ok(())
+        Ok(Flow::Next)
     }
 }
 
 #[async_trait::async_trait]
 impl<const N: usize> VM for BytecodeVM<N> {
-    async fn execute(&mut self, state: &DynState, code: &[u8]) -> Result<()> {
+    async fn execute(
+        &mut self,
+        state: &DynState,
+        code: &[u8],
+        ctx: &ExecutionContext,
+        gas_limit: u64,
+    ) -> ExecutionResult {
         self.ip = 0;
+        self.stack.clear();
+        self.ctx = ctx.clone();
+        self.logs.clear();
+        self.return_data.clear();
+        self.accesses.clear();
+        self.gas_limit = gas_limit;
+        self.gas_used = 0;
+        let storage = execution_storage(state, ctx.address.as_ref());
+
+        let ops = match decode(code) {
+            Ok(ops) => ops,
+            Err(err) => return ExecutionResult::failed(0, err),
+        };
 
-        loop {
-            // TODO: handle this better (maybe check previous instruction and determine if we are getting a value)
-            if let Ok(instr) = Instruction::try_from(code[self.ip]) {
-                self.execute_instruction(state, instr, code).await?;
+        while let Some(op) = ops.get(self.ip).cloned() {
+            let step = self.tracer.is_some().then(|| self.begin_step(&op));
+
+            // The gas is charged before the instruction runs, running out of gas uses up the whole limit
+            self.gas_used += self.gas_cost(&op);
+            if self.gas_used > gas_limit {
+                return ExecutionResult::failed(gas_limit, VmError::OutOfGas);
             }
 
-            self.ip += 1;
+            let flow = self.execute_instruction(state, &storage, op, &ops).await;
+            if let (Some(tracer), Some(mut step)) = (&mut self.tracer, step) {
+                step.state = std::mem::take(&mut self.accesses);
+                tracer.step(&step);
+            }
 
-            if self.ip >= code.len() {
-                break;
+            match flow {
+                Ok(Flow::Next) => self.ip += 1,
+                Ok(Flow::Jump(ip)) => self.ip = ip,
+                Ok(Flow::Halt) => break,
+                Err(err) => {
+                    return ExecutionResult::failed(self.gas_used, err);
+                }
             }
         }
 
-        Ok(())
+        ExecutionResult {
+            gas_used: self.gas_used,
+            error: None,
+            logs: std::mem::take(&mut self.logs),
+            return_data: std::mem::take(&mut self.return_data),
+        }
+    }
+
+    fn set_tracer(&mut self, tracer: Option<DynTracer>) {
+        self.tracer = tracer;
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::core::state::mem_state::MemState;
+    use crate::core::{
+        global_storage,
+        state::mem_state::MemState,
+        vm::{trace::TraceRecorder, DEFAULT_TX_GAS_LIMIT},
+        Address, Hash,
+    };
+    use anyhow::Result;
 
     use super::*;
 
+    // Runs the code on an empty state and returns the top of the stack
+    async fn run(code: &[u8]) -> Result<StackItem> {
+        let mut vm: BytecodeVM<256> = BytecodeVM::new();
+        let state = Box::new(MemState::new()) as DynState;
+        vm.execute(
+            &state,
+            code,
+            &ExecutionContext::default(),
+            DEFAULT_TX_GAS_LIMIT,
+        )
+        .await
+        .into_result()?;
+        Ok(vm.stack.pop()?)
+    }
+
     #[tokio::test]
     async fn test_vm() -> Result<()> {
+        let res = run(&[0xaa, 0x02, 0x00, 0x00, 0x00]).await?;
+        assert_eq!(res, StackItem::Int(2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_push_immediates() -> Result<()> {
+        let code = [[0xaa].as_slice(), &(-100_000i32).to_le_bytes()].concat();
+        assert_eq!(run(&code).await?, StackItem::Int(-100_000));
+
+        let code = [[0xde].as_slice(), &i64::MIN.to_le_bytes()].concat();
+        assert_eq!(run(&code).await?, StackItem::Int64(i64::MIN));
+
+        assert_eq!(run(&[0xac, 0xaa]).await?, StackItem::Byte(0xaa));
+        assert_eq!(run(&[0xab, 0x01]).await?, StackItem::Bool(true));
+
+        let res = run(&[0xdf, 0x03, 0x00, 0xaa, 0xab, 0xac]).await?;
+        assert_eq!(res, StackItem::Bytes(vec![0xaa, 0xab, 0xac]));
+
+        let code = [[0xea].as_slice(), &u64::MAX.to_le_bytes()].concat();
+        assert_eq!(run(&code).await?, StackItem::U64(u64::MAX));
+
+        let code = [[0xeb].as_slice(), &U256::MAX.to_le_bytes()].concat();
+        assert_eq!(run(&code).await?, StackItem::U256(U256::MAX));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_add() -> Result<()> {
+        let code = vec![
+            0xaa, 0x02, 0x00, 0x00, 0x00, 0xaa, 0x03, 0x00, 0x00, 0x00, 0xad,
+        ];
+        assert_eq!(run(&code).await?, StackItem::Int(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_sub() -> Result<()> {
+        let code = vec![
+            0xaa, 0x03, 0x00, 0x00, 0x00, 0xaa, 0x02, 0x00, 0x00, 0x00, 0xae,
+        ];
+        assert_eq!(run(&code).await?, StackItem::Int(-1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_mul() -> Result<()> {
+        let code = vec![
+            0xaa, 0x03, 0x00, 0x00, 0x00, 0xaa, 0x02, 0x00, 0x00, 0x00, 0xba,
+        ];
+        assert_eq!(run(&code).await?, StackItem::Int(6));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_div() -> Result<()> {
+        let code = vec![
+            0xaa, 0x02, 0x00, 0x00, 0x00, 0xaa, 0x02, 0x00, 0x00, 0x00, 0xbb,
+        ];
+        assert_eq!(run(&code).await?, StackItem::Int(1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_int64() -> Result<()> {
+        let code = [
+            [0xde].as_slice(),
+            &3_000_000_000i64.to_le_bytes(),
+            &[0xde],
+            &2i64.to_le_bytes(),
+            &[0xba],
+        ]
+        .concat();
+        assert_eq!(run(&code).await?, StackItem::Int64(6_000_000_000));
+
+        // an int and an int64 can't be mixed
+        let code = [
+            [0xde].as_slice(),
+            &2i64.to_le_bytes(),
+            &[0xaa, 2, 0, 0, 0, 0xad],
+        ]
+        .concat();
+        assert_eq!(
+            run(&code).await.unwrap_err().downcast::<VmError>()?,
+            VmError::TypeMismatch
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_checked_arithmetic() -> Result<()> {
+        let cases = [
+            ("PushInt 0\nPushInt 1\nDiv", VmError::DivisionByZero),
+            ("PushInt64 0\nPushInt64 -1\nDiv", VmError::DivisionByZero),
+            ("PushU256 0\nPushU256 1\nDiv", VmError::DivisionByZero),
+            ("PushInt 1\nPushInt 0x7fffffff\nAdd", VmError::Overflow),
+            ("PushInt -1\nPushInt -0x80000000\nDiv", VmError::Overflow),
+            ("PushU64 2\nPushU64 1\nSub", VmError::Overflow),
+            ("PushU64 2\nPushU64 0xffffffffffffffff\nMul", VmError::Overflow),
+            (
+                "PushU256 1\nPushU256 0xffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff\nAdd",
+                VmError::Overflow,
+            ),
+            ("PushU64 1\nPushInt64 1\nAdd", VmError::TypeMismatch),
+            ("PushBytes 0x01\nPushBytes 0x01\nAdd", VmError::TypeMismatch),
+            ("PushU64 1\nPushU256 1\nLt", VmError::TypeMismatch),
+        ];
+
+        for (source, error) in cases {
+            let result = run(&asm::assemble(source)?).await;
+            assert_eq!(
+                result.unwrap_err().downcast::<VmError>()?,
+                error,
+                "{source}"
+            );
+        }
+
+        let code = asm::assemble("PushU64 3\nPushU64 0xffffffffffffffff\nDiv")?;
+        assert_eq!(run(&code).await?, StackItem::U64(u64::MAX / 3));
+
+        // 2^128 * 2^64 = 2^192
+        let code = asm::assemble(
+            "PushU256 0x10000000000000000\nPushU256 0x100000000000000000000000000000000\nMul",
+        )?;
+        let mut bytes = [0u8; 32];
+        bytes[24] = 1;
+        assert_eq!(
+            run(&code).await?,
+            StackItem::U256(U256::from_le_bytes(bytes))
+        );
+
+        let code = asm::assemble("PushU256 2\nPushU256 1\nLt")?;
+        assert_eq!(run(&code).await?, StackItem::Bool(true));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_store() -> Result<()> {
         let mut vm: BytecodeVM<256> = BytecodeVM::new();
-        let code = vec![0x02, 0xaa];
+        let code = vec![
+            0xaa, 0x02, 0x00, 0x00, 0x00, 0xaa, 0x04, 0x00, 0x00, 0x00, 0xbc,
+        ];
         let state = Box::new(MemState::new()) as DynState;
-        vm.execute(&state, &code).await?;
-        let res = vm.stack.pop();
+        vm.execute(
+            &state,
+            &code,
+            &ExecutionContext::default(),
+            DEFAULT_TX_GAS_LIMIT,
+        )
+        .await
+        .into_result()?;
+        let v = global_storage(&state).get(&[4, 0, 0, 0]).await?;
+        assert_eq!(StackItem::decode(&v), Some(StackItem::Int(2)));
+
+        vm.execute(
+            &state,
+            &[0xaa, 0x04, 0x00, 0x00, 0x00, 0xaf],
+            &ExecutionContext::default(),
+            DEFAULT_TX_GAS_LIMIT,
+        )
+        .await
+        .into_result()?;
+
+        let res = vm.stack.pop()?;
         assert_eq!(res, StackItem::Int(2));
+
+        // the stored values keep their type
+        let code = asm::assemble("PushBytes 0xaabbcc\nPushInt 5\nStore\nPushInt 5\nGet")?;
+        vm.execute(
+            &state,
+            &code,
+            &ExecutionContext::default(),
+            DEFAULT_TX_GAS_LIMIT,
+        )
+        .await
+        .into_result()?;
+        assert_eq!(vm.stack.pop()?, StackItem::Bytes(vec![0xaa, 0xbb, 0xcc]));
+
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_vm_add() -> Result<()> {
+    async fn test_vm_log() -> Result<()> {
         let mut vm: BytecodeVM<256> = BytecodeVM::new();
-        let code = vec![0x02, 0xaa, 0x03, 0xaa, 0xad];
         let state = Box::new(MemState::new()) as DynState;
-        vm.execute(&state, &code).await?;
-        let res = vm.stack.pop();
-        assert_eq!(res, StackItem::Int(5));
+        let code = asm::assemble(
+            "PushBytes 0xbb\nPushInt 1\nPushByte 2\nPushBytes 0x0102\nLog\nPushByte 0\nPushBool true\nLog",
+        )?;
+        let result = vm
+            .execute(
+                &state,
+                &code,
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await;
+        assert_eq!(result.error, None);
+        assert_eq!(
+            result.logs,
+            vec![
+                Log {
+                    address: None,
+                    topics: vec![vec![1, 0, 0, 0], vec![0xbb]],
+                    data: vec![1, 2],
+                },
+                Log {
+                    address: None,
+                    topics: vec![],
+                    data: vec![1],
+                },
+            ]
+        );
+
+        // logs of a failed execution are dropped
+        let code = asm::assemble("PushByte 0\nPushInt 1\nLog\nRevert")?;
+        let result = vm
+            .execute(
+                &state,
+                &code,
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await;
+        assert_eq!(result.error, Some(VmError::Reverted));
+        assert!(result.logs.is_empty());
+
+        let code = asm::assemble("PushByte 5\nPushInt 1\nLog")?;
+        let result = vm
+            .execute(
+                &state,
+                &code,
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await;
+        assert_eq!(result.error, Some(VmError::TooManyTopics));
+
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_vm_sub() -> Result<()> {
+    async fn test_vm_context() -> Result<()> {
         let mut vm: BytecodeVM<256> = BytecodeVM::new();
-        let code = vec![0x03, 0xaa, 0x02, 0xaa, 0xae];
         let state = Box::new(MemState::new()) as DynState;
-        vm.execute(&state, &code).await?;
-        let res = vm.stack.pop();
-        assert_eq!(res, StackItem::Int(-1));
+        let ctx = ExecutionContext {
+            sender: Some(Address::from_bytes(&[1; 20])),
+            tx_hash: Hash::from_bytes(&[2; 32]),
+            block_height: 7,
+            block_timestamp: 1_000,
+            validator: Some(Address::from_bytes(&[3; 20])),
+            ..Default::default()
+        };
+
+        let code = asm::assemble("Caller\nTxHash\nBlockHeight\nTimestamp\nValidator\nGasLeft")?;
+        vm.execute(&state, &code, &ctx, 100).await.into_result()?;
+        assert_eq!(vm.stack.pop()?, StackItem::U64(100 - 12));
+        assert_eq!(vm.stack.pop()?, StackItem::Bytes(vec![3; 20]));
+        assert_eq!(vm.stack.pop()?, StackItem::U64(1_000));
+        assert_eq!(vm.stack.pop()?, StackItem::U64(7));
+        assert_eq!(vm.stack.pop()?, StackItem::Bytes(vec![2; 32]));
+        assert_eq!(vm.stack.pop()?, StackItem::Bytes(vec![1; 20]));
+
+        let ctx = ExecutionContext::default();
+        let result = vm.execute(&state, &[0xee], &ctx, 100).await;
+        assert_eq!(result.error, Some(VmError::MissingSender));
+        let result = vm.execute(&state, &[0xfc], &ctx, 100).await;
+        assert_eq!(result.error, Some(VmError::MissingValidator));
+
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_vm_mul() -> Result<()> {
+    async fn test_vm_trace() -> Result<()> {
+        let mut vm: BytecodeVM<256> = BytecodeVM::new();
+        let state = Box::new(MemState::new()) as DynState;
+        let recorder = TraceRecorder::new();
+        vm.set_tracer(Some(Box::new(recorder.clone())));
+
+        let code = asm::assemble("PushInt 2\nPushInt 4\nStore\nPushInt 4\nGet\nPushInt 5\nGet")?;
+        let result = vm
+            .execute(&state, &code, &ExecutionContext::default(), 2000)
+            .await;
+        let trace = recorder.take_trace(&result);
+
+        let opcodes: Vec<&str> = trace
+            .steps
+            .iter()
+            .map(|step| step.opcode.as_str())
+            .collect();
+        assert_eq!(
+            opcodes,
+            ["PushInt", "PushInt", "Store", "PushInt", "Get", "PushInt", "Get"]
+        );
+        assert_eq!(trace.steps[2].stack, ["Int(2)", "Int(4)"]);
+        assert_eq!(trace.steps[2].gas_left, 1998);
+        assert_eq!(
+            trace.steps[2].state,
+            [StateAccess::Write {
+                key: vec![4, 0, 0, 0],
+                value: vec![0, 2, 0, 0, 0],
+            }]
+        );
+        assert_eq!(
+            trace.steps[4].state,
+            [StateAccess::Read {
+                key: vec![4, 0, 0, 0],
+                value: Some(vec![0, 2, 0, 0, 0]),
+            }]
+        );
+
+        // the failing read of a missing key is the last step
+        assert_eq!(
+            trace.steps[6].state,
+            [StateAccess::Read {
+                key: vec![5, 0, 0, 0],
+                value: None,
+            }]
+        );
+        assert!(matches!(trace.error, Some(VmError::State(_))));
+        assert_eq!(trace.gas_used, result.gas_used);
+        assert!(trace.to_json()?.contains("\"opcode\": \"Store\""));
+
+        // without a tracer nothing is recorded
+        vm.set_tracer(None);
+        vm.execute(&state, &code, &ExecutionContext::default(), 2000)
+            .await;
+        assert!(recorder.take_trace(&result).steps.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_gas() -> Result<()> {
         let mut vm: BytecodeVM<256> = BytecodeVM::new();
-        let code = vec![0x03, 0xaa, 0x02, 0xaa, 0xba];
+        let code = vec![
+            0xaa, 0x02, 0x00, 0x00, 0x00, 0xaa, 0x04, 0x00, 0x00, 0x00, 0xbc,
+        ];
         let state = Box::new(MemState::new()) as DynState;
-        vm.execute(&state, &code).await?;
-        let res = vm.stack.pop();
-        assert_eq!(res, StackItem::Int(6));
+
+        // the key and the tagged value take up 4 and 5 bytes
+        let gas = Instruction::PushInt.gas_cost() * 2
+            + Instruction::Store.gas_cost()
+            + (4 + 5) * STATE_BYTE_GAS;
+        assert_eq!(
+            vm.execute(&state, &code, &ExecutionContext::default(), gas)
+                .await
+                .into_result()?,
+            gas
+        );
+
+        // one gas short, the store never happens
+        let state = Box::new(MemState::new()) as DynState;
+        let result = vm
+            .execute(&state, &code, &ExecutionContext::default(), gas - 1)
+            .await;
+        assert_eq!(result.error, Some(VmError::OutOfGas));
+        assert_eq!(result.gas_used, gas - 1);
+        assert!(global_storage(&state).get(&[4, 0, 0, 0]).await.is_err());
+
+        // pushing and copying bytes costs more the more bytes there are
+        let gas_used = |bytes: usize| {
+            let code = asm::assemble(&format!("PushBytes 0x{}\nDup", "ab".repeat(bytes))).unwrap();
+            let state = Box::new(MemState::new()) as DynState;
+            let mut vm: BytecodeVM<256> = BytecodeVM::new();
+            async move {
+                vm.execute(&state, &code, &ExecutionContext::default(), gas)
+                    .await
+                    .gas_used
+            }
+        };
+        assert_eq!(gas_used(101).await - gas_used(1).await, 2 * 100 * BYTE_GAS);
+
+        // storing and reading a value costs more the larger it is
+        let gas_used = |bytes: usize| {
+            let code = asm::assemble(&format!(
+                "PushBytes 0x{}\nPushInt 1\nStore\nPushInt 1\nGet",
+                "ab".repeat(bytes)
+            ))
+            .unwrap();
+            let state = Box::new(MemState::new()) as DynState;
+            let mut vm: BytecodeVM<256> = BytecodeVM::new();
+            async move {
+                vm.execute(&state, &code, &ExecutionContext::default(), 1_000_000)
+                    .await
+                    .gas_used
+            }
+        };
+        // pushed, stored, read and pushed again by Get
+        let per_byte = BYTE_GAS * 2 + STATE_BYTE_GAS * 2;
+        assert_eq!(gas_used(101).await - gas_used(1).await, 100 * per_byte);
+
         Ok(())
     }
 
+    // sum of 1..=n, the sum is kept in the state at key 0 and the counter on the stack
+    fn sum_code(n: i32) -> Vec<u8> {
+        asm::assemble(&format!(
+            "
+            .const SUM 0
+            PushInt 0
+            PushInt SUM
+            Store
+            PushInt {n}     ; i = n
+            loop: Dup
+            PushInt SUM
+            Get
+            Add
+            PushInt SUM
+            Store           ; sum = sum + i
+            PushInt 1
+            Swap
+            Sub             ; i = i - 1
+            Dup
+            PushInt 0
+            Lt              ; 0 < i
+            PushInt loop
+            JumpIf
+            Pop
+            "
+        ))
+        .unwrap()
+    }
+
     #[tokio::test]
-    async fn test_vm_div() -> Result<()> {
+    async fn test_vm_loop() -> Result<()> {
+        for (n, sum) in [(1, 1i32), (10, 55), (50, 1275)] {
+            let mut vm: BytecodeVM<256> = BytecodeVM::new();
+            let state = Box::new(MemState::new()) as DynState;
+            vm.execute(
+                &state,
+                &sum_code(n),
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await
+            .into_result()?;
+
+            let value = global_storage(&state).get(&[0, 0, 0, 0]).await?;
+            assert_eq!(StackItem::decode(&value), Some(StackItem::Int(sum)));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_loop_out_of_gas() -> Result<()> {
         let mut vm: BytecodeVM<256> = BytecodeVM::new();
-        let code = vec![0x02, 0xaa, 0x02, 0xaa, 0xbb];
         let state = Box::new(MemState::new()) as DynState;
-        vm.execute(&state, &code).await?;
-        let res = vm.stack.pop();
-        assert_eq!(res, StackItem::Int(1));
+        let result = vm
+            .execute(&state, &sum_code(100), &ExecutionContext::default(), 10_000)
+            .await;
+        assert_eq!(result.error, Some(VmError::OutOfGas));
         Ok(())
     }
 
     #[tokio::test]
-    async fn test_vm_store() -> Result<()> {
+    async fn test_vm_comparison_and_logic() -> Result<()> {
+        let push_3 = [0xaa, 0x03, 0x00, 0x00, 0x00];
+        let push_2 = [0xaa, 0x02, 0x00, 0x00, 0x00];
+        let push_true = [0xab, 0x01];
+        let push_false = [0xab, 0x00];
+
+        let cases: Vec<(Vec<u8>, StackItem)> = vec![
+            (
+                [&push_3[..], &push_3, &[0xbd]].concat(),
+                StackItem::Bool(true),
+            ),
+            (
+                [&push_3[..], &push_true, &[0xbd]].concat(),
+                StackItem::Bool(false),
+            ),
+            (
+                [&push_3[..], &push_2, &[0xbe]].concat(),
+                StackItem::Bool(true),
+            ),
+            (
+                [&push_3[..], &push_2, &[0xbf]].concat(),
+                StackItem::Bool(false),
+            ),
+            ([&push_false[..], &[0xca]].concat(), StackItem::Bool(true)),
+            (
+                [&push_true[..], &push_false, &[0xcb]].concat(),
+                StackItem::Bool(false),
+            ),
+            (
+                [&push_true[..], &push_false, &[0xcc]].concat(),
+                StackItem::Bool(true),
+            ),
+            ([&push_3[..], &push_2, &[0xda]].concat(), StackItem::Int(3)),
+            ([&push_3[..], &push_2, &[0xdb]].concat(), StackItem::Int(3)),
+        ];
+
+        for (code, expected) in cases {
+            assert_eq!(run(&code).await?, expected, "code: {:x?}", code);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_halt_and_revert() -> Result<()> {
+        // the push after the halt never runs
+        let code = [
+            0xaa, 0x01, 0x00, 0x00, 0x00, 0xdc, 0xaa, 0x02, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(run(&code).await?, StackItem::Int(1));
+
+        let state = Box::new(MemState::new()) as DynState;
         let mut vm: BytecodeVM<256> = BytecodeVM::new();
-        let code = vec![0x02, 0xaa, 0x04, 0xaa, 0xbc];
+        let code = [0xaa, 0x01, 0x00, 0x00, 0x00, 0xdd];
+        let result = vm
+            .execute(
+                &state,
+                &code,
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await;
+        assert_eq!(result.error, Some(VmError::Reverted));
+        assert_eq!(result.gas_used, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_invalid_jump() -> Result<()> {
         let state = Box::new(MemState::new()) as DynState;
-        vm.execute(&state, &code).await?;
-        let v = state.get(&[4, 0, 0, 0]).await?;
-        assert_eq!(v, vec![2, 0, 0, 0]);
 
-        vm.execute(&state, &[0x04, 0xaa, 0xaf]).await?;
+        // offset 1 is the immediate of the push
+        for target in [1i32, 100] {
+            let mut vm: BytecodeVM<256> = BytecodeVM::new();
+            let code = [[0xaa].as_slice(), &target.to_le_bytes(), &[0xcd]].concat();
+            let result = vm
+                .execute(
+                    &state,
+                    &code,
+                    &ExecutionContext::default(),
+                    DEFAULT_TX_GAS_LIMIT,
+                )
+                .await;
+            assert_eq!(result.error, Some(VmError::InvalidJump(target)));
+        }
 
-        let res = vm.stack.pop();
-        assert_eq!(res, StackItem::Int(2));
+        // jumping back to the start forever
+        let mut vm: BytecodeVM<256> = BytecodeVM::new();
+        let code = [0xaa, 0x00, 0x00, 0x00, 0x00, 0xcd];
+        let result = vm
+            .execute(
+                &state,
+                &code,
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await;
+        assert_eq!(result.error, Some(VmError::OutOfGas));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_invalid_code() -> Result<()> {
+        let state = Box::new(MemState::new()) as DynState;
+        let mut vm: BytecodeVM<256> = BytecodeVM::new();
+
+        // the store is never executed because the code is rejected before it runs
+        let code = [
+            0xaa, 0x02, 0x00, 0x00, 0x00, 0xaa, 0x04, 0x00, 0x00, 0x00, 0xbc, 0x07,
+        ];
+        let result = vm
+            .execute(
+                &state,
+                &code,
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await;
+        assert_eq!(result.error, Some(VmError::InvalidInstruction(11)));
+        assert!(global_storage(&state).get(&[4, 0, 0, 0]).await.is_err());
+
+        let result = vm
+            .execute(
+                &state,
+                &[0xaa, 0x01],
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await;
+        assert_eq!(result.error, Some(VmError::InvalidImmediate(0)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_vm_stack_errors() -> Result<()> {
+        let state = Box::new(MemState::new()) as DynState;
+
+        let mut vm: BytecodeVM<2> = BytecodeVM::new();
+        let code = asm::assemble("PushInt 1\nAdd")?;
+        let result = vm
+            .execute(
+                &state,
+                &code,
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await;
+        assert_eq!(result.error, Some(VmError::StackUnderflow));
+
+        let code = asm::assemble("PushInt 1\nDup\nDup")?;
+        let result = vm
+            .execute(
+                &state,
+                &code,
+                &ExecutionContext::default(),
+                DEFAULT_TX_GAS_LIMIT,
+            )
+            .await;
+        assert_eq!(result.error, Some(VmError::StackOverflow));
 
         Ok(())
     }
@@ -1,20 +1,71 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use super::u256::U256;
+use crate::core::vm::VmError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum StackItem {
     Int(i32),
+    Int64(i64),
+    U64(u64),
+    U256(U256),
     Bool(bool),
     Byte(u8),
-    Bytes([u8; 64]),
+    Bytes(Vec<u8>),
 }
 
 impl StackItem {
+    // The raw bytes of the value, used as key in the state
     pub fn to_bytes(&self) -> Vec<u8> {
         match self {
             Self::Int(i) => i.to_le_bytes().to_vec(),
+            Self::Int64(i) => i.to_le_bytes().to_vec(),
+            Self::U64(i) => i.to_le_bytes().to_vec(),
+            Self::U256(i) => i.to_le_bytes().to_vec(),
             Self::Bool(b) => vec![*b as u8],
             Self::Byte(b) => vec![*b],
-            Self::Bytes(b) => b.to_vec(),
+            Self::Bytes(b) => b.clone(),
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Self::Int(_) => 0,
+            Self::Int64(_) => 1,
+            Self::U64(_) => 2,
+            Self::U256(_) => 3,
+            Self::Bool(_) => 4,
+            Self::Byte(_) => 5,
+            Self::Bytes(_) => 6,
         }
     }
+
+    // Values in the state are prefixed with their type so that Get returns the same item that was stored
+    pub fn encode(&self) -> Vec<u8> {
+        [vec![self.tag()], self.to_bytes()].concat()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (tag, value) = bytes.split_first()?;
+
+        let item = match tag {
+            0 => Self::Int(i32::from_le_bytes(value.try_into().ok()?)),
+            1 => Self::Int64(i64::from_le_bytes(value.try_into().ok()?)),
+            2 => Self::U64(u64::from_le_bytes(value.try_into().ok()?)),
+            3 => Self::U256(U256::from_le_bytes(value.try_into().ok()?)),
+            4 => match value {
+                [0] => Self::Bool(false),
+                [1] => Self::Bool(true),
+                _ => return None,
+            },
+            5 => match value {
+                [b] => Self::Byte(*b),
+                _ => return None,
+            },
+            6 => Self::Bytes(value.to_vec()),
+            _ => return None,
+        };
+
+        Some(item)
+    }
 }
 
 impl Default for StackItem {
@@ -23,46 +74,152 @@ impl Default for StackItem {
     }
 }
 
+// A stack of at most N items that lives inside a fixed array, the top is at data[len - 1]
+// so pushing and popping never moves the other items
 #[derive(Debug, Clone)]
 pub struct Stack<const N: usize> {
     data: [StackItem; N],
-    sp: usize,
+    len: usize,
 }
 
 impl<const N: usize> Stack<N> {
     pub fn new() -> Self {
         Self {
-            data: [StackItem::default(); N],
-            sp: 0,
+            data: std::array::from_fn(|_| StackItem::default()),
+            len: 0,
         }
     }
 
-    pub fn pop(&mut self) -> StackItem {
-        let val = self.data[0];
+    pub fn pop(&mut self) -> Result<StackItem, VmError> {
+        if self.len == 0 {
+            return Err(VmError::StackUnderflow);
+        }
 
-        let mut data: [StackItem; N] = self.data;
-        // Copy all other elements from old array to new array starting from first element
+        self.len -= 1;
+        Ok(std::mem::take(&mut self.data[self.len]))
+    }
 
-        data[..N - 1].copy_from_slice(self.data[1..].as_ref());
+    pub fn push(&mut self, item: StackItem) -> Result<(), VmError> {
+        if self.len == N {
+            return Err(VmError::StackOverflow);
+        }
 
-        self.data = data;
+        self.data[self.len] = item;
+        self.len += 1;
+        Ok(())
+    }
 
-        // Decrease Stack Pointer by 1 or set it to 0 if it is already 0
-        self.sp = self.sp.saturating_sub(1);
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
 
-        val
+    // the items from the bottom to the top
+    pub fn items(&self) -> &[StackItem] {
+        &self.data[..self.len]
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
 
-    pub fn push_front(&mut self, item: StackItem) {
-        let mut data = [StackItem::default(); N];
+    #[test]
+    fn test_push_pop() -> Result<(), VmError> {
+        let mut stack: Stack<2> = Stack::new();
+        stack.push(StackItem::Int(1))?;
+        stack.push(StackItem::Bool(true))?;
+        assert_eq!(stack.push(StackItem::Int(3)), Err(VmError::StackOverflow));
 
-        // Set item to first element of Array
-        // Copy all other elements from old array to new array starting from second element
-        data[1..].copy_from_slice(self.data[..N - 1].as_ref());
-        data[0] = item;
+        assert_eq!(stack.pop()?, StackItem::Bool(true));
+        assert_eq!(stack.pop()?, StackItem::Int(1));
+        assert_eq!(stack.pop(), Err(VmError::StackUnderflow));
 
-        self.data = data;
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let items = [
+            StackItem::Int(-1),
+            StackItem::Int64(i64::MIN),
+            StackItem::U64(u64::MAX),
+            StackItem::U256(U256::MAX),
+            StackItem::Bool(true),
+            StackItem::Byte(7),
+            StackItem::Bytes(vec![]),
+            StackItem::Bytes(vec![1, 2, 3]),
+        ];
+        for item in items {
+            assert_eq!(StackItem::decode(&item.encode()), Some(item));
+        }
+
+        assert_eq!(StackItem::decode(&[]), None);
+        assert_eq!(StackItem::decode(&[0, 1, 2]), None);
+        assert_eq!(StackItem::decode(&[4, 2]), None);
+        assert_eq!(StackItem::decode(&[9]), None);
+    }
+
+    // The previous stack, it shifted the whole array on every push and pop
+    struct ShiftingStack<const N: usize> {
+        data: [StackItem; N],
+    }
+
+    impl<const N: usize> ShiftingStack<N> {
+        fn pop(&mut self) -> StackItem {
+            let val = self.data[0].clone();
+            let mut data: [StackItem; N] = self.data.clone();
+            data[..N - 1].clone_from_slice(self.data[1..].as_ref());
+            self.data = data;
+            val
+        }
+
+        fn push_front(&mut self, item: StackItem) {
+            let mut data: [StackItem; N] = std::array::from_fn(|_| StackItem::default());
+            data[1..].clone_from_slice(self.data[..N - 1].as_ref());
+            data[0] = item;
+            self.data = data;
+        }
+    }
+
+    // cargo test --release bench_stack -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_stack() {
+        const ROUNDS: usize = 100_000;
+        const DEPTH: usize = 16;
+
+        let start = Instant::now();
+        let mut stack: Stack<128> = Stack::new();
+        for i in 0..ROUNDS {
+            for _ in 0..DEPTH {
+                stack.push(StackItem::Int(i as i32)).unwrap();
+            }
+            for _ in 0..DEPTH {
+                std::hint::black_box(stack.pop().unwrap());
+            }
+        }
+        let stack_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut shifting: ShiftingStack<128> = ShiftingStack {
+            data: std::array::from_fn(|_| StackItem::default()),
+        };
+        for i in 0..ROUNDS {
+            for _ in 0..DEPTH {
+                shifting.push_front(StackItem::Int(i as i32));
+            }
+            for _ in 0..DEPTH {
+                std::hint::black_box(shifting.pop());
+            }
+        }
+        let shifting_time = start.elapsed();
 
-        self.sp += 1;
+        println!(
+            "{} push/pop pairs: stack {:?}, shifting stack {:?}",
+            ROUNDS * DEPTH,
+            stack_time,
+            shifting_time
+        );
     }
 }
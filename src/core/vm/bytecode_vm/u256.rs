@@ -0,0 +1,198 @@
+use std::{cmp::Ordering, fmt};
+
+// 256 bit unsigned integer, the limbs are little endian (limbs[0] is the least significant)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: Self = Self([0; 4]);
+    pub const MAX: Self = Self([u64::MAX; 4]);
+
+    pub fn from_le_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let mut chunk = [0u8; 8];
+            chunk.copy_from_slice(&bytes[i * 8..(i + 1) * 8]);
+            *limb = u64::from_le_bytes(chunk);
+        }
+        Self(limbs)
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in self.0.iter().enumerate() {
+            bytes[i * 8..(i + 1) * 8].copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *limb = sum;
+            carry = c1 || c2;
+        }
+        (!carry).then_some(Self(result))
+    }
+
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        let mut result = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in result.iter_mut().enumerate() {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *limb = diff;
+            borrow = b1 || b2;
+        }
+        (!borrow).then_some(Self(result))
+    }
+
+    pub fn checked_mul(self, other: Self) -> Option<Self> {
+        // schoolbook multiplication into 8 limbs, everything above the 4th limb is an overflow
+        let mut result = [0u64; 8];
+        for i in 0..4 {
+            let mut carry = 0u128;
+            for j in 0..4 {
+                let product =
+                    self.0[i] as u128 * other.0[j] as u128 + result[i + j] as u128 + carry;
+                result[i + j] = product as u64;
+                carry = product >> 64;
+            }
+            result[i + 4] = carry as u64;
+        }
+
+        if result[4..].iter().any(|limb| *limb != 0) {
+            return None;
+        }
+        Some(Self([result[0], result[1], result[2], result[3]]))
+    }
+
+    // Binary long division, None if `other` is zero
+    pub fn checked_div(self, other: Self) -> Option<Self> {
+        if other.is_zero() {
+            return None;
+        }
+
+        let mut quotient = Self::ZERO;
+        let mut remainder = Self::ZERO;
+        for bit in (0..256).rev() {
+            remainder = remainder.shl1();
+            if self.bit(bit) {
+                remainder.0[0] |= 1;
+            }
+            if remainder >= other {
+                remainder = remainder.checked_sub(other)?;
+                quotient.0[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        Some(quotient)
+    }
+
+    fn bit(&self, bit: usize) -> bool {
+        self.0[bit / 64] & (1 << (bit % 64)) != 0
+    }
+
+    fn shl1(self) -> Self {
+        let mut result = [0u64; 4];
+        for (i, limb) in result.iter_mut().enumerate() {
+            *limb = self.0[i] << 1;
+            if i > 0 {
+                *limb |= self.0[i - 1] >> 63;
+            }
+        }
+        Self(result)
+    }
+}
+
+impl From<u64> for U256 {
+    fn from(value: u64) -> Self {
+        Self([value, 0, 0, 0])
+    }
+}
+
+impl TryFrom<U256> for u64 {
+    type Error = ();
+
+    fn try_from(value: U256) -> Result<Self, Self::Error> {
+        match value.0 {
+            [low, 0, 0, 0] => Ok(low),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Hex without leading zeros, e.g. 0x1f
+impl fmt::Display for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex: String = self
+            .0
+            .iter()
+            .rev()
+            .map(|limb| format!("{limb:016x}"))
+            .collect();
+        let hex = hex.trim_start_matches('0');
+        write!(f, "0x{}", if hex.is_empty() { "0" } else { hex })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_sub() {
+        let a = U256::from(u64::MAX);
+        let b = a.checked_add(U256::from(1)).unwrap();
+        assert_eq!(b, U256([0, 1, 0, 0]));
+        assert_eq!(b.checked_sub(U256::from(1)), Some(a));
+
+        assert_eq!(U256::MAX.checked_add(U256::from(1)), None);
+        assert_eq!(U256::ZERO.checked_sub(U256::from(1)), None);
+    }
+
+    #[test]
+    fn test_mul_div() {
+        let a = U256([0, 0, 1, 0]);
+        let b = U256([0, 1, 0, 0]);
+        assert_eq!(a.checked_mul(b), Some(U256([0, 0, 0, 1])));
+        assert_eq!(a.checked_mul(a), None);
+
+        let c = U256([7, 3, 0, 9]);
+        let product = c.checked_mul(U256::from(1000)).unwrap();
+        assert_eq!(product.checked_div(U256::from(1000)), Some(c));
+        assert_eq!(
+            U256::from(7).checked_div(U256::from(2)),
+            Some(U256::from(3))
+        );
+        assert_eq!(c.checked_div(U256::ZERO), None);
+    }
+
+    #[test]
+    fn test_ord_bytes_display() {
+        assert!(U256([0, 0, 0, 1]) > U256([u64::MAX, u64::MAX, u64::MAX, 0]));
+
+        let value = U256([1, 2, 3, 4]);
+        assert_eq!(U256::from_le_bytes(value.to_le_bytes()), value);
+        assert_eq!(U256::from(31).to_string(), "0x1f");
+        assert_eq!(U256::ZERO.to_string(), "0x0");
+    }
+}
@@ -1,18 +1,139 @@
 // VM should be a trait and should be modular so that the user can put in their own VM.
 // This will allow for more flexibility and will allow for more VMs to be added in the future.
 
-use anyhow::Result;
 use dyn_clone::DynClone;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
+use thiserror::Error;
 
 pub mod bytecode_vm;
+pub mod trace;
+pub mod wasm_vm;
 
-use super::state::DynState;
+use self::trace::DynTracer;
+use super::{state::DynState, Address, Hash};
 pub type DynVM = Box<dyn VM>;
 
+// Gas limit of a transaction that doesn't set one
+pub const DEFAULT_TX_GAS_LIMIT: u64 = 100_000;
+
 #[async_trait::async_trait]
 pub trait VM: Debug + DynClone + Send + Sync {
-    async fn execute(&mut self, state: &DynState, code: &[u8]) -> Result<()>;
+    // Executing never fails with an error that is not part of the result,
+    // every node has to come to the same result for the same code and context.
+    // `state` is the global state, the code of a contract works on the storage of `ctx.address`
+    async fn execute(
+        &mut self,
+        state: &DynState,
+        code: &[u8],
+        ctx: &ExecutionContext,
+        gas_limit: u64,
+    ) -> ExecutionResult;
+
+    // Report every step of the following executions to `tracer`, None stops tracing.
+    // VMs that can't be traced ignore it
+    fn set_tracer(&mut self, _tracer: Option<DynTracer>) {}
 }
 
 dyn_clone::clone_trait_object!(VM);
+
+// Maximum depth of nested contract calls, the transaction itself runs at depth 0.
+// Every call is a nested future, so the depth has to stay low enough for the stack of a tokio worker
+pub const MAX_CALL_DEPTH: usize = 32;
+
+// What the code can know about the transaction and the block it runs in.
+// Everything in here is part of the block, so it is the same on every node
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionContext {
+    // the account that signed the transaction or the contract that made the call
+    pub sender: Option<Address>,
+    // the contract whose code runs, None for execute transactions which run on the global state
+    pub address: Option<Address>,
+    pub depth: usize,
+    pub tx_hash: Hash,
+    pub block_height: u32,
+    // the timestamp of the block header in nanoseconds since the unix epoch, saturated to u64
+    pub block_timestamp: u64,
+    // address of the validator that created the block
+    pub validator: Option<Address>,
+    // input data of a contract call, empty for everything else
+    pub input: Vec<u8>,
+}
+
+// Deterministic reasons for a transaction to fail, they end up in the receipt of the transaction
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Error)]
+pub enum VmError {
+    #[error("out of gas")]
+    OutOfGas,
+    #[error("invalid instruction at offset {0}")]
+    InvalidInstruction(usize),
+    #[error("invalid immediate of the instruction at offset {0}")]
+    InvalidImmediate(usize),
+    #[error("invalid jump destination {0}")]
+    InvalidJump(i32),
+    #[error("stack overflow")]
+    StackOverflow,
+    #[error("stack underflow")]
+    StackUnderflow,
+    #[error("type mismatch")]
+    TypeMismatch,
+    #[error("arithmetic overflow")]
+    Overflow,
+    #[error("division by zero")]
+    DivisionByZero,
+    #[error("execution reverted")]
+    Reverted,
+    #[error("a log has at most {MAX_LOG_TOPICS} topics")]
+    TooManyTopics,
+    #[error("transaction has no sender")]
+    MissingSender,
+    #[error("block has no validator")]
+    MissingValidator,
+    #[error("no contract at address {0}")]
+    NoContract(Address),
+    #[error("state error: {0}")]
+    State(String),
+    #[error("invalid wasm module: {0}")]
+    InvalidModule(String),
+    #[error("wasm trap: {0}")]
+    Trap(String),
+}
+
+// An event emitted by the code, `address` is the contract that emitted it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Log {
+    pub address: Option<Address>,
+    pub topics: Vec<Vec<u8>>,
+    pub data: Vec<u8>,
+}
+
+// Maximum number of topics of a log
+pub const MAX_LOG_TOPICS: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecutionResult {
+    pub gas_used: u64,
+    pub error: Option<VmError>,
+    // logs of a failed execution are dropped together with its state changes
+    pub logs: Vec<Log>,
+    // data the code returned to its caller
+    pub return_data: Vec<u8>,
+}
+
+impl ExecutionResult {
+    pub fn failed(gas_used: u64, error: VmError) -> Self {
+        Self {
+            gas_used,
+            error: Some(error),
+            logs: vec![],
+            return_data: vec![],
+        }
+    }
+
+    pub fn into_result(self) -> Result<u64, VmError> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(self.gas_used),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+/*
+    Tracing of executions. A VM with a tracer calls it after every instruction with a step,
+    the TraceRecorder collects these steps into a trace that can be exported as json.
+    Only the BytecodeVM reports steps, other VMs ignore their tracer.
+*/
+
+use std::sync::{Arc, Mutex};
+
+use dyn_clone::DynClone;
+use serde::Serialize;
+use std::fmt::Debug;
+
+use super::{ExecutionResult, VmError};
+
+pub type DynTracer = Box<dyn Tracer>;
+
+pub trait Tracer: Debug + DynClone + Send + Sync {
+    // called after every executed instruction, also the one that failed
+    fn step(&mut self, step: &Step);
+}
+
+dyn_clone::clone_trait_object!(Tracer);
+
+// A read or write of the state done by an instruction, a read of a missing key has no value
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum StateAccess {
+    Read {
+        key: Vec<u8>,
+        value: Option<Vec<u8>>,
+    },
+    Write {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Step {
+    // depth of the call the instruction runs in, the steps of a nested call come before the Call step
+    pub depth: usize,
+    // index of the instruction in the decoded code and the offset of its opcode in the code
+    pub ip: usize,
+    pub offset: usize,
+    pub opcode: String,
+    // the stack before the instruction ran, the top is the last item
+    pub stack: Vec<String>,
+    // the gas left before the instruction ran and what the instruction costs
+    pub gas_left: u64,
+    pub gas_cost: u64,
+    pub state: Vec<StateAccess>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Trace {
+    pub steps: Vec<Step>,
+    pub gas_used: u64,
+    pub error: Option<VmError>,
+}
+
+impl Trace {
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+// Collects the steps of an execution, the clones share the steps
+// so the recorder can be handed to a vm and read afterwards
+#[derive(Debug, Clone, Default)]
+pub struct TraceRecorder {
+    steps: Arc<Mutex<Vec<Step>>>,
+}
+
+impl TraceRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The trace of the execution that ended with `result`, the recorder is empty afterwards
+    pub fn take_trace(&self, result: &ExecutionResult) -> Trace {
+        Trace {
+            steps: std::mem::take(&mut *self.steps.lock().unwrap()),
+            gas_used: result.gas_used,
+            error: result.error.clone(),
+        }
+    }
+}
+
+impl Tracer for TraceRecorder {
+    fn step(&mut self, step: &Step) {
+        self.steps.lock().unwrap().push(step.clone());
+    }
+}
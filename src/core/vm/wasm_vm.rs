@@ -0,0 +1,623 @@
+/*
+    VM that runs WebAssembly modules with the wasmi interpreter.
+
+    A module exports its linear memory as `memory` and a function `main` without params and results,
+    which is called for every execution. It can import these functions from the `env` module:
+
+        get(key_ptr, key_len, out_ptr, out_cap) -> i32   writes at most out_cap bytes of the value of the key
+                                                          to out_ptr, returns the length of the value
+                                                          or -1 if the key doesn't exist
+        set(key_ptr, key_len, value_ptr, value_len)
+        delete(key_ptr, key_len)
+        input_len() -> i32                                length of the input data of the call
+        input(out_ptr)                                    writes the input data to out_ptr
+        sender(out_ptr) -> i32                            writes the 20 byte address of the sender to out_ptr,
+                                                          returns 0 or -1 if the transaction has no sender
+        validator(out_ptr) -> i32                         the same for the validator of the block
+        tx_hash(out_ptr)                                  writes the 32 byte hash of the transaction to out_ptr
+        block_height() -> i64
+        block_timestamp() -> i64
+        gas_left() -> i64
+        revert()                                          stops the execution, the transaction fails
+
+    get, set and delete work on the storage of the contract, or on the global storage for the code of
+    Execute transactions, see contract.rs. Neither can reach the accounts or the code of the contracts.
+
+    Every wasm instruction costs 1 gas (the fuel of wasmi), accessing the state costs as much as in the BytecodeVM:
+    a flat cost plus BYTE_GAS for every byte of a key and of the value read or written.
+    To stay deterministic, floats are rejected when the module is compiled (NaN bits differ between platforms)
+    and there is nothing like the time to import. The memory of a module is limited to MAX_MEMORY_BYTES.
+*/
+
+use std::fmt;
+
+use wasmi::{
+    core::{HostError, TrapCode},
+    errors::MemoryError,
+    Caller, Engine, Error, Extern, Linker, Memory, Module, ResumableCall, Store, StoreLimits,
+    StoreLimitsBuilder, Val,
+};
+
+use super::{ExecutionContext, ExecutionResult, VmError, VM};
+use crate::core::{execution_storage, state::DynState};
+
+// 16 pages of 64 KiB
+pub const MAX_MEMORY_BYTES: usize = 1 << 20;
+
+const GET_GAS: u64 = 100;
+const SET_GAS: u64 = 1000;
+// charged on top for every byte of a key or value that is read or written
+const BYTE_GAS: u64 = 10;
+
+struct HostState {
+    ctx: ExecutionContext,
+    limits: StoreLimits,
+}
+
+// Raised by the host functions to leave the interpreter. State accesses are async,
+// so they are done by `call_main` which then resumes the execution with their result
+#[derive(Debug, Clone)]
+enum HostCall {
+    Get {
+        key: Vec<u8>,
+        out_ptr: usize,
+        out_cap: usize,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    Revert,
+    OutOfGas,
+}
+
+impl fmt::Display for HostCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl HostError for HostCall {}
+
+#[derive(Debug, Clone)]
+pub struct WasmVM {
+    engine: Engine,
+}
+
+impl WasmVM {
+    pub fn new() -> Self {
+        let mut config = wasmi::Config::default();
+        config.consume_fuel(true).floats(false);
+
+        Self {
+            engine: Engine::new(&config),
+        }
+    }
+
+    fn linker(&self) -> Result<Linker<HostState>, Error> {
+        let mut linker = Linker::new(&self.engine);
+
+        linker.func_wrap(
+            "env",
+            "get",
+            |mut caller: Caller<'_, HostState>,
+             key_ptr: i32,
+             key_len: i32,
+             out_ptr: i32,
+             out_cap: i32|
+             -> Result<i32, Error> {
+                charge(&mut caller, GET_GAS + byte_gas(key_len))?;
+                Err(Error::host(HostCall::Get {
+                    key: read(&caller, key_ptr, key_len)?,
+                    out_ptr: out_ptr as u32 as usize,
+                    out_cap: out_cap as u32 as usize,
+                }))
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "set",
+            |mut caller: Caller<'_, HostState>,
+             key_ptr: i32,
+             key_len: i32,
+             value_ptr: i32,
+             value_len: i32|
+             -> Result<(), Error> {
+                charge(
+                    &mut caller,
+                    SET_GAS + byte_gas(key_len) + byte_gas(value_len),
+                )?;
+                Err(Error::host(HostCall::Set {
+                    key: read(&caller, key_ptr, key_len)?,
+                    value: read(&caller, value_ptr, value_len)?,
+                }))
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "delete",
+            |mut caller: Caller<'_, HostState>, key_ptr: i32, key_len: i32| -> Result<(), Error> {
+                charge(&mut caller, SET_GAS + byte_gas(key_len))?;
+                Err(Error::host(HostCall::Delete {
+                    key: read(&caller, key_ptr, key_len)?,
+                }))
+            },
+        )?;
+        linker.func_wrap("env", "input_len", |caller: Caller<'_, HostState>| {
+            caller.data().ctx.input.len() as i32
+        })?;
+        linker.func_wrap(
+            "env",
+            "input",
+            |mut caller: Caller<'_, HostState>, out_ptr: i32| -> Result<(), Error> {
+                let input = caller.data().ctx.input.clone();
+                write(&mut caller, out_ptr, &input)
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "sender",
+            |mut caller: Caller<'_, HostState>, out_ptr: i32| -> Result<i32, Error> {
+                match caller.data().ctx.sender {
+                    Some(sender) => write(&mut caller, out_ptr, sender.as_bytes()).map(|_| 0),
+                    None => Ok(-1),
+                }
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "validator",
+            |mut caller: Caller<'_, HostState>, out_ptr: i32| -> Result<i32, Error> {
+                match caller.data().ctx.validator {
+                    Some(validator) => write(&mut caller, out_ptr, validator.as_bytes()).map(|_| 0),
+                    None => Ok(-1),
+                }
+            },
+        )?;
+        linker.func_wrap(
+            "env",
+            "tx_hash",
+            |mut caller: Caller<'_, HostState>, out_ptr: i32| -> Result<(), Error> {
+                let hash = caller.data().ctx.tx_hash;
+                write(&mut caller, out_ptr, hash.as_bytes())
+            },
+        )?;
+        linker.func_wrap("env", "block_height", |caller: Caller<'_, HostState>| {
+            caller.data().ctx.block_height as i64
+        })?;
+        linker.func_wrap("env", "block_timestamp", |caller: Caller<'_, HostState>| {
+            caller.data().ctx.block_timestamp as i64
+        })?;
+        linker.func_wrap(
+            "env",
+            "gas_left",
+            |caller: Caller<'_, HostState>| -> Result<i64, Error> { Ok(caller.get_fuel()? as i64) },
+        )?;
+        linker.func_wrap("env", "revert", || -> Result<(), Error> {
+            Err(Error::host(HostCall::Revert))
+        })?;
+
+        Ok(linker)
+    }
+
+    async fn run(
+        &self,
+        state: &DynState,
+        code: &[u8],
+        ctx: &ExecutionContext,
+        gas_limit: u64,
+    ) -> (u64, Result<(), VmError>) {
+        let module = match Module::new(&self.engine, code) {
+            Ok(module) => module,
+            Err(err) => return (0, Err(VmError::InvalidModule(err.to_string()))),
+        };
+
+        let host = HostState {
+            ctx: ctx.clone(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(MAX_MEMORY_BYTES)
+                .build(),
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(gas_limit).expect("fuel metering is enabled");
+
+        let storage = execution_storage(state, ctx.address.as_ref());
+        let result = self.call_main(&mut store, &module, &storage).await;
+        match result {
+            // running out of gas uses up the whole limit
+            Err(VmError::OutOfGas) => (gas_limit, result),
+            _ => (gas_limit - store.get_fuel().unwrap_or(0), result),
+        }
+    }
+
+    async fn call_main(
+        &self,
+        store: &mut Store<HostState>,
+        module: &Module,
+        state: &DynState,
+    ) -> Result<(), VmError> {
+        let linker = self
+            .linker()
+            .map_err(|err| VmError::InvalidModule(err.to_string()))?;
+        // a start function would run before we could handle its state accesses
+        let instance = linker
+            .instantiate(&mut *store, module)
+            .and_then(|pre| Ok(pre.ensure_no_start(&mut *store)?))
+            .map_err(|err| VmError::InvalidModule(err.to_string()))?;
+        let main = instance
+            .get_func(&*store, "main")
+            .ok_or_else(|| VmError::InvalidModule("no main function".into()))?;
+
+        let mut call = main
+            .call_resumable(&mut *store, &[], &mut [])
+            .map_err(vm_error)?;
+
+        while let ResumableCall::Resumable(invocation) = call {
+            let Some(host_call) = invocation.host_error().downcast_ref::<HostCall>().cloned()
+            else {
+                return Err(VmError::Trap(invocation.host_error().to_string()));
+            };
+
+            let results = match host_call {
+                HostCall::Get {
+                    key,
+                    out_ptr,
+                    out_cap,
+                } => {
+                    let len = match state.get(&key).await {
+                        Ok(value) => {
+                            let memory = instance
+                                .get_memory(&*store, "memory")
+                                .ok_or_else(|| VmError::InvalidModule("no memory".into()))?;
+                            let written = &value[..value.len().min(out_cap)];
+                            // the value is only known now, its bytes are charged before they are copied
+                            let gas = written.len() as u64 * BYTE_GAS;
+                            let fuel = store.get_fuel().unwrap_or(0);
+                            if fuel < gas {
+                                return Err(VmError::OutOfGas);
+                            }
+                            store
+                                .set_fuel(fuel - gas)
+                                .map_err(|err| VmError::Trap(err.to_string()))?;
+                            memory
+                                .write(&mut *store, out_ptr, written)
+                                .map_err(|err| VmError::Trap(err.to_string()))?;
+                            value.len() as i32
+                        }
+                        Err(_) => -1,
+                    };
+                    vec![Val::I32(len)]
+                }
+                HostCall::Set { key, value } => {
+                    state
+                        .set(&key, &value)
+                        .await
+                        .map_err(|err| VmError::State(err.to_string()))?;
+                    vec![]
+                }
+                HostCall::Delete { key } => {
+                    state
+                        .delete(&key)
+                        .await
+                        .map_err(|err| VmError::State(err.to_string()))?;
+                    vec![]
+                }
+                HostCall::Revert => return Err(VmError::Reverted),
+                HostCall::OutOfGas => return Err(VmError::OutOfGas),
+            };
+
+            call = invocation
+                .resume(&mut *store, &results, &mut [])
+                .map_err(vm_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn memory(caller: &Caller<'_, HostState>) -> Result<Memory, Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Error::new("no memory"))
+}
+
+fn read(caller: &Caller<'_, HostState>, ptr: i32, len: i32) -> Result<Vec<u8>, Error> {
+    // the contract chooses the length, nothing is allocated before it's checked against the memory
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    let bytes = memory(caller)?
+        .data(caller)
+        .get(ptr..ptr.saturating_add(len))
+        .ok_or(MemoryError::OutOfBoundsAccess)?
+        .to_vec();
+    Ok(bytes)
+}
+
+fn write(caller: &mut Caller<'_, HostState>, ptr: i32, bytes: &[u8]) -> Result<(), Error> {
+    memory(caller)?.write(&mut *caller, ptr as u32 as usize, bytes)?;
+    Ok(())
+}
+
+fn byte_gas(len: i32) -> u64 {
+    len as u32 as u64 * BYTE_GAS
+}
+
+fn charge(caller: &mut Caller<'_, HostState>, gas: u64) -> Result<(), Error> {
+    let fuel = caller.get_fuel()?;
+    if fuel < gas {
+        caller.set_fuel(0)?;
+        return Err(Error::host(HostCall::OutOfGas));
+    }
+    caller.set_fuel(fuel - gas)?;
+    Ok(())
+}
+
+fn vm_error(err: Error) -> VmError {
+    match err.as_trap_code() {
+        Some(TrapCode::OutOfFuel) => VmError::OutOfGas,
+        Some(TrapCode::StackOverflow) => VmError::StackOverflow,
+        Some(TrapCode::IntegerDivisionByZero) => VmError::DivisionByZero,
+        Some(TrapCode::IntegerOverflow) => VmError::Overflow,
+        _ => VmError::Trap(err.to_string()),
+    }
+}
+
+#[async_trait::async_trait]
+impl VM for WasmVM {
+    async fn execute(
+        &mut self,
+        state: &DynState,
+        code: &[u8],
+        ctx: &ExecutionContext,
+        gas_limit: u64,
+    ) -> ExecutionResult {
+        match self.run(state, code, ctx, gas_limit).await {
+            (gas_used, Ok(())) => ExecutionResult {
+                gas_used,
+                error: None,
+                logs: vec![],
+                return_data: vec![],
+            },
+            (gas_used, Err(err)) => ExecutionResult::failed(gas_used, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        balance, global_storage, state::mem_state::MemState, vm::DEFAULT_TX_GAS_LIMIT, Address,
+        Hash,
+    };
+    use anyhow::Result;
+
+    const IMPORTS: &str = r#"
+        (import "env" "get" (func $get (param i32 i32 i32 i32) (result i32)))
+        (import "env" "set" (func $set (param i32 i32 i32 i32)))
+        (import "env" "delete" (func $delete (param i32 i32)))
+        (import "env" "input_len" (func $input_len (result i32)))
+        (import "env" "input" (func $input (param i32)))
+        (import "env" "revert" (func $revert))
+        (import "env" "sender" (func $sender (param i32) (result i32)))
+        (import "env" "tx_hash" (func $tx_hash (param i32)))
+        (import "env" "block_height" (func $block_height (result i64)))
+    "#;
+
+    // a module with the host imports, a page of memory with "key" at 0 and the given main function
+    fn module(main: &str) -> Vec<u8> {
+        wat::parse_str(format!(
+            r#"(module {IMPORTS}
+                (memory (export "memory") 1)
+                (data (i32.const 0) "key")
+                (func (export "main") {main}))"#
+        ))
+        .unwrap()
+    }
+
+    async fn run(state: &DynState, code: &[u8], input: &[u8]) -> ExecutionResult {
+        let ctx = ExecutionContext {
+            input: input.to_vec(),
+            ..Default::default()
+        };
+        WasmVM::new()
+            .execute(state, code, &ctx, DEFAULT_TX_GAS_LIMIT)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_wasm_state() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+
+        // store the input at "key"
+        let code = module(
+            "(call $input (i32.const 16))
+             (call $set (i32.const 0) (i32.const 3) (i32.const 16) (call $input_len))",
+        );
+        let result = run(&state, &code, b"hello").await;
+        assert_eq!(result.error, None);
+        assert!(result.gas_used > SET_GAS);
+        assert_eq!(global_storage(&state).get(b"key").await?, b"hello".to_vec());
+
+        // copy the value of "key" to "kez" and delete "key"
+        let code = module(
+            "(local $len i32)
+             (local.set $len (call $get (i32.const 0) (i32.const 3) (i32.const 16) (i32.const 64)))
+             (i32.store8 (i32.const 2) (i32.const 122))
+             (call $set (i32.const 0) (i32.const 3) (i32.const 16) (local.get $len))
+             (i32.store8 (i32.const 2) (i32.const 121))
+             (call $delete (i32.const 0) (i32.const 3))",
+        );
+        assert_eq!(run(&state, &code, &[]).await.error, None);
+        assert_eq!(global_storage(&state).get(b"kez").await?, b"hello".to_vec());
+        assert!(global_storage(&state).get(b"key").await.is_err());
+
+        // a missing key has length -1
+        let code = module(
+            "(if (i32.ne (call $get (i32.const 0) (i32.const 3) (i32.const 16) (i32.const 64)) (i32.const -1))
+                (then unreachable))",
+        );
+        assert_eq!(run(&state, &code, &[]).await.error, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wasm_cant_change_balance() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let account = Address::from_bytes(&[1; 20]);
+
+        // sets "balance/" followed by the address to the bytes of "balance/"
+        let code = wat::parse_str(format!(
+            r#"(module {IMPORTS}
+                (memory (export "memory") 1)
+                (data (i32.const 0) "balance/{}")
+                (func (export "main")
+                    (call $set (i32.const 0) (i32.const 28) (i32.const 0) (i32.const 8))))"#,
+            account
+                .as_bytes()
+                .iter()
+                .map(|b| format!("\\{b:02x}"))
+                .collect::<String>()
+        ))?;
+        assert_eq!(run(&state, &code, &[]).await.error, None);
+
+        let key = [b"balance/".as_slice(), account.as_bytes()].concat();
+        assert_eq!(
+            global_storage(&state).get(&key).await?,
+            b"balance/".to_vec()
+        );
+        assert_eq!(balance(&state, &account).await, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wasm_context() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+        let ctx = ExecutionContext {
+            sender: Some(Address::from_bytes(&[1; 20])),
+            tx_hash: Hash::from_bytes(&[2; 32]),
+            block_height: 7,
+            ..Default::default()
+        };
+
+        // stores the sender, the tx hash and the height one after another
+        let code = module(
+            "(if (call $sender (i32.const 16)) (then unreachable))
+             (call $tx_hash (i32.const 36))
+             (i64.store (i32.const 68) (call $block_height))
+             (call $set (i32.const 0) (i32.const 3) (i32.const 16) (i32.const 60))",
+        );
+        let result = WasmVM::new()
+            .execute(&state, &code, &ctx, DEFAULT_TX_GAS_LIMIT)
+            .await;
+        assert_eq!(result.error, None);
+        assert_eq!(
+            global_storage(&state).get(b"key").await?,
+            [[1; 20].as_slice(), &[2; 32], &7u64.to_le_bytes()].concat()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wasm_gas() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+
+        let code = module("(loop $loop (br $loop))");
+        let result = WasmVM::new()
+            .execute(&state, &code, &ExecutionContext::default(), 10_000)
+            .await;
+        assert_eq!(result.error, Some(VmError::OutOfGas));
+        assert_eq!(result.gas_used, 10_000);
+
+        // not enough gas left for the store
+        let code = module("(call $set (i32.const 0) (i32.const 3) (i32.const 0) (i32.const 3))");
+        let result = WasmVM::new()
+            .execute(&state, &code, &ExecutionContext::default(), 500)
+            .await;
+        assert_eq!(result.error, Some(VmError::OutOfGas));
+        assert!(global_storage(&state).get(b"key").await.is_err());
+
+        // every byte of the value costs extra
+        let set = |len: i32| {
+            module(&format!(
+                "(call $set (i32.const 0) (i32.const 3) (i32.const 0) (i32.const {len}))"
+            ))
+        };
+        let small = run(&state, &set(1), &[]).await.gas_used;
+        let large = run(&state, &set(101), &[]).await.gas_used;
+        assert_eq!(large - small, 100 * BYTE_GAS);
+
+        // a length far outside the memory runs out of gas or traps, but never allocates it
+        assert_eq!(
+            run(&state, &set(-1), &[]).await.error,
+            Some(VmError::OutOfGas)
+        );
+        let result = WasmVM::new()
+            .execute(&state, &set(-1), &ExecutionContext::default(), 1 << 40)
+            .await;
+        assert!(matches!(result.error, Some(VmError::Trap(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wasm_failures() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+
+        let cases = [
+            (module("(call $revert)"), VmError::Reverted),
+            (
+                module("(drop (i32.div_u (i32.const 1) (i32.const 0)))"),
+                VmError::DivisionByZero,
+            ),
+        ];
+        for (code, error) in cases {
+            assert_eq!(run(&state, &code, b"a").await.error, Some(error));
+        }
+
+        // writing the input outside of the memory traps
+        let result = run(&state, &module("(call $input (i32.const 65536))"), b"a").await;
+        assert!(matches!(result.error, Some(VmError::Trap(_))));
+
+        let invalid = [
+            vec![0, 1, 2, 3],
+            // floats are not deterministic
+            module("(drop (f32.add (f32.const 1) (f32.const 2)))"),
+            // no main function
+            wat::parse_str(r#"(module (memory (export "memory") 1))"#)?,
+            // more memory than allowed
+            wat::parse_str(r#"(module (memory (export "memory") 17) (func (export "main")))"#)?,
+        ];
+        for code in invalid {
+            let result = run(&state, &code, &[]).await;
+            assert!(
+                matches!(result.error, Some(VmError::InvalidModule(_))),
+                "{:?}",
+                result.error
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_wasm_memory_limit() -> Result<()> {
+        let state: DynState = Box::new(MemState::new());
+
+        // growing beyond the limit fails, growing within it works
+        let code = module(
+            "(if (i32.ne (memory.grow (i32.const 16)) (i32.const -1)) (then unreachable))
+             (if (i32.eq (memory.grow (i32.const 15)) (i32.const -1)) (then unreachable))",
+        );
+        assert_eq!(run(&state, &code, &[]).await.error, None);
+
+        Ok(())
+    }
+}
@@ -7,6 +7,43 @@ pub use public_key::PublicKey;
 pub use signature::Signature;
 pub use Hash;
 
+use sha2::{Digest, Sha256};
+
+// Size of a compressed P-256 public key and a signature, what a signer adds to a signed payload
+pub const SIGNER_SIZE: usize = 33 + 64;
+
+// The kinds of payloads that get signed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigningDomain {
+    Transaction,
+    Block,
+}
+
+impl SigningDomain {
+    fn tag(self) -> &'static [u8] {
+        match self {
+            Self::Transaction => b"muckchain/transaction",
+            Self::Block => b"muckchain/block",
+        }
+    }
+}
+
+/*
+    What gets signed instead of the payload itself: the hash of a tag for the kind of payload,
+    the chain id of the network and the canonical encoding of the payload.
+    A signature is only valid for one kind of payload on one network
+*/
+pub fn signing_digest(domain: SigningDomain, chain_id: u64, payload: &[u8]) -> Vec<u8> {
+    let tag = domain.tag();
+    Sha256::new()
+        .chain_update([tag.len() as u8])
+        .chain_update(tag)
+        .chain_update(chain_id.to_le_bytes())
+        .chain_update(payload)
+        .finalize()
+        .to_vec()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -36,4 +73,18 @@ mod tests {
         assert!(!sig.verify(msg, &other_public_key));
         assert!(!sig.verify(b"wrong message", &other_public_key));
     }
+
+    #[test]
+    fn test_signing_digest_is_domain_separated() {
+        let digest = signing_digest(SigningDomain::Transaction, 1, b"payload");
+        assert_eq!(
+            digest,
+            signing_digest(SigningDomain::Transaction, 1, b"payload")
+        );
+        assert_ne!(digest, signing_digest(SigningDomain::Block, 1, b"payload"));
+        assert_ne!(
+            digest,
+            signing_digest(SigningDomain::Transaction, 2, b"payload")
+        );
+    }
 }
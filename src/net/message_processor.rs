@@ -1,5 +1,10 @@
 use super::{message::Message, message_sender::MessageSender, TxPool};
-use crate::{config::HasherConfig, net::Status, prelude::*};
+use crate::{
+    config::HasherConfig,
+    core::{ChainUpdate, McError},
+    net::Status,
+    prelude::*,
+};
 
 #[derive(Debug, Clone)]
 pub struct MessageProcessor {
@@ -29,7 +34,7 @@ impl MessageProcessor {
 
     pub async fn process_message(&self, from: NetAddr, msg: Message) -> Result<()> {
         match msg {
-            Message::Transaction(tx) => self.process_transaction(tx).await?,
+            Message::Transaction(tx) => self.process_transaction(from, tx).await?,
             Message::Block(block) => self.process_block(block).await?,
             // TODO: this was added for debug purposes, maybe remove it
             Message::Text(text) => {
@@ -80,20 +85,32 @@ impl MessageProcessor {
 
     async fn process_blocks(&self, blocks: Vec<Block>) -> Result<()> {
         for block in blocks {
-            if let Err(_err) = self.blockchain.add_block(block).await {
-                // TODO: handle error
-                // debug!("Error processing block: {:?}", err);
+            match self.blockchain.add_block(block).await {
+                Ok(update) => self.update_tx_pool(&update).await?,
+                Err(_err) => {
+                    // TODO: handle error
+                    // debug!("Error processing block: {:?}", err);
+                }
             }
         }
         Ok(())
     }
 
+    // The transactions of blocks that made it into the main chain leave the pool,
+    // the ones of blocks a reorg removed come back
+    async fn update_tx_pool(&self, update: &ChainUpdate) -> Result<()> {
+        self.tx_pool
+            .update(self.hashers.tx_hasher.clone(), update)
+            .await
+    }
+
     pub async fn process_block(&self, mut block: Block) -> Result<()> {
         let block_hash = block.hash(&self.hashers.block_hasher.clone())?;
 
         info!("Node={} received block={}", self.node_id, block_hash);
 
-        self.blockchain.add_block(block.clone()).await?;
+        let update = self.blockchain.add_block(block.clone()).await?;
+        self.update_tx_pool(&update).await?;
 
         self.sender.broadcast_block_threaded(block);
 
@@ -101,7 +118,7 @@ impl MessageProcessor {
         Ok(())
     }
 
-    pub async fn process_transaction(&self, mut tx: Transaction) -> Result<()> {
+    pub async fn process_transaction(&self, from: NetAddr, mut tx: Transaction) -> Result<()> {
         let tx_hash = tx.hash(self.hashers.tx_hasher.clone()).await?;
 
         info!("Node={} received transaction={}", self.node_id, tx_hash);
@@ -117,14 +134,47 @@ impl MessageProcessor {
         tx.set_first_seen(first_seen);
 
         // Verify the transaction
-        tx.verify()?;
+        tx.verify(self.blockchain.chain_id())?;
+
+        // A transaction over the size limit can never be part of a block
+        let max_tx_bytes = self.blockchain.config.limits.max_tx_bytes;
+        if tx.size() > max_tx_bytes {
+            return Err(anyhow!(
+                "transaction of {} bytes exceeds the limit of {}",
+                tx.size(),
+                max_tx_bytes
+            ));
+        }
+
+        // Reject transactions with a nonce that is already used and ones whose sender can't pay their fee and value right now
+        if let Some(sender) = tx.sender() {
+            let nonce = self.blockchain.nonce(&sender).await;
+            if tx.nonce < nonce {
+                Err(McError::InvalidNonce {
+                    account: sender,
+                    expected: nonce,
+                    found: tx.nonce,
+                })?;
+            }
 
+            let balance = self.blockchain.balance(&sender).await;
+            if balance < tx.cost() {
+                Err(McError::InsufficientBalance {
+                    account: sender,
+                    balance,
+                    value: tx.cost(),
+                })?;
+            }
+        }
+
+        // Transactions the pool doesn't take are not passed on either
         if let Err(err) = self
             .tx_pool
-            .add_tx(self.hashers.tx_hasher.clone(), tx.clone())
+            .add_tx(self.hashers.tx_hasher.clone(), tx.clone(), Some(from))
             .await
         {
             error!("could not add transaction to tx_pool: {:?}", err);
+            return Ok(());
         }
 
         self.sender.broadcast_transaction_threaded(tx);
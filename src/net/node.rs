@@ -1,5 +1,5 @@
 use crate::config::{Config, NodeConfig, ValidatorConfig};
-use crate::core::{BlockchainConfig, McError};
+use crate::core::{Address, BlockchainConfig, McError};
 use crate::crypto::PrivateKey;
 use crate::net::message::Message;
 use crate::prelude::*;
@@ -40,14 +40,14 @@ impl Node {
         validator_config: Option<ValidatorConfig>,
     ) -> Result<Self> {
         let blockchain = Blockchain::new(blockchain_config).await?;
-        let tx_pool = TxPool::new();
+        let tx_pool = TxPool::new(config.tx_pool.clone());
 
         let msg_sender = MessageSender::new(transport.clone(), config.encoding.encoder.clone());
         let msg_processor = MessageProcessor::new(
             id.clone(),
             blockchain.clone(),
             config.hashers.clone(),
-            tx_pool,
+            tx_pool.clone(),
             msg_sender.clone(),
         );
 
@@ -55,7 +55,7 @@ impl Node {
             id,
             transport,
             rpc_channel: new_channel(),
-            tx_pool: TxPool::new(),
+            tx_pool,
             validator: None,
             blockchain,
             config,
@@ -128,6 +128,7 @@ impl Node {
                         match mc_err {
                             // Don't print an error if the block already exists
                             McError::BlockAlreadyExists(_) => {}
+                            _ => error!("Node={} Error processing message: {}", self.id, mc_err),
                         }
                     } else {
                         error!("Node={} Error processing message: {:?}", self.id, err);
@@ -160,7 +161,35 @@ pub async fn create_and_start_node(
 
     */
 
-    let config = Config::default();
+    let mut config = Config::default();
+
+    /*
+        If MUCKCHAIN_DATA_DIR is set the blocks are persisted to disk
+        so that the node can continue its chain after a restart
+    */
+    if let Ok(data_dir) = std::env::var("MUCKCHAIN_DATA_DIR") {
+        config = config.with_file_storage(std::path::Path::new(&data_dir).join(node_id))?;
+    }
+
+    // MUCKCHAIN_TRIE_STATE switches the state to the merkle trie, it has to be set for all nodes or none
+    if std::env::var("MUCKCHAIN_TRIE_STATE").is_ok() {
+        config = config.with_trie_state();
+    }
+
+    // MUCKCHAIN_WASM_VM runs transactions as WebAssembly modules, also the same for all nodes
+    if std::env::var("MUCKCHAIN_WASM_VM").is_ok() {
+        config = config.with_wasm_vm();
+    }
+
+    // MUCKCHAIN_CHAIN_ID separates networks, a node only accepts blocks and transactions signed for its chain
+    if let Ok(chain_id) = std::env::var("MUCKCHAIN_CHAIN_ID") {
+        config = config.with_chain_id(chain_id.parse()?);
+    }
+
+    // MUCKCHAIN_GENESIS_ALLOC gives accounts a starting balance, e.g. `<address>=100,<address>=50`
+    if let Ok(alloc) = std::env::var("MUCKCHAIN_GENESIS_ALLOC") {
+        config = config.with_genesis_alloc(parse_genesis_alloc(&alloc)?);
+    }
 
     /*
         If the node is a validator we create a validator config which
@@ -203,3 +232,15 @@ pub async fn create_and_start_node(
 
     Ok(node)
 }
+
+fn parse_genesis_alloc(alloc: &str) -> Result<Vec<(Address, u64)>> {
+    alloc
+        .split(',')
+        .map(|entry| {
+            let (address, amount) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("genesis allocation {entry} is not <address>=<amount>"))?;
+            Ok((address.trim().parse()?, amount.trim().parse()?))
+        })
+        .collect()
+}
@@ -4,68 +4,567 @@
     It's supposed to be attached to a Node struct.
     Which means that it needs to be usable in many threads and therefore needs a shared state.
 
+    The transactions of a sender can only be included in the order of their nonces,
+    a transaction whose nonce comes after a gap stays queued until the gap is filled.
+    Transactions that pay a higher gas price go first, a pending transaction can be replaced
+    by one with the same sender and nonce that pays at least PRICE_BUMP_PERCENT more.
+
+    The pool is bounded by the TxPoolConfig: when it's full the lowest paying transactions
+    are evicted, transactions expire after a while and every sender and peer has a quota.
+    Transactions leave the pool when a block includes them and come back when a reorg
+    removes their block from the main chain again.
 */
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::Arc,
+};
+
+use tokio::{sync::RwLock, time::Instant};
 
-use tokio::sync::RwLock;
+use crate::{
+    config::TxPoolConfig,
+    core::{tx_hashes, Address, ChainUpdate},
+    prelude::*,
+};
 
-use crate::prelude::*;
+use super::NetAddr;
+
+// How much more a transaction has to pay to replace a pending one with the same nonce
+pub const PRICE_BUMP_PERCENT: u64 = 10;
 
 #[derive(Debug, Clone)]
 pub struct TxPool {
-    all_txs: Arc<RwLock<HashMap<Hash, Transaction>>>,
-    pending_txs: Arc<RwLock<HashMap<Hash, Transaction>>>,
+    config: TxPoolConfig,
+    txs: Arc<RwLock<PoolTxs>>,
+}
+
+#[derive(Debug, Clone)]
+struct PoolTx {
+    tx: Transaction,
+    // the peer we got the transaction from, None for our own and reinserted ones
+    peer: Option<NetAddr>,
+    added: Instant,
+    // order in which the transactions arrived
+    seq: u64,
+    size: usize,
+}
+
+// The transactions in the pool and the indexes over them, always changed together
+#[derive(Debug, Default)]
+struct PoolTxs {
+    txs: HashMap<Hash, PoolTx>,
+    // the transaction of every sender and nonce
+    nonces: HashMap<(Address, u64), Hash>,
+    per_sender: HashMap<Address, usize>,
+    per_peer: HashMap<NetAddr, usize>,
+    bytes: usize,
+    next_seq: u64,
+}
+
+impl PoolTxs {
+    fn insert(&mut self, hash: Hash, mut entry: PoolTx) {
+        entry.seq = self.next_seq;
+        self.next_seq += 1;
+        self.bytes += entry.size;
+        if let Some(sender) = entry.tx.sender() {
+            self.nonces.insert((sender, entry.tx.nonce), hash);
+            *self.per_sender.entry(sender).or_default() += 1;
+        }
+        if let Some(peer) = &entry.peer {
+            *self.per_peer.entry(peer.clone()).or_default() += 1;
+        }
+        self.txs.insert(hash, entry);
+    }
+
+    fn remove(&mut self, hash: &Hash) -> Option<PoolTx> {
+        let entry = self.txs.remove(hash)?;
+        self.bytes -= entry.size;
+        if let Some(sender) = entry.tx.sender() {
+            if self.nonces.get(&(sender, entry.tx.nonce)) == Some(hash) {
+                self.nonces.remove(&(sender, entry.tx.nonce));
+            }
+            decrement(&mut self.per_sender, &sender);
+        }
+        if let Some(peer) = &entry.peer {
+            decrement(&mut self.per_peer, peer);
+        }
+        Some(entry)
+    }
+
+    fn expire(&mut self, ttl: Duration) {
+        let expired: Vec<Hash> = self
+            .txs
+            .iter()
+            .filter(|(_, entry)| entry.added.elapsed() > ttl)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            debug!("transaction {hash} expired");
+            self.remove(&hash);
+        }
+    }
+
+    // The transaction to evict first: the lowest gas price, of those the latest nonce and the latest arrival
+    fn cheapest(&self) -> Option<Hash> {
+        self.txs
+            .iter()
+            .min_by_key(|(_, entry)| {
+                (
+                    entry.tx.gas_price,
+                    Reverse(entry.tx.nonce),
+                    Reverse(entry.seq),
+                )
+            })
+            .map(|(hash, _)| *hash)
+    }
+}
+
+fn decrement<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
 }
 
 impl TxPool {
-    pub fn new() -> Self {
+    pub fn new(config: TxPoolConfig) -> Self {
         Self {
-            all_txs: Arc::new(RwLock::new(HashMap::new())),
-            pending_txs: Arc::new(RwLock::new(HashMap::new())),
+            config,
+            txs: Arc::new(RwLock::new(PoolTxs::default())),
         }
     }
 
+    fn ttl(&self) -> Duration {
+        Duration::from_millis(self.config.tx_ttl_ms)
+    }
+
     pub async fn add_tx(
         &self,
         hasher: Box<dyn Hasher<Transaction>>,
+        tx: Transaction,
+        peer: Option<NetAddr>,
+    ) -> Result<()> {
+        let mut txs = self.txs.write().await;
+        txs.expire(self.ttl());
+        self.insert(&mut txs, hasher, tx, peer).await
+    }
+
+    async fn insert(
+        &self,
+        txs: &mut PoolTxs,
+        hasher: Box<dyn Hasher<Transaction>>,
         mut tx: Transaction,
+        peer: Option<NetAddr>,
     ) -> Result<()> {
         let tx_hash = tx.hash(hasher).await?;
+        if txs.txs.contains_key(&tx_hash) {
+            return Ok(());
+        }
+
+        if tx.gas_price < self.config.min_gas_price {
+            return Err(anyhow!(
+                "transaction {tx_hash} pays a gas price of {} but the pool needs at least {}",
+                tx.gas_price,
+                self.config.min_gas_price
+            ));
+        }
+
+        let sender = tx
+            .sender()
+            .ok_or_else(|| anyhow!("transaction {tx_hash} has no sender"))?;
+
+        let replaced = txs.nonces.get(&(sender, tx.nonce)).copied();
+        if let Some(pending_hash) = replaced {
+            let min_gas_price = replacement_gas_price(txs.txs[&pending_hash].tx.gas_price);
+            if tx.gas_price < min_gas_price {
+                return Err(anyhow!(
+                    "transaction {tx_hash} has to pay a gas price of at least {min_gas_price} to replace {pending_hash}"
+                ));
+            }
+        } else {
+            // a replacement takes the place of the transaction it replaces, anything else needs room in the quotas
+            if txs.per_sender.get(&sender).copied().unwrap_or(0) >= self.config.max_txs_per_sender {
+                return Err(anyhow!(
+                    "sender {sender} already has {} transactions in the pool",
+                    self.config.max_txs_per_sender
+                ));
+            }
+            if let Some(peer) = &peer {
+                if txs.per_peer.get(peer).copied().unwrap_or(0) >= self.config.max_txs_per_peer {
+                    return Err(anyhow!(
+                        "peer {peer} already has {} transactions in the pool",
+                        self.config.max_txs_per_peer
+                    ));
+                }
+            }
+        }
+
+        if let Some(pending_hash) = replaced {
+            debug!("transaction {tx_hash} replaces {pending_hash}");
+            txs.remove(&pending_hash);
+        }
+
         debug!("adding tx to pool: {:?}", tx_hash);
-        self.all_txs.write().await.insert(tx_hash, tx.clone());
-        self.pending_txs.write().await.insert(tx_hash, tx);
+        txs.insert(
+            tx_hash,
+            PoolTx {
+                size: tx.size(),
+                tx,
+                peer,
+                added: Instant::now(),
+                seq: 0,
+            },
+        );
+
+        // make room by evicting the lowest paying transactions, which can be the new one
+        while txs.txs.len() > self.config.max_txs || txs.bytes > self.config.max_bytes {
+            let Some(evicted) = txs.cheapest() else {
+                break;
+            };
+            txs.remove(&evicted);
+            if evicted == tx_hash {
+                return Err(anyhow!(
+                    "transaction {tx_hash} pays too little to be kept in the full pool"
+                ));
+            }
+            debug!("evicted transaction {evicted} from the full pool");
+        }
 
         Ok(())
     }
-    pub async fn pending(&self) -> Result<Vec<Transaction>> {
-        let mut txs: Vec<Transaction> = self.pending_txs.read().await.values().cloned().collect();
 
-        // If some transactions have no valid timestamp return an error
-        if txs.iter().any(|tx| tx.first_seen() == 0) {
-            return Err(anyhow!("some transaction have first_seen set to 0 which means it didn't get set when it arrived at the node, make sure to set_first_seen after you receive a transaction"));
+    /*
+        The transactions that can go into the next block on top of the head of the blockchain.
+        They are ordered by their gas price and then by when they arrived, but the transactions
+        of every sender follow each other in the order of their nonces starting at the sender's next nonce.
+        Expired transactions and transactions with a nonce that is already used are removed from the pool
+    */
+    pub async fn pending(&self, blockchain: &Blockchain) -> Result<Vec<Transaction>> {
+        let mut pool = self.txs.write().await;
+        pool.expire(self.ttl());
+
+        let mut by_sender: HashMap<Address, BTreeMap<u64, (Hash, &PoolTx)>> = HashMap::new();
+        for (hash, entry) in &pool.txs {
+            if let Some(sender) = entry.tx.sender() {
+                by_sender
+                    .entry(sender)
+                    .or_default()
+                    .insert(entry.tx.nonce, (*hash, entry));
+            }
+        }
+
+        let mut stale = vec![];
+        let mut ready = vec![];
+        for (sender, queue) in by_sender {
+            let mut next = blockchain.nonce(&sender).await;
+            let mut txs = VecDeque::new();
+            for (nonce, (hash, entry)) in queue {
+                if nonce < next {
+                    stale.push(hash);
+                } else if nonce == next {
+                    txs.push_back(entry);
+                    next += 1;
+                } else {
+                    // a gap, the rest waits for the missing nonce
+                    break;
+                }
+            }
+            ready.push(txs);
+        }
+
+        // take the best paying transaction at the front of the senders every time
+        let mut txs = vec![];
+        while let Some(queue) = ready
+            .iter_mut()
+            .filter(|queue| !queue.is_empty())
+            .max_by_key(|queue| (queue[0].tx.gas_price, Reverse(queue[0].seq)))
+        {
+            txs.extend(queue.pop_front().map(|entry| entry.tx.clone()));
         }
 
-        txs.sort_by_key(|a| a.first_seen());
+        for hash in stale {
+            pool.remove(&hash);
+        }
 
         Ok(txs)
     }
 
     pub async fn has_tx(&self, tx_hash: &Hash) -> bool {
-        self.all_txs.read().await.contains_key(tx_hash)
+        self.txs.read().await.txs.contains_key(tx_hash)
+    }
+
+    pub async fn remove_pending(&self, tx_hashes: &[Hash]) {
+        let mut txs = self.txs.write().await;
+        for tx_hash in tx_hashes {
+            txs.remove(tx_hash);
+        }
+    }
+
+    pub async fn remove_tx(&self, tx_hash: &Hash) -> Option<Transaction> {
+        self.txs.write().await.remove(tx_hash).map(|entry| entry.tx)
+    }
+
+    pub async fn get_tx(&self, tx_hash: &Hash) -> Option<Transaction> {
+        self.txs
+            .read()
+            .await
+            .txs
+            .get(tx_hash)
+            .map(|entry| entry.tx.clone())
+    }
+
+    // Follow a change of the main chain: the transactions of the enacted blocks are included now,
+    // the ones of the retracted blocks are not anymore and go back into the pool
+    pub async fn update(
+        &self,
+        hasher: Box<dyn Hasher<Transaction>>,
+        update: &ChainUpdate,
+    ) -> Result<()> {
+        let mut txs = self.txs.write().await;
+
+        let mut included = vec![];
+        for block in &update.enacted {
+            included.extend(tx_hashes(&block.transactions, &hasher)?);
+        }
+
+        for block in &update.retracted {
+            for (tx, tx_hash) in block
+                .transactions
+                .iter()
+                .zip(tx_hashes(&block.transactions, &hasher)?)
+            {
+                if included.contains(&tx_hash) {
+                    continue;
+                }
+                if let Err(err) = self
+                    .insert(&mut txs, hasher.clone(), tx.clone(), None)
+                    .await
+                {
+                    debug!("could not reinsert transaction {tx_hash} of a retracted block: {err}");
+                }
+            }
+        }
+
+        for tx_hash in &included {
+            txs.remove(tx_hash);
+        }
+
+        Ok(())
+    }
+}
+
+fn replacement_gas_price(gas_price: u64) -> u64 {
+    let bump = (gas_price.saturating_mul(PRICE_BUMP_PERCENT) / 100).max(1);
+    gas_price.saturating_add(bump)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Config, TxPoolConfig},
+        core::{use_nonce, TxHasher},
+        crypto::PrivateKey,
+        util::random_hash,
+    };
+
+    fn tx(key: &PrivateKey, nonce: u64, gas_price: u64) -> Transaction {
+        let mut tx = Transaction::new(vec![])
+            .with_nonce(nonce)
+            .with_gas_price(gas_price);
+        tx.sign(key);
+        tx
+    }
+
+    #[tokio::test]
+    async fn test_pending_nonce_order() -> Result<()> {
+        let bc = Blockchain::new(Config::default().blockchain_config()).await?;
+        let pool = TxPool::new(Config::default().tx_pool);
+        let alice = PrivateKey::generate();
+        let bob = PrivateKey::generate();
+
+        let add =
+            |key: &PrivateKey, nonce: u64| pool.add_tx(Box::new(TxHasher), tx(key, nonce, 0), None);
+        let nonces = |txs: Vec<Transaction>| {
+            txs.iter()
+                .map(|tx| (tx.sender().unwrap(), tx.nonce))
+                .collect::<Vec<_>>()
+        };
+        let (a, b) = (alice.public_key().address(), bob.public_key().address());
+
+        // alice's nonce 1 arrives before her nonce 0, nonce 3 waits for nonce 2
+        add(&alice, 1).await?;
+        add(&bob, 0).await?;
+        add(&alice, 0).await?;
+        add(&alice, 3).await?;
+        assert_eq!(nonces(pool.pending(&bc).await?), [(b, 0), (a, 0), (a, 1)]);
+
+        add(&alice, 2).await?;
+        assert_eq!(
+            nonces(pool.pending(&bc).await?),
+            [(b, 0), (a, 0), (a, 1), (a, 2), (a, 3)]
+        );
+
+        // once alice's nonce 0 and 1 are used her transactions with them are dropped
+        use_nonce(&bc.config.state, &a, 0).await?;
+        use_nonce(&bc.config.state, &a, 1).await?;
+        assert_eq!(nonces(pool.pending(&bc).await?), [(b, 0), (a, 2), (a, 3)]);
+        assert_eq!(pool.txs.read().await.txs.len(), 3);
+
+        Ok(())
     }
 
-    pub async fn clear_pending(&self) {
-        self.pending_txs.write().await.clear();
+    #[tokio::test]
+    async fn test_pending_fee_order() -> Result<()> {
+        let bc = Blockchain::new(Config::default().blockchain_config()).await?;
+        let pool = TxPool::new(TxPoolConfig {
+            min_gas_price: 2,
+            ..Config::default().tx_pool
+        });
+        let alice = PrivateKey::generate();
+        let bob = PrivateKey::generate();
+
+        let add = |key: &PrivateKey, nonce: u64, gas_price: u64| {
+            pool.add_tx(Box::new(TxHasher), tx(key, nonce, gas_price), None)
+        };
+        let prices = |txs: Vec<Transaction>| txs.iter().map(|tx| tx.gas_price).collect::<Vec<_>>();
+
+        // below the floor
+        assert!(add(&alice, 0, 1).await.is_err());
+
+        add(&alice, 0, 2).await?;
+        add(&alice, 1, 9).await?;
+        add(&bob, 0, 5).await?;
+        // bob pays more than alice's first transaction, her second one still has to wait for it
+        assert_eq!(prices(pool.pending(&bc).await?), [5, 2, 9]);
+
+        // a replacement has to pay at least 10% more
+        let mut other = Transaction::new(vec![1]).with_gas_price(5);
+        other.sign(&bob);
+        assert!(pool.add_tx(Box::new(TxHasher), other, None).await.is_err());
+        add(&alice, 0, 3).await?;
+        assert_eq!(prices(pool.pending(&bc).await?), [5, 3, 9]);
+        assert_eq!(pool.txs.read().await.txs.len(), 3);
+
+        add(&alice, 0, 6).await?;
+        assert_eq!(prices(pool.pending(&bc).await?), [6, 9, 5]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bounds() -> Result<()> {
+        let pool = TxPool::new(TxPoolConfig {
+            max_txs: 3,
+            max_txs_per_sender: 2,
+            max_txs_per_peer: 2,
+            ..Config::default().tx_pool
+        });
+        let keys: Vec<PrivateKey> = (0..4).map(|_| PrivateKey::generate()).collect();
+        let add = |key: usize, nonce: u64, gas_price: u64, peer: &str| {
+            pool.add_tx(
+                Box::new(TxHasher),
+                tx(&keys[key], nonce, gas_price),
+                Some(peer.to_string()),
+            )
+        };
+
+        add(0, 0, 1, "a").await?;
+        add(0, 1, 1, "b").await?;
+        // the sender and the peer have used up their quota
+        assert!(add(0, 2, 1, "c").await.is_err());
+        add(1, 0, 5, "a").await?;
+        assert!(add(2, 0, 5, "a").await.is_err());
+        assert_eq!(pool.txs.read().await.txs.len(), 3);
+
+        // the pool is full, the latest nonce of the lowest gas price goes first
+        add(2, 0, 3, "c").await?;
+        assert_eq!(pool.txs.read().await.txs.len(), 3);
+        assert!(!pool.has_tx(&TxHasher.hash(&tx(&keys[0], 1, 1))?).await);
+        assert!(pool.has_tx(&TxHasher.hash(&tx(&keys[0], 0, 1))?).await);
+
+        // a transaction paying less than everything in the full pool isn't kept
+        assert!(add(3, 0, 0, "d").await.is_err());
+        assert_eq!(pool.txs.read().await.txs.len(), 3);
+
+        Ok(())
     }
 
-    pub async fn remove_tx(&self, tx: &Transaction) {
-        // let mut transactions = self.transactions.write().await;
-        // transactions.retain(|t| t != transaction);
+    #[tokio::test]
+    async fn test_expiry() -> Result<()> {
+        let bc = Blockchain::new(Config::default().blockchain_config()).await?;
+        let pool = TxPool::new(TxPoolConfig {
+            tx_ttl_ms: 50,
+            ..Config::default().tx_pool
+        });
+        pool.add_tx(Box::new(TxHasher), tx(&PrivateKey::generate(), 0, 0), None)
+            .await?;
+        assert_eq!(pool.pending(&bc).await?.len(), 1);
+
+        sleep(Duration::from_millis(100)).await;
+        assert!(pool.pending(&bc).await?.is_empty());
+        assert_eq!(pool.txs.read().await.txs.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_update() -> Result<()> {
+        let config = Config::default();
+        let pool = TxPool::new(config.tx_pool.clone());
+        let key = PrivateKey::generate();
+        let (included, pending) = (tx(&key, 0, 0), tx(&key, 1, 0));
+        let hasher = || -> Box<dyn Hasher<Transaction>> { Box::new(TxHasher) };
+
+        pool.add_tx(hasher(), included.clone(), None).await?;
+        pool.add_tx(hasher(), pending.clone(), None).await?;
+
+        let block = Block::from_prev_header(
+            &config.genesis_block.header,
+            vec![included.clone()],
+            &config.encoding.encoder,
+            &config.hashers.block_hasher,
+            &config.hashers.tx_hasher,
+        )?;
+        let enacted = ChainUpdate {
+            enacted: vec![block.clone()],
+            retracted: vec![],
+        };
+        pool.update(hasher(), &enacted).await?;
+        assert!(!pool.has_tx(&TxHasher.hash(&included)?).await);
+        assert!(pool.has_tx(&TxHasher.hash(&pending)?).await);
+
+        // a reorg takes the block out of the main chain again
+        let retracted = ChainUpdate {
+            enacted: vec![],
+            retracted: vec![block],
+        };
+        pool.update(hasher(), &retracted).await?;
+        assert!(pool.has_tx(&TxHasher.hash(&included)?).await);
+        assert_eq!(pool.txs.read().await.txs.len(), 2);
+
+        Ok(())
     }
 
-    pub async fn get_tx(&self) -> Vec<Transaction> {
-        todo!()
-        // self.transactions.read().await.clone()
+    #[tokio::test]
+    async fn test_forged_hash_is_ignored() -> Result<()> {
+        let pool = TxPool::new(Config::default().tx_pool);
+        let tx = tx(&PrivateKey::generate(), 0, 0);
+        let forged_hash = random_hash();
+
+        // a peer sends the transaction with the hash of another one
+        let mut json = serde_json::to_value(&tx)?;
+        json["hash"] = serde_json::to_value(forged_hash)?;
+        let forged: Transaction = serde_json::from_value(json)?;
+
+        pool.add_tx(Box::new(TxHasher), forged, None).await?;
+        assert!(pool.has_tx(&TxHasher.hash(&tx)?).await);
+        assert!(!pool.has_tx(&forged_hash).await);
+
+        Ok(())
     }
 }
@@ -1,4 +1,10 @@
-use crate::{config::ValidatorConfig, prelude::*};
+use crate::{
+    config::ValidatorConfig,
+    core::{vm::bytecode_vm::asm::assemble, Address, BLOCK_HEADER_SIZE},
+    prelude::*,
+};
+
+use std::collections::{HashMap, HashSet};
 
 use super::{message_sender::MessageSender, DynTransport, TxPool};
 
@@ -30,7 +36,8 @@ impl Validator {
 
     // TODO: move this to a test
     fn send_signed_test_transaction(&self) {
-        let mut tx = Transaction::new("hello world!".into());
+        let code = assemble("PushInt 2\nPushInt 4\nStore").unwrap();
+        let mut tx = Transaction::new(code).with_chain_id(self.blockchain.chain_id());
         tx.sign(&self.config.private_key);
 
         let msg_sender = self.msg_sender.clone();
@@ -75,19 +82,84 @@ impl Validator {
             .await
             .ok_or_else(|| anyhow!("No header found"))?;
 
-        // Gather all pending transactions
-        let pending_txs = self.tx_pool.pending().await?;
+        // Gather the pending transactions that fit into the block limits, the rest waits for the next block
+        let limits = &self.config.limits;
+        let mut txs = vec![];
+        let mut block_gas = 0u64;
+        let mut block_bytes = BLOCK_HEADER_SIZE;
+        // what every sender already spends in this block, a block with a transaction its sender can't pay is invalid
+        let mut spent: HashMap<Address, u64> = HashMap::new();
+        // senders with a transaction that was left out, their later nonces can't be included either
+        let mut skipped: HashSet<Address> = HashSet::new();
+        for mut tx in self.tx_pool.pending(&self.blockchain).await? {
+            if txs.len() >= limits.max_block_txs {
+                break;
+            }
+            let block_gas_limit = limits.block_gas_limit;
+            let Some(sender) = tx.sender() else {
+                continue;
+            };
+            if skipped.contains(&sender) {
+                continue;
+            }
+
+            if tx.gas_limit > block_gas_limit {
+                let tx_hash = tx.hash(self.config.hashers.tx_hasher.clone()).await?;
+                warn!("dropping transaction {tx_hash}, its gas limit exceeds the block gas limit");
+                self.tx_pool.remove_pending(&[tx_hash]).await;
+                skipped.insert(sender);
+                continue;
+            }
+
+            if tx.size() > limits.max_tx_bytes {
+                let tx_hash = tx.hash(self.config.hashers.tx_hasher.clone()).await?;
+                warn!("dropping transaction {tx_hash}, it exceeds the transaction size limit");
+                self.tx_pool.remove_pending(&[tx_hash]).await;
+                skipped.insert(sender);
+                continue;
+            }
 
-        // Create a new Block from the current_header and put all the pending transactions in it
+            if block_gas + tx.gas_limit > block_gas_limit
+                || block_bytes + tx.size() > limits.max_block_bytes
+            {
+                skipped.insert(sender);
+                continue;
+            }
+
+            let spent = spent.entry(sender).or_default();
+            let balance = self.blockchain.balance(&sender).await;
+            if balance.saturating_sub(*spent) < tx.cost() {
+                let tx_hash = tx.hash(self.config.hashers.tx_hasher.clone()).await?;
+                warn!("dropping transaction {tx_hash}, its sender can't pay its fee and value");
+                self.tx_pool.remove_pending(&[tx_hash]).await;
+                skipped.insert(sender);
+                continue;
+            }
+            *spent += tx.cost();
+
+            block_gas += tx.gas_limit;
+            block_bytes += tx.size();
+            txs.push(tx);
+        }
+
+        // Create a new Block from the current_header and put the selected transactions in it
         let mut block = Block::from_prev_header(
             &current_header,
-            pending_txs,
+            txs,
             &self.config.encoding.encoder,
             &self.config.hashers.block_hasher,
+            &self.config.hashers.tx_hasher,
         )?;
 
+        // The transactions can read the validator of their block, so it has to be known before they run
+        block.validator_public_key = Some(self.config.private_key.public_key());
+
+        // Execute the transactions to get the state root and the receipts root the block results in
+        (block.header.state_root, block.header.receipts_root) =
+            self.blockchain.compute_roots(&block).await?;
+
         // Sign the block with the validator's private key
-        block.sign(&self.config.private_key, &self.config.encoding.encoder)?;
+        block.sign(&self.config.private_key)?;
 
         info!(
             "Validator created new block: {}",
@@ -95,10 +167,12 @@ impl Validator {
         );
 
         // Add the new block to the blockchain
-        self.blockchain.add_block(block.clone()).await?;
+        let update = self.blockchain.add_block(block.clone()).await?;
 
-        // Clear all pending transactions
-        self.tx_pool.clear_pending().await;
+        // Remove the included transactions from the pool
+        self.tx_pool
+            .update(self.config.hashers.tx_hasher.clone(), &update)
+            .await?;
 
         // Broadcast the new block to all the nodes in the network
         self.msg_sender.broadcast_block_threaded(block);
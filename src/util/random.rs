@@ -1,5 +1,5 @@
 use crate::{
-    core::{data_hash, TxHasher},
+    core::{data_hash, timestamp_now, TxHasher, DEFAULT_CHAIN_ID},
     crypto::PrivateKey,
     prelude::*,
 };
@@ -21,15 +21,19 @@ pub fn random_block(height: u32, prev_block_header_hash: Hash, enc: &DynEncoder)
 
     let header = BlockHeader {
         version: 1,
+        chain_id: DEFAULT_CHAIN_ID,
         height,
-        timestamp: tokio::time::Instant::now().elapsed().as_nanos(),
+        timestamp: timestamp_now(),
         prev_block_header_hash: Some(prev_block_header_hash),
         data_hash: Hash::zero(),
+        tx_root: Hash::zero(),
+        state_root: Hash::zero(),
+        receipts_root: Hash::zero(),
     };
 
     let mut b = Block::new(header, vec![]);
     b.header.data_hash = data_hash(&b.transactions, enc)?;
-    b.sign(&private_key, enc)?;
+    b.sign(&private_key)?;
     Ok(b)
 }
 
@@ -40,6 +44,6 @@ pub fn random_block_with_signature(
 ) -> Result<Block> {
     let private_key = PrivateKey::generate();
     let mut b = random_block(height, prev_block_header_hash, enc)?;
-    b.sign(&private_key, enc)?;
+    b.sign(&private_key)?;
     Ok(b)
 }